@@ -38,7 +38,8 @@ impl BBProgram {
     let func_index = prog
       .functions
       .into_iter()
-      .map(|func| BBFunction::new(func, &func_map))
+      .enumerate()
+      .map(|(index, func)| BBFunction::new(func, index, &func_map))
       .collect::<Result<Vec<BBFunction>, InterpError>>()?;
 
     let bb = Self {
@@ -88,6 +89,8 @@ pub struct NumifiedInstruction {
   pub dest: Option<usize>,
   pub args: Vec<usize>,
   pub funcs: Vec<usize>,
+  // The block a `guard` jumps to when it fails, filled in with the rest of the cfg
+  pub guard_target: Option<usize>,
 }
 
 fn get_num_from_map(
@@ -124,6 +127,7 @@ impl NumifiedInstruction {
         dest: Some(get_num_from_map(dest, num_of_vars, num_var_map)),
         args: Vec::new(),
         funcs: Vec::new(),
+        guard_target: None,
       },
       Instruction::Value {
         dest,
@@ -146,6 +150,7 @@ impl NumifiedInstruction {
               .ok_or_else(|| InterpError::FuncNotFound(f.to_string()).add_pos(pos.clone()))
           })
           .collect::<Result<Vec<usize>, PositionalInterpError>>()?,
+        guard_target: None,
       },
       Instruction::Effect {
         args, funcs, pos, ..
@@ -164,6 +169,7 @@ impl NumifiedInstruction {
               .ok_or_else(|| InterpError::FuncNotFound(f.to_string()).add_pos(pos.clone()))
           })
           .collect::<Result<Vec<usize>, PositionalInterpError>>()?,
+        guard_target: None,
       },
     })
   }
@@ -173,6 +179,8 @@ impl NumifiedInstruction {
 #[derive(Debug)]
 pub struct BBFunction {
   pub name: String,
+  // The position of the function in `BBProgram::func_index`
+  pub index: usize,
   pub args: Vec<bril_rs::Argument>,
   pub return_type: Option<bril_rs::Type>,
   pub blocks: Vec<BasicBlock>,
@@ -185,14 +193,19 @@ pub struct BBFunction {
 }
 
 impl BBFunction {
-  fn new(f: Function, func_map: &FxHashMap<String, usize>) -> Result<Self, InterpError> {
-    let (mut func, label_map) = Self::find_basic_blocks(f, func_map)?;
+  fn new(
+    f: Function,
+    index: usize,
+    func_map: &FxHashMap<String, usize>,
+  ) -> Result<Self, InterpError> {
+    let (mut func, label_map) = Self::find_basic_blocks(f, index, func_map)?;
     func.build_cfg(&label_map)?;
     Ok(func)
   }
 
   fn find_basic_blocks(
     func: bril_rs::Function,
+    index: usize,
     func_map: &FxHashMap<String, usize>,
   ) -> Result<(Self, FxHashMap<String, usize>), PositionalInterpError> {
     let mut blocks = Vec::new();
@@ -254,6 +267,7 @@ impl BBFunction {
     Ok((
       Self {
         name: func.name,
+        index,
         args: func.args,
         return_type: func.return_type,
         blocks,
@@ -271,6 +285,22 @@ impl BBFunction {
     }
     let last_idx = self.blocks.len() - 1;
     for (i, block) in self.blocks.iter_mut().enumerate() {
+      for (instr, numified) in block.instrs.iter().zip(block.numified_instrs.iter_mut()) {
+        if let bril_rs::Instruction::Effect {
+          op: bril_rs::EffectOps::Guard,
+          labels,
+          ..
+        } = instr
+        {
+          if let Some(l) = labels.first() {
+            numified.guard_target = Some(
+              *label_map
+                .get(l)
+                .ok_or_else(|| InterpError::MissingLabel(l.clone()))?,
+            );
+          }
+        }
+      }
       // Get the last instruction
       let last_instr = block.instrs.last().cloned();
       if let Some(bril_rs::Instruction::Effect {
@@ -308,7 +308,11 @@ fn type_check_instruction<'a>(
       check_num_funcs(0, funcs)?;
       // Phi nodes are a little weird with their args and there has been some discussion on an _undefined var name in #108
       // Instead, we are going to assign the type we expect to all of the args and this will trigger an error if any of these args ends up being a different type.
-      args.iter().try_for_each(|a| update_env(env, a, op_type))?;
+      // `__undefined`, what SSA construction puts on the edges without a definition, can stand for any type
+      args
+        .iter()
+        .filter(|a| *a != "__undefined")
+        .try_for_each(|a| update_env(env, a, op_type))?;
 
       update_env(env, dest, op_type)
     }
@@ -426,7 +430,7 @@ fn type_check_instruction<'a>(
       })
     }
     Instruction::Effect {
-      op: EffectOps::Nop,
+      op: EffectOps::Nop | EffectOps::Speculate | EffectOps::Commit,
       args,
       funcs,
       labels,
@@ -496,13 +500,17 @@ fn type_check_instruction<'a>(
       Ok(())
     }
     Instruction::Effect {
-      op: EffectOps::Speculate | EffectOps::Guard | EffectOps::Commit,
-      args: _,
-      funcs: _,
-      labels: _,
+      op: EffectOps::Guard,
+      args,
+      funcs,
+      labels,
       pos: _,
     } => {
-      unimplemented!()
+      check_num_args(1, args)?;
+      check_asmt_type(&Type::Bool, get_type(env, 0, args)?)?;
+      check_num_funcs(0, funcs)?;
+      check_num_labels(1, labels)?;
+      Ok(())
     }
   }
 }
@@ -8,6 +8,10 @@ pub struct Cli {
   #[arg(short, long, action)]
   pub profile: bool,
 
+  /// File to write the number of times each function, block and edge between blocks ran to
+  #[arg(short, long, action)]
+  pub block_profile: Option<String>,
+
   /// The bril file to run. stdin is assumed if file is not provided
   #[arg(short, long, action)]
   pub file: Option<String>,
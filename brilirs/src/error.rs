@@ -59,6 +59,10 @@ pub enum InterpError {
   IoError(#[from] std::io::Error),
   #[error("value ${0} cannot be converted to char")]
   ToCharError(i64),
+  #[error("`{0}` outside of speculation")]
+  NotSpeculating(String),
+  #[error("returned while speculating")]
+  ReturnWhileSpeculating,
   #[error("You probably shouldn't see this error, this is here to handle conversions between InterpError and PositionalError")]
   PositionalInterpErrorConversion(#[from] PositionalInterpError),
 }
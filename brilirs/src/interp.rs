@@ -1,5 +1,6 @@
 use crate::basic_block::{BBFunction, BBProgram, BasicBlock};
 use crate::error::{InterpError, PositionalInterpError};
+use crate::profile::BlockProfile;
 use bril2json::escape_control_chars;
 use bril_rs::Instruction;
 
@@ -85,6 +86,16 @@ impl Environment {
   pub fn pop_frame(&mut self) {
     (self.current_pointer, self.current_frame_size) = self.stack_pointers.pop().unwrap();
   }
+
+  // A copy of the current frame, to roll back to when a speculation is aborted
+  pub fn snapshot_frame(&self) -> Vec<Value> {
+    self.env[self.current_pointer..self.current_pointer + self.current_frame_size].to_vec()
+  }
+
+  pub fn restore_frame(&mut self, frame: &[Value]) {
+    self.env[self.current_pointer..self.current_pointer + self.current_frame_size]
+      .clone_from_slice(frame);
+  }
 }
 
 // todo: This is basically a copy of the heap implement in brili and we could probably do something smarter. This currently isn't that worth it to optimize because most benchmarks do not use the memory extension nor do they run for very long. You (the reader in the future) may be working with bril programs that you would like to speed up that extensively use the bril memory extension. In that case, it would be worth seeing how to implement Heap without a map based memory. Maybe try to re-implement malloc for a large Vec<Value>?
@@ -584,7 +595,8 @@ fn execute_effect_op<T: std::io::Write>(
       let arg0 = get_arg::<&Pointer>(&state.env, 0, args);
       state.heap.free(arg0)?;
     }
-    Speculate | Commit | Guard => unimplemented!(),
+    // These change the frame and the control flow so they are handled by `execute`
+    Speculate | Commit | Guard => unreachable!(),
   }
   Ok(())
 }
@@ -598,12 +610,21 @@ fn execute<'a, T: std::io::Write>(
   let mut curr_block_idx = 0;
   // A possible return value
   let mut result = None;
+  // The frames to roll back to when a `guard` fails, one per `speculate` that was not committed
+  let mut speculation: Vec<Vec<Value>> = Vec::new();
+
+  if let Some(profile) = &mut state.block_profile {
+    profile.record_call(func.index);
+  }
 
   loop {
     let curr_block = &func.blocks[curr_block_idx];
+    if let Some(profile) = &mut state.block_profile {
+      profile.record_block(func.index, curr_block_idx);
+    }
     let curr_instrs = &curr_block.instrs;
     let curr_numified_instrs = &curr_block.numified_instrs;
-    // WARNING!!! We can add the # of instructions at once because you can only jump to a new block at the end. This may need to be changed if speculation is implemented
+    // We add the # of instructions at once because you can only jump to a new block at the end. A failing `guard` is the exception and takes back the instructions it skips
     state.instruction_count += curr_instrs.len();
     last_label = current_label;
     current_label = curr_block.label.as_ref();
@@ -611,7 +632,11 @@ fn execute<'a, T: std::io::Write>(
     // A place to store the next block that will be jumped to if specified by an instruction
     let mut next_block_idx = None;
 
-    for (code, numified_code) in curr_instrs.iter().zip(curr_numified_instrs.iter()) {
+    for (position, (code, numified_code)) in curr_instrs
+      .iter()
+      .zip(curr_numified_instrs.iter())
+      .enumerate()
+    {
       match code {
         Instruction::Constant {
           op: bril_rs::ConstOps::Const,
@@ -660,6 +685,36 @@ fn execute<'a, T: std::io::Write>(
           )
           .map_err(|e| e.add_pos(pos.clone()))?;
         }
+        Instruction::Effect {
+          op: bril_rs::EffectOps::Speculate,
+          ..
+        } => {
+          speculation.push(state.env.snapshot_frame());
+        }
+        Instruction::Effect {
+          op: bril_rs::EffectOps::Commit,
+          pos,
+          ..
+        } => {
+          speculation.pop().ok_or_else(|| {
+            InterpError::NotSpeculating("commit".to_string()).add_pos(pos.clone())
+          })?;
+        }
+        Instruction::Effect {
+          op: bril_rs::EffectOps::Guard,
+          pos,
+          ..
+        } => {
+          if !get_arg::<bool>(&state.env, 0, &numified_code.args) {
+            let frame = speculation.pop().ok_or_else(|| {
+              InterpError::NotSpeculating("guard".to_string()).add_pos(pos.clone())
+            })?;
+            state.env.restore_frame(&frame);
+            state.instruction_count -= curr_instrs.len() - position - 1;
+            next_block_idx = numified_code.guard_target;
+            break;
+          }
+        }
         Instruction::Effect {
           op,
           args: _,
@@ -667,6 +722,9 @@ fn execute<'a, T: std::io::Write>(
           funcs: _,
           pos,
         } => {
+          if *op == bril_rs::EffectOps::Return && !speculation.is_empty() {
+            return Err(InterpError::ReturnWhileSpeculating.add_pos(pos.clone()));
+          }
           execute_effect_op(
             state,
             *op,
@@ -682,12 +740,18 @@ fn execute<'a, T: std::io::Write>(
     }
 
     // Are we jumping to a new block or are we done?
+    let prev_block_idx = curr_block_idx;
     if let Some(idx) = next_block_idx {
       curr_block_idx = idx;
     } else if curr_block.exit.len() == 1 {
       curr_block_idx = curr_block.exit[0];
-    } else {
+    } else if speculation.is_empty() {
       return Ok(result);
+    } else {
+      return Err(InterpError::ReturnWhileSpeculating.into());
+    }
+    if let Some(profile) = &mut state.block_profile {
+      profile.record_edge(func.index, prev_block_idx, curr_block_idx);
     }
   }
 }
@@ -765,16 +829,25 @@ struct State<'a, T: std::io::Write> {
   heap: Heap,
   out: T,
   instruction_count: usize,
+  // Only gathered when asked for since it costs a few lookups per block
+  block_profile: Option<BlockProfile>,
 }
 
 impl<'a, T: std::io::Write> State<'a, T> {
-  const fn new(prog: &'a BBProgram, env: Environment, heap: Heap, out: T) -> Self {
+  const fn new(
+    prog: &'a BBProgram,
+    env: Environment,
+    heap: Heap,
+    out: T,
+    block_profile: Option<BlockProfile>,
+  ) -> Self {
     Self {
       prog,
       env,
       heap,
       out,
       instruction_count: 0,
+      block_profile,
     }
   }
 }
@@ -789,8 +862,42 @@ pub fn execute_main<T: std::io::Write, U: std::io::Write>(
   out: T,
   input_args: &[String],
   profiling: bool,
-  mut profiling_out: U,
+  profiling_out: U,
 ) -> Result<(), PositionalInterpError> {
+  run_main(prog, out, input_args, profiling, profiling_out, None).map(|_| ())
+}
+
+/// Like [`execute_main`] but also counts how many times each function, block and edge between blocks ran
+/// # Panics
+/// This should not panic with normal use except if there is a bug or if you are using an unimplemented feature
+/// # Errors
+/// Will error on malformed `BBProgram`, like if the original Bril program was not well-formed
+pub fn execute_main_with_block_profile<T: std::io::Write, U: std::io::Write>(
+  prog: &BBProgram,
+  out: T,
+  input_args: &[String],
+  profiling: bool,
+  profiling_out: U,
+) -> Result<BlockProfile, PositionalInterpError> {
+  run_main(
+    prog,
+    out,
+    input_args,
+    profiling,
+    profiling_out,
+    Some(BlockProfile::new(prog)),
+  )
+  .map(Option::unwrap)
+}
+
+fn run_main<T: std::io::Write, U: std::io::Write>(
+  prog: &BBProgram,
+  out: T,
+  input_args: &[String],
+  profiling: bool,
+  mut profiling_out: U,
+  block_profile: Option<BlockProfile>,
+) -> Result<Option<BlockProfile>, PositionalInterpError> {
   let main_func = prog
     .index_of_main
     .map(|i| prog.get(i).unwrap())
@@ -802,7 +909,7 @@ pub fn execute_main<T: std::io::Write, U: std::io::Write>(
   env = parse_args(env, &main_func.args, &main_func.args_as_nums, input_args)
     .map_err(|e| e.add_pos(main_func.pos.clone()))?;
 
-  let mut state = State::new(prog, env, heap, out);
+  let mut state = State::new(prog, env, heap, out, block_profile);
 
   execute(&mut state, main_func)?;
 
@@ -820,5 +927,5 @@ pub fn execute_main<T: std::io::Write, U: std::io::Write>(
       .map_err(InterpError::IoError)?;
   }
 
-  Ok(())
+  Ok(state.block_profile)
 }
@@ -21,6 +21,8 @@ pub mod cli;
 pub mod error;
 /// Provides ```interp::execute_main``` to execute [Program] that have been converted into [`BBProgram`]
 pub mod interp;
+/// Provides ```profile::BlockProfile```, the per block execution counts ```interp::execute_main_with_block_profile``` gathers
+pub mod profile;
 
 #[doc(hidden)]
 pub fn run_input<T: std::io::Write, U: std::io::Write>(
@@ -32,6 +34,7 @@ pub fn run_input<T: std::io::Write, U: std::io::Write>(
   check: bool,
   text: bool,
   src_name: Option<String>,
+  block_profile: Option<String>,
 ) -> Result<(), PositionalInterpError> {
   // It's a little confusing because of the naming conventions.
   //      - bril_rs takes file.json as input
@@ -45,7 +48,22 @@ pub fn run_input<T: std::io::Write, U: std::io::Write>(
   check::type_check(&bbprog)?;
 
   if !check {
-    interp::execute_main(&bbprog, out, input_args, profiling, profiling_out)?;
+    match block_profile {
+      None => interp::execute_main(&bbprog, out, input_args, profiling, profiling_out)?,
+      Some(path) => {
+        let profile = interp::execute_main_with_block_profile(
+          &bbprog,
+          out,
+          input_args,
+          profiling,
+          profiling_out,
+        )?;
+        let file = std::fs::File::create(path).map_err(error::InterpError::IoError)?;
+        profile
+          .write(&bbprog, std::io::BufWriter::new(file))
+          .map_err(error::InterpError::IoError)?;
+      }
+    }
   }
 
   Ok(())
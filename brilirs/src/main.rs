@@ -28,6 +28,7 @@ fn main() {
     args.check,
     args.text,
     args.file,
+    args.block_profile,
   ) {
     eprintln!("error: {e}");
     if let PositionalInterpError {
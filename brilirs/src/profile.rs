@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::basic_block::{BBFunction, BBProgram};
+
+/// Execution counts of one function
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+  /// How many times the function was called, `main` counts once
+  pub calls: u64,
+  /// Indexed like the basic blocks of the function
+  pub blocks: Vec<u64>,
+  /// How many times control went from one block to another, by block index
+  pub edges: BTreeMap<(usize, usize), u64>,
+}
+
+/// Per function and per block execution counts gathered while interpreting a [`BBProgram`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockProfile {
+  /// Indexed like the functions of the [`BBProgram`]
+  pub functions: Vec<FunctionProfile>,
+}
+
+impl BlockProfile {
+  /// An empty profile with room for every function and block of `prog`
+  #[must_use]
+  pub fn new(prog: &BBProgram) -> Self {
+    Self {
+      functions: prog
+        .func_index
+        .iter()
+        .map(|func| FunctionProfile {
+          blocks: vec![0; func.blocks.len()],
+          ..FunctionProfile::default()
+        })
+        .collect(),
+    }
+  }
+
+  pub(crate) fn record_call(&mut self, func: usize) {
+    self.functions[func].calls += 1;
+  }
+
+  pub(crate) fn record_block(&mut self, func: usize, block: usize) {
+    self.functions[func].blocks[block] += 1;
+  }
+
+  pub(crate) fn record_edge(&mut self, func: usize, from: usize, to: usize) {
+    *self.functions[func].edges.entry((from, to)).or_default() += 1;
+  }
+
+  /// Name of a block in the profile file: its label, or `#` followed by its index in the function
+  /// when it has none
+  fn block_name(func: &BBFunction, block: usize) -> String {
+    func.blocks[block]
+      .label
+      .as_ref()
+      .map_or_else(|| format!("#{block}"), |label| format!(".{label}"))
+  }
+
+  /// Write the profile as text, one count per line:
+  ///
+  /// ```text
+  /// function main 1
+  /// block main .loop 10
+  /// edge main .loop #2 9
+  /// ```
+  ///
+  /// Blocks that never ran and edges that were never taken are left out
+  /// # Errors
+  /// Any error of `out`
+  pub fn write<W: Write>(&self, prog: &BBProgram, mut out: W) -> std::io::Result<()> {
+    for (func, profile) in prog.func_index.iter().zip(self.functions.iter()) {
+      writeln!(out, "function {} {}", func.name, profile.calls)?;
+      for (block, count) in profile.blocks.iter().enumerate() {
+        if *count > 0 {
+          writeln!(
+            out,
+            "block {} {} {count}",
+            func.name,
+            Self::block_name(func, block)
+          )?;
+        }
+      }
+      for ((from, to), count) in &profile.edges {
+        writeln!(
+          out,
+          "edge {} {} {} {count}",
+          func.name,
+          Self::block_name(func, *from),
+          Self::block_name(func, *to)
+        )?;
+      }
+    }
+    out.flush()
+  }
+}
@@ -6,7 +6,7 @@ use crate::data_flow::DataFlowDirection;
 use crate::data_flow::DataFlowOrder;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
-use std::collections::LinkedList;
+use std::collections::VecDeque;
 
 type State = BTreeMap<String, BTreeSet<usize>>;
 pub struct AliasAnalysis {
@@ -15,16 +15,40 @@ pub struct AliasAnalysis {
     alias_states: BTreeMap<BlockID, State>,
 
     set_of_all_memory_location: BTreeSet<usize>,
+
+    // INFO: When false, transform leaves the blocks alone so the points-to sets can be queried
+    // by other passes
+    dead_store_elimination: bool,
+
+    // The blocks reachable from the entry when a single function is analyzed
+    subset: Option<VecDeque<BlockID>>,
 }
 impl AliasAnalysis {
     pub fn new(cfg: &CFG) -> AliasAnalysis {
+        Self::with_blocks(cfg, &cfg.order, None)
+    }
+
+    /// Same as `query_only`, for a single function given its blocks as `CFG::function_blocks`
+    /// groups them. The allocation sites are the ones of the function
+    pub fn for_function(cfg: &CFG, blocks: &[BlockID]) -> AliasAnalysis {
+        Self {
+            dead_store_elimination: false,
+            ..Self::with_blocks(cfg, blocks, Some(cfg.bfs_children(blocks[0])))
+        }
+    }
+
+    fn with_blocks(
+        cfg: &CFG,
+        blocks: &[BlockID],
+        subset: Option<VecDeque<BlockID>>,
+    ) -> AliasAnalysis {
         // TODO: Make a function to query set_of_all_mem from cfg
 
         let mut alias_states = BTreeMap::<BlockID, State>::default();
         let mut set_of_all_memory_location = BTreeSet::default();
-        for (_, bb_ptr) in cfg.hm.iter() {
-            alias_states.entry(bb_ptr.borrow().id).or_default();
-            for instr in bb_ptr.borrow().instrs.iter() {
+        for bb in blocks.iter().map(|id| &cfg.blocks[*id]) {
+            alias_states.entry(bb.id).or_default();
+            for instr in bb.instrs.iter() {
                 if let crate::bril_syntax::InstructionOrLabel::Instruction(i) = instr {
                     if i.is_alloc() {
                         set_of_all_memory_location.insert(i.instruction_id.unwrap());
@@ -35,17 +59,44 @@ impl AliasAnalysis {
         Self {
             alias_states,
             set_of_all_memory_location,
+            dead_store_elimination: true,
+            subset,
+        }
+    }
+
+    /// Same analysis as `new`, but running it through `cfg.dataflow` does not remove any store
+    pub fn query_only(cfg: &CFG) -> AliasAnalysis {
+        Self {
+            dead_store_elimination: false,
+            ..Self::new(cfg)
+        }
+    }
+
+    /// The set of allocation sites (instruction id of the `alloc`) that `var` may point to, in any
+    /// block of the program
+    pub fn points_to(&self, var: &str) -> BTreeSet<usize> {
+        let mut result = BTreeSet::new();
+        for state in self.alias_states.values() {
+            if let Some(sites) = state.get(var) {
+                result.extend(sites.iter());
+            }
         }
+        result
+    }
+
+    pub fn may_point_to(&self, var: &str, site: usize) -> bool {
+        self.alias_states
+            .values()
+            .any(|state| state.get(var).is_some_and(|sites| sites.contains(&site)))
     }
 }
 impl DataFlowAnalysis for AliasAnalysis {
-    fn meet(&mut self, bb: &mut crate::basic_block::BasicBlock) {
+    fn meet(&mut self, bb: &crate::basic_block::BasicBlock) {
         // if two variables has non-empty intersection, they might alias
         let mut empty_state = State::default();
         //let mut bb_state = self.alias_states.entry(bb.id).or_default();
 
-        for pred in bb.predecessors.iter() {
-            let pred_id = pred.borrow().id;
+        for pred_id in bb.predecessors.iter().copied() {
             if pred_id == bb.id {
                 continue;
             }
@@ -74,7 +125,7 @@ impl DataFlowAnalysis for AliasAnalysis {
 
     fn transfer(
         &mut self,
-        bb: &mut crate::basic_block::BasicBlock,
+        bb: &crate::basic_block::BasicBlock,
     ) -> crate::data_flow::TransferResult {
         let initial_state = self.alias_states.entry(bb.id).or_default().clone();
         let mut changing_state = self.alias_states.entry(bb.id).or_default().clone();
@@ -127,6 +178,9 @@ impl DataFlowAnalysis for AliasAnalysis {
 
     /// This dead store performs dead store elimination
     fn transform(&mut self, bb: &mut crate::basic_block::BasicBlock) {
+        if !self.dead_store_elimination {
+            return;
+        }
         // unused var : map from var(String) to instruction
         // to_be_removed : set of instruction when rescaning again, we don't include this
         //
@@ -146,7 +200,7 @@ impl DataFlowAnalysis for AliasAnalysis {
         //
         let mut to_be_removed = BTreeMap::<String, usize>::new();
         let mut actually_removed = BTreeSet::<usize>::new();
-        let bb_state = self.alias_states.get(&bb.id).unwrap();
+        let _bb_state = self.alias_states.get(&bb.id).unwrap();
         for ilb in bb.instrs.clone() {
             if let InstructionOrLabel::Instruction(i) = ilb {
                 if i.is_load() || i.is_ptradd() || i.is_alloc() || i.is_store() || i.is_id() {
@@ -176,15 +230,15 @@ impl DataFlowAnalysis for AliasAnalysis {
         }
         eprintln!("Actually removed in the end : {:?}", actually_removed);
 
-        let mut kept_instruction = LinkedList::new();
+        let mut kept_instruction = Vec::new();
         for ilb in bb.instrs.clone() {
             match ilb {
                 InstructionOrLabel::Label(_) => {
-                    kept_instruction.push_back(ilb.clone());
+                    kept_instruction.push(ilb.clone());
                 }
                 InstructionOrLabel::Instruction(ref instruction) => {
                     if !actually_removed.contains(&instruction.instruction_id.unwrap()) {
-                        kept_instruction.push_back(ilb);
+                        kept_instruction.push(ilb);
                     }
                 }
             }
@@ -198,6 +252,9 @@ impl DataFlowAnalysis for AliasAnalysis {
     }
 
     fn get_dataflow_order(&self) -> crate::data_flow::DataFlowOrder {
-        DataFlowOrder::BFS
+        match &self.subset {
+            Some(subset) => DataFlowOrder::Subset(subset.clone()),
+            None => DataFlowOrder::BFS,
+        }
     }
 }
@@ -0,0 +1,203 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+use crate::{
+    alias_analysis::AliasAnalysis,
+    aliases::BlockID,
+    cfg::CFG,
+    dominance::DominanceDataFlow,
+    liveness::{Liveness, LivenessKind},
+    loops::NaturalLoops,
+    purity::PuritySummaries,
+};
+
+/// The analyses `AnalysisManager` caches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnalysisKind {
+    Dominance,
+    Loops,
+    Liveness,
+    Alias,
+    Purity,
+}
+
+/// The cached analyses a pass leaves valid, the others are dropped once it ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreservedAnalyses {
+    kinds: BTreeSet<AnalysisKind>,
+}
+
+impl PreservedAnalyses {
+    /// Nothing survives the pass
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn all() -> Self {
+        Self::none()
+            .preserve(AnalysisKind::Dominance)
+            .preserve(AnalysisKind::Loops)
+            .preserve(AnalysisKind::Liveness)
+            .preserve(AnalysisKind::Alias)
+            .preserve(AnalysisKind::Purity)
+    }
+
+    /// The blocks and edges are untouched, only the instructions changed
+    pub fn cfg() -> Self {
+        Self::none()
+            .preserve(AnalysisKind::Dominance)
+            .preserve(AnalysisKind::Loops)
+    }
+
+    pub fn preserve(mut self, kind: AnalysisKind) -> Self {
+        self.kinds.insert(kind);
+        self
+    }
+
+    pub fn preserves(&self, kind: AnalysisKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+}
+
+/// The analyses computed so far for one function
+#[derive(Default)]
+struct FunctionAnalyses {
+    dominance: Option<Rc<DominanceDataFlow>>,
+    loops: Option<Rc<NaturalLoops>>,
+    liveness: BTreeMap<LivenessKind, Rc<Liveness>>,
+    alias: Option<Rc<AliasAnalysis>>,
+}
+
+/// Computes analyses on demand and keeps them per function, keyed by the entry block, until a
+/// pass that does not preserve them runs. The blocks of a function are the ones
+/// `CFG::function_blocks` groups together
+#[derive(Default)]
+pub struct AnalysisManager {
+    functions: BTreeMap<BlockID, FunctionAnalyses>,
+    // The summaries are about the whole program, the functions calling each other
+    purity: Option<Rc<PuritySummaries>>,
+    /// How many times each analysis was computed, a cache hit does not count
+    pub computed: BTreeMap<AnalysisKind, usize>,
+}
+
+impl AnalysisManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&mut self, kind: AnalysisKind) {
+        *self.computed.entry(kind).or_default() += 1;
+    }
+
+    pub fn dominance(&mut self, cfg: &CFG, blocks: &[BlockID]) -> Rc<DominanceDataFlow> {
+        if let Some(dominance) = &self.functions.entry(blocks[0]).or_default().dominance {
+            return dominance.clone();
+        }
+        self.record(AnalysisKind::Dominance);
+        let dominance = Rc::new(DominanceDataFlow::for_function(cfg, blocks));
+        self.functions.get_mut(&blocks[0]).unwrap().dominance = Some(dominance.clone());
+        dominance
+    }
+
+    /// The dominance of every function of the program put together
+    pub fn program_dominance(&mut self, cfg: &CFG) -> DominanceDataFlow {
+        let mut result = DominanceDataFlow::default();
+        for blocks in cfg.function_blocks() {
+            result.merge(&self.dominance(cfg, &blocks));
+        }
+        result
+    }
+
+    pub fn loops(&mut self, cfg: &CFG, blocks: &[BlockID]) -> Rc<NaturalLoops> {
+        if let Some(loops) = &self.functions.entry(blocks[0]).or_default().loops {
+            return loops.clone();
+        }
+        let dominance = self.dominance(cfg, blocks);
+        self.record(AnalysisKind::Loops);
+        let loops = Rc::new(NaturalLoops::new(cfg, &dominance));
+        self.functions.get_mut(&blocks[0]).unwrap().loops = Some(loops.clone());
+        loops
+    }
+
+    /// The liveness of the function, calls to the functions the purity summaries find removable
+    /// are not critical
+    pub fn liveness(&mut self, cfg: &CFG, blocks: &[BlockID], kind: LivenessKind) -> Rc<Liveness> {
+        if let Some(liveness) = self
+            .functions
+            .entry(blocks[0])
+            .or_default()
+            .liveness
+            .get(&kind)
+        {
+            return liveness.clone();
+        }
+        let purity = self.purity(cfg);
+        let liveness = Rc::new(Liveness::analyze_function_with_summaries(
+            cfg, blocks, kind, &purity,
+        ));
+        let analyses = self.functions.get_mut(&blocks[0]).unwrap();
+        analyses.liveness.insert(kind, liveness.clone());
+        self.record(AnalysisKind::Liveness);
+        liveness
+    }
+
+    /// The points-to sets of the function, as `AliasAnalysis::for_function` computes them
+    pub fn alias(&mut self, cfg: &CFG, blocks: &[BlockID]) -> Rc<AliasAnalysis> {
+        if let Some(alias) = &self.functions.entry(blocks[0]).or_default().alias {
+            return alias.clone();
+        }
+        self.record(AnalysisKind::Alias);
+        let mut alias = AliasAnalysis::for_function(cfg, blocks);
+        cfg.solve(&mut alias);
+        let alias = Rc::new(alias);
+        self.functions.get_mut(&blocks[0]).unwrap().alias = Some(alias.clone());
+        alias
+    }
+
+    /// What calling each function of the program may do, see `PuritySummaries`
+    pub fn purity(&mut self, cfg: &CFG) -> Rc<PuritySummaries> {
+        if let Some(purity) = &self.purity {
+            return purity.clone();
+        }
+        self.record(AnalysisKind::Purity);
+        let purity = Rc::new(PuritySummaries::new(cfg));
+        self.purity = Some(purity.clone());
+        purity
+    }
+
+    /// Drop every cached analysis that `preserved` does not list
+    pub fn invalidate(&mut self, preserved: &PreservedAnalyses) {
+        if !preserved.preserves(AnalysisKind::Purity) {
+            self.purity = None;
+        }
+        for analyses in self.functions.values_mut() {
+            if !preserved.preserves(AnalysisKind::Dominance) {
+                analyses.dominance = None;
+            }
+            if !preserved.preserves(AnalysisKind::Loops) {
+                analyses.loops = None;
+            }
+            if !preserved.preserves(AnalysisKind::Liveness) {
+                analyses.liveness.clear();
+            }
+            if !preserved.preserves(AnalysisKind::Alias) {
+                analyses.alias = None;
+            }
+        }
+    }
+
+    /// Drop everything, for when the CFG is rebuilt
+    pub fn clear(&mut self) {
+        self.functions.clear();
+        self.purity = None;
+    }
+
+    /// One line per analysis with how many times it was computed
+    pub fn report(&self) -> String {
+        let mut result = String::new();
+        for (kind, count) in self.computed.iter() {
+            result += &format!("{:<12} computed {}\n", format!("{:?}", kind), count);
+        }
+        result
+    }
+}
@@ -1,12 +1,11 @@
 use crate::{
-    aliases::{BlockID, DomTree, IdToBbMap, SSANameStack},
-    bril_syntax::{Function, Instruction, InstructionOrLabel, Label},
+    aliases::{BlockID, DomTree, SSANameStack},
+    bril_syntax::{BrilType, Function, Instruction, InstructionOrLabel, Label},
+    definite_assignment::UNDEFINED_MARKER,
 };
 use std::{
-    cell::RefCell,
-    collections::{BTreeMap, BTreeSet, LinkedList},
+    collections::{BTreeMap, BTreeSet},
     hash::{Hash, Hasher},
-    rc::Rc,
 };
 
 // Maybe this will be useful in the future but for now a leader is the first instruction in the
@@ -30,32 +29,33 @@ use std::{
 //        Self::InstructionOrLabel(instr)
 //    }
 //}
+/// A block of the CFG arena. Edges are the ids of the blocks at their other end, see
+/// `CFG::blocks`
 pub struct BasicBlock {
     pub func: Option<Function>,
     pub id: BlockID,
-    pub instrs: LinkedList<InstructionOrLabel>,
-    pub predecessors: Vec<Rc<RefCell<BasicBlock>>>,
-    pub successors: Vec<Rc<RefCell<BasicBlock>>>,
+    pub instrs: Vec<InstructionOrLabel>,
+    pub predecessors: Vec<BlockID>,
+    pub successors: Vec<BlockID>,
+    /// How many times the block ran, once a profile was applied with `CFG::apply_profile`
+    pub execution_count: Option<u64>,
+    /// How many times each outgoing edge was taken, keyed by successor
+    pub edge_counts: BTreeMap<BlockID, u64>,
 }
 impl std::fmt::Debug for BasicBlock {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "----Basic Block----
+            "----Basic Block {}----
 ----Instructions: \n",
+            self.id
         )
         .unwrap();
         for instr in self.instrs.iter() {
             writeln!(f, "{:?}", instr).unwrap();
         }
-        writeln!(f, "Pred: ").unwrap();
-        for instr in self.predecessors.iter() {
-            writeln!(f, "{:?}", instr.borrow()).unwrap();
-        }
-        writeln!(f, "Succ: ").unwrap();
-        for instr in self.successors.iter() {
-            writeln!(f, "{:?}", instr.borrow()).unwrap();
-        }
+        writeln!(f, "Pred: {:?}", self.predecessors).unwrap();
+        writeln!(f, "Succ: {:?}", self.successors).unwrap();
         writeln!(f, "\n")
     }
 }
@@ -75,26 +75,23 @@ impl PartialEq for BasicBlock {
 impl Eq for BasicBlock {}
 
 impl BasicBlock {
-    pub fn push_front(&mut self, ilb: &InstructionOrLabel) {
+    pub fn push_front(&mut self, _ilb: &InstructionOrLabel) {
         todo!();
     }
-    pub fn push_before_header(&mut self, lib: &InstructionOrLabel) {}
+    pub fn push_before_header(&mut self, _lib: &InstructionOrLabel) {}
     pub fn push_back(&mut self, ilb: &InstructionOrLabel) {
-        self.instrs.push_back(ilb.clone());
+        self.instrs.push(ilb.clone());
     }
 
     pub fn insert_at(&mut self, position: usize, ilb: &InstructionOrLabel) {
-        let mut tail = self.instrs.split_off(position);
-
-        self.instrs.push_back(ilb.clone()); // Manually walk the iterator to the desired position
-        self.instrs.append(&mut tail);
+        self.instrs.insert(position, ilb.clone());
     }
     pub fn get_label(&self) -> String {
         if self.instrs.is_empty() {
             unreachable!()
         }
 
-        let a = self.instrs.front().unwrap();
+        let a = self.instrs.first().unwrap();
 
         match a {
             InstructionOrLabel::Label(l) => l.label.to_string(),
@@ -102,60 +99,35 @@ impl BasicBlock {
         }
     }
 
-    fn new_name(
-        var: &String,
-        stack_of: &mut SSANameStack,
-        name_counter: &mut BTreeMap<String, usize>,
-        new_to_old_names: &mut BTreeMap<String, String>,
-    ) -> String {
-        let fresh = var.clone() + &name_counter.get(var).unwrap_or(&0).to_string();
+    /// The first of `var0`, `var1`, ... not taken yet, pushed on the stack of `var`
+    fn new_name(var: &String, stack_of: &mut SSANameStack, taken: &mut BTreeSet<String>) -> String {
+        let fresh = (0..)
+            .map(|counter| var.clone() + &counter.to_string())
+            .find(|fresh| taken.insert(fresh.clone()))
+            .unwrap();
         eprintln!("Old name: {}, new name : {}", var, fresh);
-        *name_counter.entry(var.clone()).or_insert(0) += 1;
 
         stack_of.entry(var.clone()).or_default().push(fresh.clone());
-
-        new_to_old_names.insert(fresh.clone(), var.clone());
         fresh
     }
     pub fn starts_with_label(&self, label: &String) -> bool {
-        if let Some(ilb) = self.instrs.back() {
-            match ilb {
-                InstructionOrLabel::Label(l) => l.label == *label,
-                _ => false,
-            }
-        } else {
-            false
-        }
+        matches!(self.instrs.last(), Some(InstructionOrLabel::Label(l)) if l.label == *label)
     }
     pub fn ends_with_jmp(&self) -> bool {
-        if let Some(ilb) = self.instrs.back() {
-            match ilb {
-                InstructionOrLabel::Instruction(i) => i.is_jmp(),
-                _ => false,
-            }
-        } else {
-            false
-        }
+        matches!(self.instrs.last(), Some(InstructionOrLabel::Instruction(i)) if i.is_jmp())
     }
 
     pub fn ends_with_br(&self) -> bool {
-        if let Some(ilb) = self.instrs.back() {
-            match ilb {
-                InstructionOrLabel::Instruction(i) => i.is_br(),
-                _ => false,
-            }
-        } else {
-            false
-        }
+        matches!(self.instrs.last(), Some(InstructionOrLabel::Instruction(i)) if i.is_br())
     }
     pub fn rename_phi_def(
         &self,
         mut stack_of: SSANameStack,
         dom_tree: &DomTree,
-        name_counter: &mut BTreeMap<String, usize>,
-        id_to_bb: &IdToBbMap,
-        id_to_ins: &mut BTreeMap<usize, LinkedList<InstructionOrLabel>>,
-        new_to_old_names: &mut BTreeMap<String, String>,
+        blocks: &[BasicBlock],
+        id_to_ins: &mut BTreeMap<usize, Vec<InstructionOrLabel>>,
+        phi_variables: &BTreeMap<usize, String>,
+        taken: &mut BTreeSet<String>,
     ) {
         // INFO: Rename phi function first
         eprintln!("In block {} now", self.id);
@@ -163,12 +135,7 @@ impl BasicBlock {
             if let InstructionOrLabel::Instruction(i) = inst {
                 if i.is_phi() {
                     if let Some(dest) = &mut i.dest {
-                        *dest = BasicBlock::new_name(
-                            dest,
-                            &mut stack_of,
-                            name_counter,
-                            new_to_old_names,
-                        );
+                        *dest = BasicBlock::new_name(dest, &mut stack_of, taken);
                     }
                 }
             }
@@ -183,37 +150,26 @@ impl BasicBlock {
                 if let Some(args) = &mut i.args {
                     for arg in args.iter_mut() {
                         //eprintln!("Before, arg = {}", arg);
-                        *arg = stack_of
-                            .entry(arg.clone())
-                            .or_insert(vec![arg.clone()])
-                            .last()
-                            .unwrap()
-                            .clone();
+                        if let Some(current) = stack_of.get(arg).and_then(|names| names.last()) {
+                            *arg = current.clone();
+                        }
                         //eprintln!("After, arg = {}", arg);
                     }
                 }
                 if let Some(dest) = &mut i.dest {
-                    *dest =
-                        BasicBlock::new_name(dest, &mut stack_of, name_counter, new_to_old_names);
+                    *dest = BasicBlock::new_name(dest, &mut stack_of, taken);
                 }
             }
         }
         for succ in self.successors.iter() {
-            for instr in id_to_ins.entry(succ.borrow().id).or_default().iter_mut() {
+            for instr in id_to_ins.entry(*succ).or_default().iter_mut() {
                 if let InstructionOrLabel::Instruction(i) = instr {
                     if i.is_phi() {
-                        let v = &i.dest.clone().unwrap();
+                        let v = &phi_variables[&i.instruction_id.unwrap()];
                         eprintln!("v: {v}");
-                        let v = if let Some(old_name) = new_to_old_names.get(v) {
-                            eprintln!(
-                                "Transformed name from name maps: {}",
-                                stack_of[old_name].last().unwrap()
-                            );
-                            stack_of[old_name].last().unwrap().clone()
-                        } else if let Some(a) = stack_of.get(v) {
-                            a.last().unwrap().clone()
-                        } else {
-                            "undefined".to_string()
+                        let v = match stack_of.get(v).and_then(|names| names.last()) {
+                            Some(current) => current.clone(),
+                            None => UNDEFINED_MARKER.to_string(),
                         };
                         let label = self.get_label();
                         if i.labels.is_none() {
@@ -233,7 +189,7 @@ impl BasicBlock {
                             }
                         }
 
-                        eprintln!("Inserting {v} with {label} into {}", succ.borrow().id);
+                        eprintln!("Inserting {v} with {label} into {}", succ);
                     }
                 }
             }
@@ -241,13 +197,13 @@ impl BasicBlock {
         for (a, b) in dom_tree.iter() {
             if *b == self.id && b != a {
                 eprintln!("b: {b}, a: {a}");
-                id_to_bb[a].borrow().rename_phi_def(
+                blocks[*a].rename_phi_def(
                     stack_of.clone(),
                     dom_tree,
-                    name_counter,
-                    id_to_bb,
+                    blocks,
                     id_to_ins,
-                    new_to_old_names,
+                    phi_variables,
+                    taken,
                 )
             }
         }
@@ -294,6 +250,17 @@ impl BasicBlock {
         self.insert_at(1, &p); // Insert the new element at the current iterator position
     }
 
+    /// Give the phi defining `def` its type, when the variable has one
+    pub fn set_phi_type(&mut self, def: &String, bril_type: Option<&BrilType>) {
+        for ilb in self.instrs.iter_mut() {
+            if let InstructionOrLabel::Instruction(p) = ilb {
+                if p.is_phi() && p.dest.as_ref() == Some(def) {
+                    p.bril_type = bril_type.cloned();
+                }
+            }
+        }
+    }
+
     // Contains empty phi def
     pub fn contains_empty_phi_def(&self, def: &String) -> bool {
         self.instrs.iter().any(|x| {
@@ -344,15 +311,17 @@ impl BasicBlock {
             instrs: Default::default(),
             predecessors: Default::default(),
             successors: Default::default(),
+            execution_count: None,
+            edge_counts: Default::default(),
         };
         *id += 1;
         result
     }
-    pub fn default_with_label(id: &mut BlockID, label: &String) -> BasicBlock {
+    pub fn default_with_label(id: &mut BlockID, label: &str) -> BasicBlock {
         let mut result = Self::default(id);
 
         result.push_back(&InstructionOrLabel::Label(Label {
-            label: label.clone(),
+            label: label.to_string(),
         }));
         result
     }
@@ -379,73 +348,72 @@ impl BasicBlock {
 
             for (dest, src, _type) in argument_id_name.iter() {
                 self.push_back(&Instruction::new_id_instruction(
-                    &dest,
-                    &src,
-                    &_type,
+                    dest,
+                    src,
+                    _type,
                     instruction_counter,
                 ));
             }
         }
     }
+    /// The blocks of a function, in order and numbered from `block_id`. Only the fall through
+    /// edges are connected, `CFG::from_program` adds the jumps
     pub fn simple_basic_blocks_vec_from_function(
         f: &Function,
         block_id: &mut BlockID,
         instruction_counter: &mut usize,
-    ) -> LinkedList<Rc<RefCell<BasicBlock>>> {
-        let mut result: LinkedList<Rc<RefCell<BasicBlock>>> = LinkedList::new();
+    ) -> Vec<BasicBlock> {
+        let mut result: Vec<BasicBlock> = Vec::new();
         let mut i = 0;
-        let entry_bb = BasicBlock::default(block_id);
-        let entry_bb_rcf: Rc<RefCell<BasicBlock>> = Rc::<RefCell<BasicBlock>>::new(entry_bb.into());
+        let mut entry_bb = BasicBlock::default(block_id);
         let entry_header_name = "entry".to_string() + &f.name;
 
-        entry_bb_rcf
-            .borrow_mut()
-            .instrs
-            .push_back(InstructionOrLabel::new_dummy_head(
-                entry_header_name.clone(),
-                instruction_counter,
-            ));
-        entry_bb_rcf.borrow_mut().func = Some(f.clone());
-        entry_bb_rcf
-            .borrow_mut()
-            .preempt_function_arg(instruction_counter);
-        result.push_back(entry_bb_rcf);
+        entry_bb.instrs.push(InstructionOrLabel::new_dummy_head(
+            entry_header_name.clone(),
+            instruction_counter,
+        ));
+        entry_bb.func = Some(f.clone());
+        entry_bb.preempt_function_arg(instruction_counter);
+        result.push(entry_bb);
 
         let mut non_linear_before = false;
         while i < f.instrs.len() {
             // this match only happens if instruction is at start of function or after a branch
             // without label
-            let b: BasicBlock = BasicBlock::default(block_id);
-            let bb: Rc<RefCell<BasicBlock>> = Rc::<RefCell<BasicBlock>>::new(b.into());
+            let mut bb_mut: BasicBlock = BasicBlock::default(block_id);
             if !non_linear_before {
-                bb.borrow_mut()
-                    .predecessors
-                    .push(result.back().unwrap().clone());
-                result
-                    .back_mut()
-                    .unwrap()
-                    .borrow_mut()
-                    .successors
-                    .push(bb.clone());
+                let previous = result.last_mut().unwrap();
+                bb_mut.predecessors.push(previous.id);
+                previous.successors.push(bb_mut.id);
                 non_linear_before = true;
             }
 
-            let mut bb_mut = bb.borrow_mut();
             match f.instrs[i] {
                 InstructionOrLabel::Label(_) => {
-                    bb_mut.instrs.push_back(f.instrs[i].clone());
+                    bb_mut.instrs.push(f.instrs[i].clone());
                 }
                 _ => {
-                    bb_mut.instrs.push_back(InstructionOrLabel::new_dummy_head(
+                    bb_mut.instrs.push(InstructionOrLabel::new_dummy_head(
                         f.name.clone() + &block_id.to_string(),
                         instruction_counter,
                     ));
-                    bb_mut.instrs.push_back(f.instrs[i].clone());
+                    bb_mut.instrs.push(f.instrs[i].clone());
                 }
             }
             //bb_mut.instrs.push_back(f.instrs[i].clone());
             i += 1;
+            // A block made of a lone jump after a ret ends right away, it does not fall through
+            let ended = match bb_mut.instrs.last() {
+                Some(InstructionOrLabel::Instruction(instr)) => {
+                    instr.is_jmp() || instr.is_br() || instr.is_ret()
+                }
+                _ => false,
+            };
             loop {
+                if ended {
+                    i -= 1;
+                    break;
+                }
                 if i >= f.instrs.len() {
                     break;
                 }
@@ -457,8 +425,9 @@ impl BasicBlock {
                         }
                         bb_mut
                             .instrs
-                            .push_back(InstructionOrLabel::Instruction(instr.clone()));
-                        if instr.is_jmp() || instr.is_br() {
+                            .push(InstructionOrLabel::Instruction(instr.clone()));
+                        // Whatever follows a ret is unreachable and starts a block of its own
+                        if instr.is_jmp() || instr.is_br() || instr.is_ret() {
                             non_linear_before = true;
                             break;
                         }
@@ -473,7 +442,7 @@ impl BasicBlock {
                 i += 1;
             }
 
-            result.push_back(bb.clone());
+            result.push(bb_mut);
             i += 1;
         }
 
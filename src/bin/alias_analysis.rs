@@ -1,5 +1,7 @@
+use std::collections::BTreeMap;
+
 use bril::alias_analysis::AliasAnalysis;
-use bril::bril_syntax::Program;
+use bril::bril_syntax::{BrilType, InstructionOrLabel, Program};
 use bril::cfg::CFG;
 fn main() {
     // Filter out "nop" instructions for each function
@@ -10,8 +12,35 @@ fn main() {
 
     let mut alias = AliasAnalysis::new(&cfg);
     cfg.dataflow(&mut alias);
+
+    // The allocations every pointer may point to, each named after the variable it is allocated to
+    let mut allocations = BTreeMap::<usize, String>::new();
+    let mut pointers = Vec::<String>::new();
+    for bb in cfg.ordered_blocks() {
+        for ilb in bb.instrs.iter() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            if let (Some(dest), Some(BrilType::Ptr(_))) = (&i.dest, &i.bril_type) {
+                if i.is_alloc() {
+                    allocations.insert(i.instruction_id.unwrap(), dest.clone());
+                }
+                pointers.push(dest.clone());
+            }
+        }
+    }
+    for pointer in pointers.iter() {
+        let sites = alias.points_to(pointer).into_iter();
+        let sites = sites.map(|site| format!("alloc {}", allocations[&site]));
+        eprintln!(
+            "{}: points to [{}]",
+            pointer,
+            sites.collect::<Vec<_>>().join(", ")
+        );
+    }
+
     let prog = cfg.to_program();
 
     prog.stdout()
-    // cfg.print_hm();
+    // cfg.print_blocks();
 }
@@ -6,10 +6,10 @@ fn main() {
 
     let cfg = CFG::from_program(&mut prog);
 
-    println!("Block count : {}", cfg.hm.len());
+    println!("Block count : {}", cfg.order.len());
 
     //let prog = cfg.to_program();
     //
     //prog.stdout()
-    // cfg.print_hm();
+    // cfg.print_blocks();
 }
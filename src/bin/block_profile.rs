@@ -0,0 +1,36 @@
+use bril::{bril_syntax::Program, cfg::CFG, profile::Profile};
+
+fn main() {
+    // block_profile <file> prints how many times each block of the program on stdin ran and how
+    // many times each of its edges was taken, from a profile written by `brilirs --block-profile`
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: block_profile <profile file> < program.json");
+        std::process::exit(2);
+    };
+    let profile = match Profile::from_file(&path) {
+        Ok(profile) => profile,
+        Err(error) => {
+            eprintln!("block_profile: {}", error);
+            std::process::exit(2);
+        }
+    };
+
+    let mut prog = Program::stdin();
+    let mut cfg = CFG::from_program(&mut prog);
+    cfg.apply_profile(&profile);
+
+    for blocks in cfg.function_blocks() {
+        let name = &cfg.blocks[blocks[0]].func.as_ref().unwrap().name;
+        println!("@{} calls {}", name, profile.call_count(name));
+        for id in blocks {
+            let bb = &cfg.blocks[id];
+            let Some(count) = bb.execution_count else {
+                continue;
+            };
+            println!("  .{} {}", bb.get_label(), count);
+            for (succ, taken) in bb.edge_counts.iter() {
+                println!("    -> .{} {}", cfg.blocks[*succ].get_label(), taken);
+            }
+        }
+    }
+}
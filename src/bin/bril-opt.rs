@@ -0,0 +1,79 @@
+use bril::bril_syntax::Program;
+use bril::cfg::CFG;
+use bril::pass_manager::{PassManager, PassRegistry};
+use bril::profile::Profile;
+fn main() {
+    // --passes=ssa,sccp,licm,from-ssa runs the passes in order on a single CFG,
+    // --print-after=<pass> (repeatable, or `all`) dumps the program to stderr after that pass and
+    // --time-passes reports how long each pass took and how many times each analysis was
+    // computed. The IR is verified after every pass in debug builds, --verify-each /
+    // --no-verify-each override that. --profile=<file> attaches a `brilirs --block-profile`
+    // profile to the blocks before the first pass. --clean restores the argument names and drops
+    // the labels made up when building the CFG, see `CFG::to_clean_program`
+    let registry = PassRegistry::default();
+    let mut pipeline = String::new();
+    let mut print_after = Vec::new();
+    let mut time_passes = false;
+    let mut verify_each = None;
+    let mut profile = None;
+    let mut clean = false;
+    for arg in std::env::args().skip(1) {
+        if let Some(passes) = arg.strip_prefix("--passes=") {
+            pipeline = passes.to_string();
+        } else if let Some(pass) = arg.strip_prefix("--print-after=") {
+            print_after.push(pass.to_string());
+        } else if let Some(path) = arg.strip_prefix("--profile=") {
+            match Profile::from_file(path) {
+                Ok(p) => profile = Some(p),
+                Err(error) => {
+                    eprintln!("bril-opt: {}", error);
+                    std::process::exit(2);
+                }
+            }
+        } else if arg == "--clean" {
+            clean = true;
+        } else if arg == "--time-passes" {
+            time_passes = true;
+        } else if arg == "--verify-each" {
+            verify_each = Some(true);
+        } else if arg == "--no-verify-each" {
+            verify_each = Some(false);
+        } else if arg == "--list-passes" {
+            println!("{}", registry.names().join("\n"));
+            return;
+        } else {
+            eprintln!("bril-opt: unknown argument `{}`", arg);
+            std::process::exit(2);
+        }
+    }
+
+    let mut pass_manager = match PassManager::from_pipeline(&registry, &pipeline) {
+        Ok(pass_manager) => pass_manager,
+        Err(error) => {
+            eprintln!("bril-opt: {}", error);
+            std::process::exit(2);
+        }
+    };
+    if let Some(verify_each) = verify_each {
+        pass_manager.verify_each(verify_each);
+    }
+    for pass in print_after.iter() {
+        pass_manager.print_after(pass);
+    }
+
+    let mut prog = Program::stdin();
+    let mut cfg = CFG::from_program(&mut prog);
+    if let Some(profile) = &profile {
+        cfg.apply_profile(profile);
+    }
+    pass_manager.run(&mut cfg);
+    if time_passes {
+        eprint!("{}", pass_manager.timing_report());
+        eprint!("{}", pass_manager.analyses.report());
+    }
+
+    match clean {
+        true => cfg.to_clean_program().stdout(),
+        false => cfg.to_program().stdout(),
+    }
+}
@@ -5,10 +5,25 @@ fn main() {
     let mut prog = Program::stdin();
 
     let cfg = CFG::from_program(&mut prog);
+    // The edges of every block, in program order
+    for bb in cfg.ordered_blocks() {
+        let labels = |ids: &Vec<usize>| {
+            let labels = ids
+                .iter()
+                .map(|id| format!(".{}", cfg.blocks[*id].get_label()));
+            labels.collect::<Vec<_>>().join(" ")
+        };
+        eprintln!(
+            ".{}: successors [{}], predecessors [{}]",
+            bb.get_label(),
+            labels(&bb.successors),
+            labels(&bb.predecessors)
+        );
+    }
 
     //cfg.analyze_loop();
     let prog = cfg.to_program();
 
     prog.stdout()
-    // cfg.print_hm();
+    // cfg.print_blocks();
 }
@@ -4,10 +4,33 @@ fn main() {
     let mut prog = Program::stdin();
 
     let cfg = CFG::from_program(&mut prog);
-    let _dominance = DominanceDataFlow::new(&cfg);
+    let dominance = DominanceDataFlow::new(&cfg);
+    // The dominators, immediate dominator and dominance frontier of every block, in program order
+    let label = |id: &usize| format!(".{}", cfg.blocks[*id].get_label());
+    for bb in cfg.ordered_blocks() {
+        let dominators = dominance.domset.get(&bb.id).into_iter().flatten();
+        let mut frontier = dominance
+            .df
+            .get(&bb.id)
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        frontier.sort();
+        eprintln!(
+            "{}: dominators [{}], idom {}, frontier [{}]",
+            label(&bb.id),
+            dominators.map(label).collect::<Vec<_>>().join(" "),
+            dominance.idom.get(&bb.id).map_or("none".to_string(), label),
+            frontier
+                .into_iter()
+                .map(label)
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
 
     let prog = cfg.to_program();
 
     prog.stdout()
-    // cfg.print_hm();
+    // cfg.print_blocks();
 }
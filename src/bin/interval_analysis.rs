@@ -0,0 +1,29 @@
+use bril::bril_syntax::{InstructionOrLabel, Program};
+use bril::cfg::CFG;
+use bril::interval_analysis::IntervalAnalysis;
+fn main() {
+    let mut prog = Program::stdin();
+
+    let mut cfg = CFG::from_program(&mut prog);
+    let intervals = IntervalAnalysis::analyze(&cfg);
+
+    // Fold the comparisons the ranges decide
+    for id in cfg.order.clone() {
+        for ilb in cfg.blocks[id].instrs.iter_mut() {
+            if let InstructionOrLabel::Instruction(i) = ilb {
+                if let Some(range) = intervals.dest_range(i.instruction_id.unwrap()) {
+                    eprintln!("{}: {}", i.dest.clone().unwrap(), range);
+                }
+                if let Some(b) = intervals.fold_comparison(i) {
+                    i.op = "const".to_string();
+                    i.value =
+                        Some(serde_json::to_value(b).expect("This should absolutely not fail"));
+                    i.args = None;
+                }
+            }
+        }
+    }
+    let prog = cfg.to_program();
+
+    prog.stdout()
+}
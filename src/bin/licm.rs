@@ -11,5 +11,5 @@ fn main() {
     let prog = cfg.to_program();
 
     prog.stdout()
-    // cfg.print_hm();
+    // cfg.print_blocks();
 }
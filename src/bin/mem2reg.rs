@@ -0,0 +1,12 @@
+use bril::bril_syntax::Program;
+use bril::cfg::CFG;
+fn main() {
+    let mut prog = Program::stdin();
+
+    let mut cfg = CFG::from_program(&mut prog);
+    cfg.promote_memory_to_registers();
+    cfg.place_phi_functions_and_generate_ssa();
+    let prog = cfg.to_program();
+
+    prog.stdout()
+}
@@ -0,0 +1,17 @@
+use bril::bril_syntax::Program;
+use bril::cfg::CFG;
+use bril::memory_safety::MemorySafety;
+fn main() {
+    // Positions are only known if the program went through `bril2json -p`
+    let mut prog = Program::stdin();
+
+    let cfg = CFG::from_program(&mut prog);
+    let errors = MemorySafety::check(&cfg);
+    for error in errors.iter() {
+        println!("{}", error);
+    }
+
+    if !errors.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,36 @@
+use bril::bril_syntax::Program;
+use bril::cfg::CFG;
+use bril::register_allocation::DEFAULT_REGISTERS;
+fn main() {
+    // --registers=K sets the number of registers of the target
+    let mut registers = DEFAULT_REGISTERS;
+    for arg in std::env::args().skip(1) {
+        match arg.strip_prefix("--registers=") {
+            Some(k) => registers = k.parse::<usize>().expect("--registers expects a number"),
+            None => {
+                eprintln!("register_allocation: unknown argument `{}`", arg);
+                std::process::exit(2);
+            }
+        }
+    }
+    let mut prog = Program::stdin();
+
+    let mut cfg = CFG::from_program(&mut prog);
+    let allocations = cfg.allocate_registers(registers);
+
+    let mut spills = 0;
+    for allocation in allocations.iter() {
+        eprintln!(
+            "@{}: {} registers used, {} copies coalesced, {} spilled {:?}",
+            allocation.name,
+            allocation.registers_used(),
+            allocation.coalesced,
+            allocation.spilled.len(),
+            allocation.spilled
+        );
+        spills += allocation.spilled.len();
+    }
+    eprintln!("Total spills: {}", spills);
+
+    cfg.to_program().stdout()
+}
@@ -0,0 +1,31 @@
+use bril::analysis_manager::AnalysisManager;
+use bril::bril_syntax::Program;
+use bril::cfg::CFG;
+use bril::scalar_evolution::ScalarEvolution;
+fn main() {
+    let mut prog = Program::stdin();
+
+    let mut cfg = CFG::from_program(&mut prog);
+    cfg.place_phi_functions_and_generate_ssa();
+    let mut analyses = AnalysisManager::new();
+    for blocks in cfg.function_blocks() {
+        let scev = ScalarEvolution::new(&cfg, &blocks, &mut analyses);
+        for header in scev.headers() {
+            let label = cfg.blocks[header].get_label();
+            match scev.trip_count(header) {
+                Some(count) => eprintln!(
+                    ".{}: trip count {}",
+                    label,
+                    scev.describe_trip_count(&count)
+                ),
+                None => eprintln!(".{}: trip count unknown", label),
+            }
+            for (name, recurrence) in scev.induction_variables(header) {
+                eprintln!("{} = {}", name, scev.describe(&recurrence));
+            }
+        }
+    }
+    let prog = cfg.to_program();
+
+    prog.stdout()
+}
@@ -0,0 +1,70 @@
+use std::path::Path;
+use std::time::Duration;
+
+use bril::translation_validation::{percentage, TranslationValidator, Verdict};
+fn main() {
+    // --passes=ssa,sccp,from-ssa followed by files or directories of textual .bril programs, each
+    // run with the arguments of its `# ARGS:` comment before and after the pipeline.
+    // --timeout=<seconds> bounds every interpretation and the pipeline itself
+    let mut pipeline = String::new();
+    let mut timeout = None;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if let Some(passes) = arg.strip_prefix("--passes=") {
+            pipeline = passes.to_string();
+        } else if let Some(seconds) = arg.strip_prefix("--timeout=") {
+            match seconds.parse::<u64>() {
+                Ok(seconds) => timeout = Some(Duration::from_secs(seconds)),
+                Err(_) => {
+                    eprintln!("translation_validation: bad timeout `{}`", seconds);
+                    std::process::exit(2);
+                }
+            }
+        } else if arg.starts_with("--") {
+            eprintln!("translation_validation: unknown argument `{}`", arg);
+            std::process::exit(2);
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    let mut validator = match TranslationValidator::new(&pipeline) {
+        Ok(validator) => validator,
+        Err(error) => {
+            eprintln!("translation_validation: {}", error);
+            std::process::exit(2);
+        }
+    };
+    if let Some(timeout) = timeout {
+        validator = validator.timeout(timeout);
+    }
+
+    let mut validations = Vec::new();
+    for path in paths.iter() {
+        for validation in validator.validate_path(Path::new(path)) {
+            println!("{}", validation);
+            validations.push(validation);
+        }
+    }
+
+    let count = |f: fn(&Verdict) -> bool| validations.iter().filter(|v| f(&v.verdict)).count();
+    let (before, after) = validations
+        .iter()
+        .filter_map(|v| v.dynamic_instructions())
+        .fold((0, 0), |(before, after), (b, a)| (before + b, after + a));
+    println!(
+        "{} equivalent, {} mismatched, {} failed, {} skipped; {} -> {} dynamic instructions ({})",
+        count(|v| matches!(v, Verdict::Equivalent)),
+        count(|v| matches!(v, Verdict::Mismatch(_))),
+        count(|v| matches!(v, Verdict::PassFailed(_))),
+        count(|v| matches!(v, Verdict::Skipped(_))),
+        before,
+        after,
+        percentage(before, after)
+    );
+
+    let failed = |v: &Verdict| matches!(v, Verdict::Mismatch(_) | Verdict::PassFailed(_));
+    if validations.iter().any(|v| failed(&v.verdict)) {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,30 @@
+use bril::bril_syntax::Program;
+use bril::cfg::CFG;
+use bril::definite_assignment::{DefiniteAssignment, UndefinedPolicy};
+fn main() {
+    // With --insert-undefined, phi arguments without a reaching definition are rewritten to
+    // `__undefined` and the program is printed, otherwise every undefined use is reported
+    let insert_markers = std::env::args().any(|arg| arg == "--insert-undefined");
+    let mut prog = Program::stdin();
+
+    let mut cfg = CFG::from_program(&mut prog);
+    let undefined_uses = match insert_markers {
+        true => DefiniteAssignment::check(&mut cfg, UndefinedPolicy::InsertMarkers),
+        false => DefiniteAssignment::check(&mut cfg, UndefinedPolicy::Error),
+    };
+
+    if insert_markers {
+        cfg.to_program().stdout();
+        for undefined_use in undefined_uses.iter() {
+            eprintln!("{}", undefined_use);
+        }
+    } else {
+        for undefined_use in undefined_uses.iter() {
+            println!("{}", undefined_use);
+        }
+    }
+
+    if !undefined_uses.is_empty() {
+        std::process::exit(1);
+    }
+}
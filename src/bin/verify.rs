@@ -0,0 +1,15 @@
+use bril::bril_syntax::Program;
+use bril::cfg::CFG;
+fn main() {
+    // With --ssa, the program is also checked to be in SSA form
+    let ssa = std::env::args().any(|arg| arg == "--ssa");
+    let mut prog = Program::stdin();
+
+    let cfg = CFG::from_program(&mut prog);
+    if let Err(errors) = cfg.verify(ssa) {
+        for error in errors.iter() {
+            println!("{}", error);
+        }
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    aliases::BlockID,
+    analysis_manager::AnalysisManager,
+    bril_syntax::{Instruction, InstructionOrLabel},
+    cfg::CFG,
+};
+
+/// How much hotter an edge is assumed to be for each loop it sits in, when there is no profile
+pub const LOOP_WEIGHT: u64 = 8;
+
+/// INFO: This impl block is denoted to be about block layout
+impl CFG {
+    /// How many times the edge `from -> to` runs: its count if a profile was applied, otherwise
+    /// `LOOP_WEIGHT` to the power of the loop depth both ends share
+    fn edge_weight(&self, depth: &BTreeMap<BlockID, u32>, from: BlockID, to: BlockID) -> u64 {
+        if self.blocks[from].execution_count.is_some() {
+            return self.blocks[from].edge_counts.get(&to).copied().unwrap_or(0);
+        }
+        LOOP_WEIGHT.saturating_pow(depth[&from].min(depth[&to]))
+    }
+
+    /// Pettis–Hansen chaining of the blocks of one function: edges are visited from the heaviest
+    /// and glue the chain ending in their source to the chain starting at their target. Only
+    /// edges out of blocks ending in a `jmp` or falling through are considered, a `br` names both
+    /// of its targets so it never saves anything. Chains are then laid out in the order of their
+    /// first block, which keeps the entry block first
+    fn chain_blocks(&self, blocks: &[BlockID], depth: &BTreeMap<BlockID, u32>) -> Vec<BlockID> {
+        let mut edges = Vec::new();
+        for from in blocks.iter() {
+            if let [to] = self.blocks[*from].successors[..] {
+                if to != blocks[0] {
+                    edges.push((self.edge_weight(depth, *from, to), *from, to));
+                }
+            }
+        }
+        // Stable, so equally hot edges keep the program order
+        edges.sort_by_key(|(weight, _, _)| std::cmp::Reverse(*weight));
+
+        let mut chains = blocks.iter().map(|id| vec![*id]).collect::<Vec<_>>();
+        let mut chain_of = blocks
+            .iter()
+            .enumerate()
+            .map(|(chain, id)| (*id, chain))
+            .collect::<BTreeMap<_, _>>();
+        for (_, from, to) in edges {
+            let (tail, head) = (chain_of[&from], chain_of[&to]);
+            if tail == head || chains[tail].last() != Some(&from) || chains[head][0] != to {
+                continue;
+            }
+            let moved = std::mem::take(&mut chains[head]);
+            for id in moved.iter() {
+                chain_of.insert(*id, tail);
+            }
+            chains[tail].extend(moved);
+        }
+        chains.into_iter().flatten().collect()
+    }
+
+    /// Make the terminators agree with `layout`: a `jmp` to the next block is dropped, a block
+    /// falling through to anything else gets a `jmp`, and the block that fell off the end of the
+    /// function gets a `ret` if it is no longer last
+    fn fix_terminators(&mut self, layout: &[BlockID]) {
+        for (position, id) in layout.iter().enumerate() {
+            let next = layout.get(position + 1).copied();
+            let successor = self.blocks[*id].successors.first().copied();
+            let last = match self.blocks[*id].instrs.last() {
+                Some(InstructionOrLabel::Instruction(i)) => Some(i.clone()),
+                _ => None,
+            };
+            let terminator = match last {
+                Some(i) if i.is_jmp() => {
+                    if next.is_some() && next == successor {
+                        self.blocks[*id].instrs.pop();
+                    }
+                    continue;
+                }
+                Some(i) if i.is_br() || i.is_ret() => continue,
+                _ => match successor {
+                    Some(successor) if next != Some(successor) => {
+                        let label = self.blocks[successor].get_label();
+                        Instruction::new_control_instruction(
+                            Some(&label),
+                            &mut self.instruction_counter,
+                        )
+                    }
+                    None if next.is_some() => {
+                        Instruction::new_control_instruction(None, &mut self.instruction_counter)
+                    }
+                    _ => continue,
+                },
+            };
+            self.blocks[*id].push_back(&terminator);
+        }
+    }
+
+    /// Reorder the blocks of every function so the hottest edges become fall-throughs and drop
+    /// the jumps the new order makes redundant. Edges are weighted with the profile if one was
+    /// applied with `apply_profile`, with the loop depth otherwise
+    pub fn layout_blocks_with(&mut self, analyses: &mut AnalysisManager) {
+        let mut order = Vec::new();
+        for blocks in self.function_blocks() {
+            let loops = analyses.loops(self, &blocks);
+            let mut depth = blocks
+                .iter()
+                .map(|id| (*id, 0))
+                .collect::<BTreeMap<BlockID, u32>>();
+            for body in loops.bodies.values() {
+                for id in body.iter() {
+                    *depth.entry(*id).or_default() += 1;
+                }
+            }
+            let layout = self.chain_blocks(&blocks, &depth);
+            self.fix_terminators(&layout);
+            order.extend(layout);
+        }
+        self.order = order;
+    }
+
+    pub fn layout_blocks(&mut self) {
+        self.layout_blocks_with(&mut AnalysisManager::new())
+    }
+}
@@ -1,6 +1,7 @@
 use serde_derive::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{
+    collections::BTreeSet,
     fmt::Display,
     io::{self, Read},
 };
@@ -12,6 +13,7 @@ pub enum BrilType {
     Int,
     Bool,
     Float,
+    Char,
     Ptr(Box<BrilType>), // Ptr variant that points to another BrilType
 }
 
@@ -21,6 +23,7 @@ impl Display for BrilType {
             BrilType::Int => write!(f, "int"),
             BrilType::Bool => write!(f, "bool"),
             BrilType::Float => write!(f, "float"),
+            BrilType::Char => write!(f, "char"),
             BrilType::Ptr(bril_type) => write!(f, "ptr<{}>", bril_type),
         }
     }
@@ -49,6 +52,19 @@ pub struct Instruction {
     pub other_fields: Value, // Store unknown fields here
 }
 
+/// Source position attached by `bril2json -p`, kept in `other_fields` under "pos"
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Position {
+    pub row: u64,
+    pub col: u64,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.row, self.col)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Label {
     pub label: String,
@@ -71,7 +87,7 @@ impl InstructionOrLabel {
     pub fn new_phi(def: String, instruction_counter: &mut usize) -> Self {
         InstructionOrLabel::Instruction(Instruction::new_phi(def, instruction_counter))
     }
-    pub fn new_dummy_head(header_name: String, instruction_counter: &mut usize) -> Self {
+    pub fn new_dummy_head(header_name: String, _instruction_counter: &mut usize) -> Self {
         InstructionOrLabel::Label(Label { label: header_name })
     }
 }
@@ -79,7 +95,7 @@ impl Display for InstructionOrLabel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             InstructionOrLabel::Label(lb) => write!(f, "{}", lb.clone().label),
-            InstructionOrLabel::Instruction(ins) => write!(f, "{}", ins.clone().to_string()),
+            InstructionOrLabel::Instruction(ins) => write!(f, "{}", ins),
         }
     }
 }
@@ -130,6 +146,91 @@ impl Function {
             false
         }
     }
+
+    fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.instrs.iter().filter_map(|ilb| match ilb {
+            InstructionOrLabel::Instruction(i) => Some(i),
+            InstructionOrLabel::Label(_) => None,
+        })
+    }
+
+    fn instructions_mut(&mut self) -> impl Iterator<Item = &mut Instruction> {
+        self.instrs.iter_mut().filter_map(|ilb| match ilb {
+            InstructionOrLabel::Instruction(i) => Some(i),
+            InstructionOrLabel::Label(_) => None,
+        })
+    }
+
+    /// Undo the `<function>_<arg>` renaming of the arguments done when building the CFG. The copy
+    /// `arg = id <function>_<arg>` goes away, or reads the argument under its own name when the
+    /// copy was renamed since. Arguments nothing reads are renamed too, arguments read anywhere
+    /// else keep their name
+    pub fn restore_argument_names(&mut self) {
+        let prefix = format!("{}_", self.name);
+        let mut args = self.args.clone().unwrap_or_default();
+        for position in 0..args.len() {
+            let Some(original) = args[position]
+                .name
+                .strip_prefix(&prefix)
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            let reads = |i: &Instruction, name: &String| i.args.iter().flatten().any(|a| a == name);
+            let mentions =
+                |i: &Instruction, name: &String| i.dest.as_ref() == Some(name) || reads(i, name);
+            let readers = self
+                .instructions()
+                .filter(|i| reads(i, &args[position].name))
+                .collect::<Vec<_>>();
+            match readers[..] {
+                [] if args.iter().all(|arg| arg.name != original) => {}
+                [copy] if copy.is_id() => {
+                    let copy_id = copy.instruction_id;
+                    if copy.dest.as_ref() == Some(&original) {
+                        self.instrs.retain(|ilb| match ilb {
+                            InstructionOrLabel::Instruction(i) => i.instruction_id != copy_id,
+                            InstructionOrLabel::Label(_) => true,
+                        });
+                    } else if self.instructions().all(|i| !mentions(i, &original)) {
+                        for i in self.instructions_mut() {
+                            if i.instruction_id == copy_id {
+                                i.args = Some(vec![original.clone()]);
+                            }
+                        }
+                    } else {
+                        continue;
+                    }
+                }
+                _ => continue,
+            }
+            args[position].name = original;
+        }
+        if self.args.is_some() {
+            self.args = Some(args);
+        }
+    }
+
+    /// Remove the labels not in `keep` that no instruction refers to, unless phis follow them
+    pub fn drop_unused_labels(&mut self, keep: &BTreeSet<String>) {
+        let referenced = self
+            .instructions()
+            .flat_map(|i| i.labels.iter().flatten().cloned())
+            .collect::<BTreeSet<_>>();
+        let instrs = std::mem::take(&mut self.instrs);
+        for (position, ilb) in instrs.iter().enumerate() {
+            if let InstructionOrLabel::Label(l) = ilb {
+                let starts_with_phi = matches!(
+                    instrs.get(position + 1),
+                    Some(InstructionOrLabel::Instruction(i)) if i.is_phi()
+                );
+                if !keep.contains(&l.label) && !referenced.contains(&l.label) && !starts_with_phi {
+                    continue;
+                }
+            }
+            self.instrs.push(ilb.clone());
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -158,15 +259,15 @@ impl Instruction {
     }
 
     pub fn new_id_instruction(
-        dest: &String,
-        src: &String,
+        dest: &str,
+        src: &str,
         _type: &BrilType,
         instruction_counter: &mut usize,
     ) -> InstructionOrLabel {
         let result = InstructionOrLabel::Instruction(Self {
             op: "id".to_string(),
-            dest: Some(dest.clone()),
-            args: vec![src.clone()].into(),
+            dest: Some(dest.to_string()),
+            args: vec![src.to_string()].into(),
             bril_type: Some(_type.clone()),
             value: Default::default(),
             funcs: Default::default(),
@@ -179,6 +280,72 @@ impl Instruction {
         result
     }
 
+    /// `jmp .label`, or `ret` when `label` is `None`
+    pub fn new_control_instruction(
+        label: Option<&str>,
+        instruction_counter: &mut usize,
+    ) -> InstructionOrLabel {
+        let result = InstructionOrLabel::Instruction(Self {
+            op: if label.is_some() { "jmp" } else { "ret" }.to_string(),
+            dest: Default::default(),
+            args: Default::default(),
+            bril_type: Default::default(),
+            value: Default::default(),
+            funcs: Default::default(),
+            labels: label.map(|label| vec![label.to_string()]),
+            instruction_id: Some(*instruction_counter),
+            other_fields: Default::default(),
+        });
+
+        *instruction_counter += 1;
+        result
+    }
+
+    /// `speculate`, `commit` or `guard`, which take their condition in `args` and their label in
+    /// `labels`
+    pub fn new_effect_instruction(
+        op: &str,
+        args: Vec<String>,
+        labels: Vec<String>,
+        instruction_counter: &mut usize,
+    ) -> InstructionOrLabel {
+        let result = InstructionOrLabel::Instruction(Self {
+            op: op.to_string(),
+            dest: Default::default(),
+            args: Some(args).filter(|args| !args.is_empty()),
+            bril_type: Default::default(),
+            value: Default::default(),
+            funcs: Default::default(),
+            labels: Some(labels).filter(|labels| !labels.is_empty()),
+            instruction_id: Some(*instruction_counter),
+            other_fields: Default::default(),
+        });
+
+        *instruction_counter += 1;
+        result
+    }
+
+    pub fn new_not_instruction(
+        dest: &str,
+        src: &str,
+        instruction_counter: &mut usize,
+    ) -> InstructionOrLabel {
+        let result = InstructionOrLabel::Instruction(Self {
+            op: "not".to_string(),
+            dest: Some(dest.to_string()),
+            args: vec![src.to_string()].into(),
+            bril_type: Some(BrilType::Bool),
+            value: Default::default(),
+            funcs: Default::default(),
+            labels: Default::default(),
+            instruction_id: Some(*instruction_counter),
+            other_fields: Default::default(),
+        });
+
+        *instruction_counter += 1;
+        result
+    }
+
     pub fn rename_phi(&mut self, from: String, to: String, block_label: String) {
         assert!(self.is_phi());
 
@@ -225,6 +392,64 @@ impl Instruction {
     pub fn is_le(&self) -> bool {
         &self.op == "le"
     }
+    pub fn is_fadd(&self) -> bool {
+        &self.op == "fadd"
+    }
+    pub fn is_fsub(&self) -> bool {
+        &self.op == "fsub"
+    }
+    pub fn is_fmul(&self) -> bool {
+        &self.op == "fmul"
+    }
+    pub fn is_fdiv(&self) -> bool {
+        &self.op == "fdiv"
+    }
+    pub fn is_feq(&self) -> bool {
+        &self.op == "feq"
+    }
+    pub fn is_flt(&self) -> bool {
+        &self.op == "flt"
+    }
+    pub fn is_fgt(&self) -> bool {
+        &self.op == "fgt"
+    }
+    pub fn is_fle(&self) -> bool {
+        &self.op == "fle"
+    }
+    pub fn is_fge(&self) -> bool {
+        &self.op == "fge"
+    }
+    pub fn is_ceq(&self) -> bool {
+        &self.op == "ceq"
+    }
+    pub fn is_clt(&self) -> bool {
+        &self.op == "clt"
+    }
+    pub fn is_cgt(&self) -> bool {
+        &self.op == "cgt"
+    }
+    pub fn is_cle(&self) -> bool {
+        &self.op == "cle"
+    }
+    pub fn is_cge(&self) -> bool {
+        &self.op == "cge"
+    }
+    pub fn is_char2int(&self) -> bool {
+        &self.op == "char2int"
+    }
+    pub fn is_int2char(&self) -> bool {
+        &self.op == "int2char"
+    }
+    /// Float arithmetic and comparisons, all of them take two float arguments
+    pub fn is_float_operation(&self) -> bool {
+        matches!(
+            self.op.as_str(),
+            "fadd" | "fsub" | "fmul" | "fdiv" | "feq" | "flt" | "fgt" | "fle" | "fge"
+        )
+    }
+    pub fn is_char_comparison(&self) -> bool {
+        matches!(self.op.as_str(), "ceq" | "clt" | "cgt" | "cle" | "cge")
+    }
     pub fn is_const(&self) -> bool {
         &self.op == "const"
     }
@@ -261,27 +486,49 @@ impl Instruction {
     pub fn is_print(&self) -> bool {
         &self.op == "print"
     }
+    pub fn is_free(&self) -> bool {
+        &self.op == "free"
+    }
     pub fn is_nonlinear(&self) -> bool {
         self.is_jmp() || self.is_call() || self.is_print() || self.is_br()
     }
 
+    pub fn is_guard(&self) -> bool {
+        &self.op == "guard"
+    }
+
     pub fn has_side_effects(&self) -> bool {
         matches!(
             self.op.as_str(),
-            "print" | "call" | "alloc" | "free" | "store" | "ret"
+            "print"
+                | "call"
+                | "alloc"
+                | "free"
+                | "store"
+                | "ret"
+                | "speculate"
+                | "commit"
+                | "guard"
         )
     }
 
+    pub fn position(&self) -> Option<Position> {
+        serde_json::from_value(self.other_fields.get("pos")?.clone()).ok()
+    }
+
     pub fn to_const_int(&mut self, i: u64) {
         self.op = "const".to_string();
         self.value = Some(json!(i));
     }
+}
 
-    /// this is for graphviz dot
-    pub fn to_string(&self) -> String {
+/// this is for graphviz dot
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // sum: int = add n five;
         if self.is_add() {
-            format!(
+            write!(
+                f,
                 "{}: {} = {} {} {};",
                 self.dest.clone().unwrap(),
                 self.bril_type.clone().unwrap(),
@@ -290,19 +537,21 @@ impl Instruction {
                 self.args.clone().unwrap()[1].to_string().replace("\"", "")
             )
         } else if self.is_const() {
-            return format!(
+            write!(
+                f,
                 "{}: {} = {} {};",
                 self.dest.clone().unwrap(),
                 self.bril_type.clone().unwrap(),
                 self.op,
                 self.value.clone().unwrap(),
-            );
+            )
         } else if self.is_ret() {
-            return format!(
+            write!(
+                f,
                 "{} {};",
                 self.op,
                 self.args.clone().unwrap()[0].to_string().replace("\"", ""),
-            );
+            )
         } else if self.is_call() {
             // let dest = match &self.dest {
             //     Some(k) => format!("{} :", k.clone()),
@@ -318,11 +567,11 @@ impl Instruction {
             //     Some(args) => args[1].to_string(),
             //     None => "".to_string(),
             // };
-            return "...call func".to_string();
+            write!(f, "...call func")
         } else if self.is_print() {
-            return "print ...".to_string();
+            write!(f, "print ...")
         } else {
-            "default".to_string()
+            write!(f, "default")
         }
 
         // TODO: support const, call, ret, add
@@ -344,4 +593,9 @@ impl Program {
     pub fn stdout(&self) {
         serde_json::to_writer_pretty(io::stdout(), &self).expect("Failed to write JSON");
     }
+
+    pub fn stderr(&self) {
+        serde_json::to_writer_pretty(io::stderr(), &self).expect("Failed to write JSON");
+        eprintln!();
+    }
 }
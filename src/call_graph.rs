@@ -0,0 +1,79 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{aliases::BlockID, bril_syntax::InstructionOrLabel, cfg::CFG};
+
+/// A `call` instruction, at `position` in the instructions of `block`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+    pub caller: String,
+    pub callee: String,
+    pub block: BlockID,
+    pub position: usize,
+}
+
+/// Which function calls which, and from where
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    /// The blocks of every function by name, the entry block first
+    pub functions: BTreeMap<String, Vec<BlockID>>,
+    /// Every call in the program, in program order
+    pub call_sites: Vec<CallSite>,
+}
+
+impl CallGraph {
+    pub fn new(cfg: &CFG) -> Self {
+        let mut result = Self::default();
+        for blocks in cfg.function_blocks() {
+            let caller = cfg.blocks[blocks[0]].func.as_ref().unwrap().name.clone();
+            for id in blocks.iter() {
+                for (position, ilb) in cfg.blocks[*id].instrs.iter().enumerate() {
+                    let InstructionOrLabel::Instruction(i) = ilb else {
+                        continue;
+                    };
+                    let Some(callee) = i.funcs.iter().flatten().next().filter(|_| i.is_call())
+                    else {
+                        continue;
+                    };
+                    result.call_sites.push(CallSite {
+                        caller: caller.clone(),
+                        callee: callee.clone(),
+                        block: *id,
+                        position,
+                    });
+                }
+            }
+            result.functions.insert(caller, blocks);
+        }
+        result
+    }
+
+    /// The calls to `callee`
+    pub fn calls_to<'a>(&'a self, callee: &'a str) -> impl Iterator<Item = &'a CallSite> {
+        self.call_sites
+            .iter()
+            .filter(move |site| site.callee == callee)
+    }
+
+    /// The functions `caller` calls
+    pub fn callees(&self, caller: &str) -> BTreeSet<&str> {
+        self.call_sites
+            .iter()
+            .filter(|site| site.caller == caller)
+            .map(|site| site.callee.as_str())
+            .collect()
+    }
+
+    /// The functions `caller` calls, directly or not. It is among them if it is recursive
+    pub fn reachable_from(&self, caller: &str) -> BTreeSet<&str> {
+        let mut result = BTreeSet::new();
+        let mut work_list = vec![caller];
+        while let Some(function) = work_list.pop() {
+            for callee in self.callees(function) {
+                if result.insert(callee) {
+                    work_list.push(callee);
+                }
+            }
+        }
+        result
+    }
+}
@@ -1,21 +1,26 @@
-use crate::aliases::{BbPtr, BlockID, IdToBbMap};
+use crate::aliases::{BlockID, SSANameStack};
+use crate::analysis_manager::{AnalysisManager, PreservedAnalyses};
 use crate::basic_block::BasicBlock;
-use crate::bril_syntax::{Function, InstructionOrLabel, Program};
-use crate::dominance::DominanceDataFlow;
+use crate::bril_syntax::{BrilType, Function, Instruction, InstructionOrLabel, Program};
+use crate::data_flow::DataFlowAnalysis;
+use crate::definite_assignment::{DefiniteAssignment, UndefinedPolicy, UNDEFINED_MARKER};
+use crate::liveness::LivenessKind;
 use crate::loops::Loops;
-use std::collections::{LinkedList, VecDeque};
+use crate::profile::Profile;
+use std::collections::VecDeque;
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
 };
 
+/// The control flow graph of a whole program, as an arena of blocks
 #[derive(Debug)]
 pub struct CFG {
-    pub hm: HashMap<InstructionOrLabel, BbPtr>,
-    pub basic_block_counter: BlockID,
+    /// Every block, at the index of its id. A block taken out of `order` keeps its slot
+    pub blocks: Vec<BasicBlock>,
+    /// The blocks in program order, the entry block of each function first
+    pub order: Vec<BlockID>,
     pub instruction_counter: usize,
-    pub id_to_bb: IdToBbMap,
-    pub bb_ptr_vec: LinkedList<BbPtr>,
 }
 // main:
 // @main
@@ -46,11 +51,7 @@ impl CFG {
     //    // O(n)
     //}
     pub fn from_program(p: &mut Program) -> Self {
-        let mut hm = HashMap::<InstructionOrLabel, BbPtr>::new();
-
-        let mut id_to_bb = HashMap::<BlockID, BbPtr>::new();
-
-        let mut bb_ptr_vec = LinkedList::<BbPtr>::new();
+        let mut blocks = Vec::<BasicBlock>::new();
         let mut basic_block_counter: BlockID = 0;
         let mut instruction_counter: usize = 0;
         // Initialize the id first.
@@ -74,66 +75,66 @@ impl CFG {
                     &mut basic_block_counter,
                     &mut instruction_counter,
                 );
-            for bb in simple_basic_blocks_vec_from_function {
-                hm.insert(bb.borrow().instrs.front().unwrap().clone(), bb.clone());
-                id_to_bb.insert(bb.borrow().id, bb.clone());
-                bb_ptr_vec.push_back(bb.clone());
+            // Labels are only unique inside a function, branches are resolved with the blocks of
+            // their own function
+            let mut labels = HashMap::<InstructionOrLabel, BlockID>::new();
+            for bb in simple_basic_blocks_vec_from_function.iter() {
+                labels.insert(bb.instrs.first().unwrap().clone(), bb.id);
             }
-        }
-        // Iterate to connect them
-        for i in hm.clone().into_values() {
-            let mut bi = i.borrow_mut();
-            if !bi.instrs.is_empty() {
-                match bi.instrs.clone().into_iter().last() {
-                    Some(instr) => match instr {
-                        InstructionOrLabel::Label(_) => {
-                            //eprintln!("This should not happen in CFG::from_program")
-                        }
-                        InstructionOrLabel::Instruction(ins) => {
-                            if ins.is_br() {
-                                let first_branch_label = &InstructionOrLabel::from(
-                                    ins.labels.clone().unwrap()[1].clone(),
-                                );
-                                let second_branch_label = &InstructionOrLabel::from(
-                                    ins.labels.clone().unwrap()[0].clone(),
-                                );
-                                bi.successors.push(hm[first_branch_label].clone());
-                                hm[first_branch_label]
-                                    .borrow_mut()
-                                    .predecessors
-                                    .push(i.clone());
-
-                                bi.successors.push(hm[second_branch_label].clone());
-                                hm[second_branch_label]
-                                    .borrow_mut()
-                                    .predecessors
-                                    .push(i.clone());
-                            } else if ins.is_jmp() {
-                                let first_branch_label = &InstructionOrLabel::from(
-                                    ins.labels.clone().unwrap()[0].clone(),
-                                );
-                                bi.successors.push(hm[first_branch_label].clone());
-                                hm[first_branch_label]
-                                    .borrow_mut()
-                                    .predecessors
-                                    .push(i.clone());
-                            }
-                        }
-                    },
-                    None => continue,
+            let first = blocks.len();
+            blocks.extend(simple_basic_blocks_vec_from_function);
+
+            // Iterate to connect them
+            for id in first..blocks.len() {
+                let Some(InstructionOrLabel::Instruction(ins)) = blocks[id].instrs.last() else {
+                    continue;
+                };
+                let mut targets = match (ins.is_br(), ins.is_jmp()) {
+                    (true, _) => {
+                        let labels = ins.labels.clone().unwrap();
+                        vec![labels[1].clone(), labels[0].clone()]
+                    }
+                    (_, true) => vec![ins.labels.clone().unwrap()[0].clone()],
+                    _ => Vec::new(),
+                };
+                // A failing `guard` leaves the block from the middle, after the other targets
+                for ilb in blocks[id].instrs.iter() {
+                    let InstructionOrLabel::Instruction(i) = ilb else {
+                        continue;
+                    };
+                    let label = i.labels.iter().flatten().next().filter(|_| i.is_guard());
+                    if let Some(label) = label.filter(|label| !targets.contains(label)) {
+                        targets.push(label.clone());
+                    }
+                }
+                for target in targets {
+                    let target = labels[&InstructionOrLabel::from(target)];
+                    blocks[id].successors.push(target);
+                    blocks[target].predecessors.push(id);
                 }
             }
         }
 
         Self {
-            hm,
-            basic_block_counter,
+            order: blocks.iter().map(|bb| bb.id).collect(),
+            blocks,
             instruction_counter,
-            id_to_bb,
-            bb_ptr_vec,
         }
     }
 
+    /// The blocks in program order
+    pub fn ordered_blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.order.iter().map(|id| &self.blocks[*id])
+    }
+
+    /// A new block holding only `label`, outside of `order` and without edges
+    pub fn new_block(&mut self, label: &str) -> BlockID {
+        let mut id = self.blocks.len();
+        self.blocks
+            .push(BasicBlock::default_with_label(&mut id, label));
+        id - 1
+    }
+
     pub fn to_program(&self) -> Program {
         let mut p = Program {
             functions: Vec::default(),
@@ -155,19 +156,19 @@ impl CFG {
         let mut first_func = true;
 
         //eprintln!("Size of bb ptr vec : {}", self.bb_ptr_vec.len());
-        for bb_ptr in self.bb_ptr_vec.iter() {
-            //eprintln!("{}", bb_ptr.borrow().func.is_some());
-            if bb_ptr.borrow().func.is_some() {
+        for bb in self.ordered_blocks() {
+            //eprintln!("{}", bb.func.is_some());
+            if bb.func.is_some() {
                 if first_func {
                     first_func = false;
                 } else {
                     p.functions.push(func);
                 }
-                func = bb_ptr.borrow().func.clone().unwrap();
+                func = bb.func.clone().unwrap();
                 func.instrs.clear();
             }
-            //eprintln!("Getting instr from {}", bb_ptr.borrow().id);
-            for instr in bb_ptr.borrow().instrs.iter() {
+            //eprintln!("Getting instr from {}", bb.id);
+            for instr in bb.instrs.iter() {
                 func.instrs.push(instr.clone());
                 match func.instrs.last_mut().unwrap() {
                     InstructionOrLabel::Label(_) => {}
@@ -183,10 +184,44 @@ impl CFG {
         p
     }
 
-    pub fn print_hm(&self) {
-        for i in self.hm.iter() {
-            eprintln!("{:?}", i.0);
-            eprintln!("{:?}", i.1.borrow())
+    /// Like `to_program`, but as close to the source as the passes allow: arguments get their
+    /// names back and the labels made up for the entry block and the blocks without one are
+    /// dropped when nothing jumps to them. A program no pass changed comes out as it came in
+    pub fn to_clean_program(&self) -> Program {
+        let mut source_labels = BTreeMap::<String, BTreeSet<String>>::new();
+        for blocks in self.function_blocks() {
+            let func = self.blocks[blocks[0]].func.as_ref().unwrap();
+            let labels = func.instrs.iter().filter_map(|ilb| match ilb {
+                InstructionOrLabel::Label(l) => Some(l.label.clone()),
+                _ => None,
+            });
+            source_labels.insert(func.name.clone(), labels.collect());
+        }
+
+        let mut p = self.to_program();
+        for func in p.functions.iter_mut() {
+            func.restore_argument_names();
+            func.drop_unused_labels(&source_labels[&func.name]);
+        }
+        p
+    }
+
+    /// Split `order` into one group of blocks per function, in program order. The first block of
+    /// every group is the entry block of the function (the one carrying `func`)
+    pub fn function_blocks(&self) -> Vec<Vec<BlockID>> {
+        let mut result = Vec::<Vec<BlockID>>::new();
+        for bb in self.ordered_blocks() {
+            if bb.func.is_some() || result.is_empty() {
+                result.push(Vec::new());
+            }
+            result.last_mut().unwrap().push(bb.id);
+        }
+        result
+    }
+
+    pub fn print_blocks(&self) {
+        for bb in self.ordered_blocks() {
+            eprintln!("{:?}", bb)
         }
     }
 
@@ -237,152 +272,537 @@ impl CFG {
     //
 }
 
+/// Which variables get a phi when building SSA form, each mode placing a subset of the phis of
+/// the one before
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SsaMode {
+    /// A phi at the iterated dominance frontier of the definitions of every variable
+    Minimal,
+    /// Only for the variables read in some block before being written in it
+    SemiPruned,
+    /// Only where the variable is live on entry of the block, so no phi is dead
+    #[default]
+    Pruned,
+}
+
 /// INFO: This impl block is denoted to be about SSA
 impl CFG {
-    /// A map that maps a variable to all the block that defines it
-    pub fn global_variables(&mut self) -> (BTreeSet<String>, BTreeMap<String, BTreeSet<BlockID>>) {
-        // For each blocks,
-        //   For each def in each block
-        //     Let a particular def be about v
-        //     Add def[v].insert(block)
+    /// The variables of the function read in a block before any write to them in that block,
+    /// and the blocks defining each variable
+    pub fn global_variables(
+        &self,
+        blocks: &[BlockID],
+    ) -> (BTreeSet<String>, BTreeMap<String, BTreeSet<BlockID>>) {
         let mut globals = BTreeSet::<String>::new();
-        let mut blocks = BTreeMap::<String, BTreeSet<BlockID>>::new();
-        for (_, bbrc) in self.hm.iter() {
+        let mut defs = BTreeMap::<String, BTreeSet<BlockID>>::new();
+        for bb in blocks.iter().map(|id| &self.blocks[*id]) {
             let mut var_kill = BTreeSet::<String>::new();
+            for ilb in bb.instrs.iter() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    continue;
+                };
+                for arg in i.args.iter().flatten() {
+                    if !var_kill.contains(arg) {
+                        globals.insert(arg.clone());
+                    }
+                }
+                if let Some(dest) = &i.dest {
+                    var_kill.insert(dest.clone());
+                    defs.entry(dest.clone()).or_default().insert(bb.id);
+                }
+            }
+        }
 
-            for ilb in bbrc.borrow().instrs.iter() {
-                if let InstructionOrLabel::Instruction(i) = ilb {
-                    if i.dest.is_none() || i.args.is_none() {
+        eprintln!("Set of global variables: {:?}", globals);
+        eprintln!("Map of global variables to blocks: {:?}", defs);
+        (globals, defs)
+    }
+
+    /// The type of every variable of the function, from its definitions
+    fn variable_types(&self, blocks: &[BlockID]) -> BTreeMap<String, BrilType> {
+        let mut types = BTreeMap::new();
+        for ilb in blocks.iter().flat_map(|id| self.blocks[*id].instrs.iter()) {
+            if let InstructionOrLabel::Instruction(Instruction {
+                dest: Some(dest),
+                bril_type: Some(bril_type),
+                ..
+            }) = ilb
+            {
+                types.entry(dest.clone()).or_insert(bril_type.clone());
+            }
+        }
+        types
+    }
+
+    /// Insert the phis of one function: the variables `mode` selects get an empty phi, typed like
+    /// their definitions, at the iterated dominance frontier of the blocks defining them
+    fn place_phi_functions(
+        &mut self,
+        blocks: &[BlockID],
+        mode: SsaMode,
+        df: &BTreeMap<BlockID, HashSet<BlockID>>,
+        analyses: &mut AnalysisManager,
+    ) {
+        let (globals, defs) = self.global_variables(blocks);
+        let types = self.variable_types(blocks);
+        let liveness = match mode {
+            SsaMode::Pruned => Some(analyses.liveness(self, blocks, LivenessKind::Live)),
+            _ => None,
+        };
+        for (name, def_blocks) in defs.iter() {
+            if mode != SsaMode::Minimal && !globals.contains(name) {
+                continue;
+            }
+            let mut work_list = def_blocks.iter().copied().collect::<VecDeque<BlockID>>();
+            while let Some(block_id) = work_list.pop_front() {
+                for d in df.get(&block_id).into_iter().flatten() {
+                    if !matches!(
+                        self.blocks[*d].instrs.first(),
+                        Some(InstructionOrLabel::Label(_))
+                    ) || self.blocks[*d].contains_phi_def(name)
+                    {
                         continue;
                     }
-                    if let Some(args) = &i.args {
-                        for arg in args {
-                            if !var_kill.contains(arg) {
-                                globals.insert(arg.clone());
-                            }
+                    if let Some(liveness) = &liveness {
+                        if !liveness.live_in(*d).contains(name) {
+                            continue;
                         }
                     }
-                    if let Some(dest) = &i.dest {
-                        var_kill.insert(dest.clone());
+                    eprintln!("Constructing phi with {name} at {d}");
+                    let block_mut_b = &mut self.blocks[*d];
+                    block_mut_b.insert_phi_def(name, &mut self.instruction_counter);
+                    block_mut_b.set_phi_type(name, types.get(name));
+                    work_list.push_back(*d);
+                }
+            }
+        }
+    }
+
+    pub fn place_phi_functions_and_generate_ssa(&mut self) {
+        self.place_phi_functions_and_generate_ssa_with(&mut AnalysisManager::new())
+    }
+
+    /// Same as `place_phi_functions_and_generate_ssa`, reusing the dominance cached in `analyses`
+    pub fn place_phi_functions_and_generate_ssa_with(&mut self, analyses: &mut AnalysisManager) {
+        self.place_phi_functions_and_generate_ssa_in(SsaMode::default(), analyses)
+    }
+
+    /// Build SSA form, placing the phis `mode` asks for
+    pub fn place_phi_functions_and_generate_ssa_in(
+        &mut self,
+        mode: SsaMode,
+        analyses: &mut AnalysisManager,
+    ) {
+        let dff = analyses.program_dominance(self);
+        for blocks in self.function_blocks() {
+            self.place_phi_functions(&blocks, mode, &dff.df, analyses);
+        }
 
-                        blocks
-                            .entry(dest.clone())
-                            .or_default()
-                            .insert(bbrc.borrow().id);
+        // A variable without a definition on the way reads as `UNDEFINED_MARKER` in a phi
+        let stack_of = SSANameStack::new();
+        // The variable of every phi by instruction id, a phi may be renamed before a
+        // predecessor fills in its argument
+        let mut phi_variables = BTreeMap::<usize, String>::new();
+        let mut map_from_id_to_instrs = BTreeMap::<usize, Vec<InstructionOrLabel>>::new();
+        for bb in self.ordered_blocks() {
+            for ilb in bb.instrs.iter() {
+                if let InstructionOrLabel::Instruction(i) = ilb {
+                    if i.is_phi() {
+                        phi_variables.insert(i.instruction_id.unwrap(), i.dest.clone().unwrap());
                     }
-                } else {
-                    continue;
-                };
+                }
             }
+            map_from_id_to_instrs
+                .entry(bb.id)
+                .or_insert(bb.instrs.clone());
         }
 
-        eprintln!("Set of global variables: {:?}", globals);
-        eprintln!("Map of global variables to blocks: {:?}", blocks);
-        (globals, blocks)
+        for blocks in self.function_blocks() {
+            // Fresh names must not clash with a name the function already uses
+            let mut taken = BTreeSet::<String>::new();
+            for ilb in blocks.iter().flat_map(|id| self.blocks[*id].instrs.iter()) {
+                if let InstructionOrLabel::Instruction(i) = ilb {
+                    taken.extend(i.dest.iter().chain(i.args.iter().flatten()).cloned());
+                }
+            }
+            self.blocks[blocks[0]].rename_phi_def(
+                stack_of.clone(),
+                &dff.domtree,
+                &self.blocks,
+                &mut map_from_id_to_instrs,
+                &phi_variables,
+                &mut taken,
+            )
+        }
+
+        for (id, instrs) in map_from_id_to_instrs {
+            self.blocks[id].instrs = instrs;
+        }
     }
-    pub fn place_phi_functions_and_generate_ssa(&mut self) {
-        let (globals, defs) = self.global_variables();
 
-        let dff = DominanceDataFlow::new(self);
-        let df = dff.df.clone();
+    /// Remove the phis of the function made of `blocks` whose value no instruction reads, directly
+    /// or through other phis. Minimal and semi-pruned SSA place many of them
+    fn remove_dead_phis(&mut self, blocks: &[BlockID]) {
+        let instructions = blocks
+            .iter()
+            .flat_map(|bb| self.blocks[*bb].instrs.iter())
+            .filter_map(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => Some(i),
+                InstructionOrLabel::Label(_) => None,
+            });
+        let (phis, others): (Vec<_>, Vec<_>) = instructions.partition(|i| i.is_phi());
+        let mut live = others
+            .iter()
+            .flat_map(|i| i.args.iter().flatten().cloned())
+            .collect::<BTreeSet<_>>();
+        let mut changed = true;
+        while changed {
+            let read = phis
+                .iter()
+                .filter(|phi| live.contains(phi.dest.as_ref().unwrap()))
+                .flat_map(|phi| phi.args.iter().flatten().cloned())
+                .collect::<Vec<_>>();
+            let before = live.len();
+            live.extend(read);
+            changed = live.len() != before;
+        }
+        for bb in blocks.iter() {
+            self.blocks[*bb].instrs.retain(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) if i.is_phi() => {
+                    live.contains(i.dest.as_ref().unwrap())
+                }
+                _ => true,
+            });
+        }
+    }
 
-        // INFO: A function to place phi functions down
-        let mut place_phi_functions = || {
-            for name in globals.clone() {
-                let mut work_list = VecDeque::<usize>::new();
+    /// The phis of the function made of `blocks` that only ever read `UNDEFINED_MARKER`, directly
+    /// or through other such phis. No copy may read them, nothing defines them
+    fn undefined_phis(&self, blocks: &[BlockID]) -> BTreeSet<String> {
+        let phis = blocks
+            .iter()
+            .flat_map(|bb| self.blocks[*bb].instrs.iter())
+            .filter_map(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) if i.is_phi() => Some(i),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut undefined = BTreeSet::<String>::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for phi in phis.iter() {
+                let dest = phi.dest.as_ref().unwrap();
+                let all_undefined =
+                    phi.args.iter().flatten().all(|arg| {
+                        arg == UNDEFINED_MARKER || arg == dest || undefined.contains(arg)
+                    });
+                if all_undefined && !undefined.contains(dest) {
+                    undefined.insert(dest.clone());
+                    changed = true;
+                }
+            }
+        }
+        undefined
+    }
 
-                if !defs.contains_key(&name) {
-                    continue;
+    /// Split the critical edges of the function made of `blocks` that lead to a phi. A copy at the
+    /// end of a block with several successors would also run on its other edges. Returns the
+    /// blocks of the function, the new ones included
+    fn split_phi_edges(&mut self, blocks: &[BlockID]) -> Vec<BlockID> {
+        let mut critical = Vec::new();
+        for bb in blocks.iter() {
+            let has_phi = self.blocks[*bb].instrs.iter().any(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => i.is_phi(),
+                InstructionOrLabel::Label(_) => false,
+            });
+            let predecessors = self.blocks[*bb].predecessors.iter();
+            if !has_phi || predecessors.collect::<BTreeSet<_>>().len() < 2 {
+                continue;
+            }
+            for pred in self.blocks[*bb].predecessors.iter() {
+                let successors = self.blocks[*pred].successors.iter();
+                if successors.collect::<BTreeSet<_>>().len() > 1
+                    && !critical.contains(&(*pred, *bb))
+                {
+                    critical.push((*pred, *bb));
                 }
-                work_list.extend(defs.get(&name).unwrap().clone());
-
-                while !work_list.is_empty() {
-                    let block_id = work_list.pop_front().unwrap();
-
-                    if let Some(df_it) = df.get(&block_id) {
-                        for d in df_it.iter() {
-                            let label =
-                                match self.id_to_bb[d].borrow().instrs.front().unwrap().clone() {
-                                    InstructionOrLabel::Label(l) => InstructionOrLabel::Label(l),
-                                    _ => continue,
-                                };
-                            if self.id_to_bb[d].borrow().contains_phi_def(&name) {
-                            } else {
-                                let mut block_mut_b = self.id_to_bb[d].borrow_mut();
-                                eprintln!("Constructing phi with  {name} from {label} at {}", d);
-                                block_mut_b.insert_phi_def(&name, &mut self.instruction_counter);
-                                work_list.push_back(*d);
+            }
+        }
+        for (from, to) in critical.iter() {
+            self.split_edge(*from, *to);
+        }
+        match critical.is_empty() {
+            true => blocks.to_vec(),
+            false => self
+                .function_blocks()
+                .into_iter()
+                .find(|function| function[0] == blocks[0])
+                .unwrap(),
+        }
+    }
+
+    /// Replace every phi by copies at the end of its predecessors. On each edge all the arguments
+    /// are read before any phi destination is written, so phis swapping values stay correct.
+    /// Critical edges into phis are split first and their copies go into the new block. Dead phis
+    /// are dropped first, and no copy reads a phi that is never given a value
+    pub fn destruct_ssa(&mut self) {
+        for blocks in self.function_blocks() {
+            self.remove_dead_phis(&blocks);
+            let blocks = self.split_phi_edges(&blocks);
+            let undefined = self.undefined_phis(&blocks);
+            // Phis are untyped, they get the type of one of their defined arguments
+            let mut types = BTreeMap::<String, BrilType>::new();
+            let mut phis = Vec::<Instruction>::new();
+            if let Some(func) = self.blocks[blocks[0]].func.as_ref() {
+                for arg in func.args.iter().flatten() {
+                    types.insert(arg.name.clone(), arg.fn_type.clone());
+                }
+            }
+            for bb in blocks.iter() {
+                for ilb in self.blocks[*bb].instrs.iter() {
+                    if let InstructionOrLabel::Instruction(i) = ilb {
+                        match (&i.dest, &i.bril_type) {
+                            (Some(dest), Some(bril_type)) => {
+                                types.insert(dest.clone(), bril_type.clone());
                             }
+                            _ if i.is_phi() => phis.push(i.clone()),
+                            _ => {}
                         }
                     }
                 }
             }
-        };
-        place_phi_functions();
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for phi in phis.iter() {
+                    let dest = phi.dest.clone().unwrap();
+                    if types.contains_key(&dest) {
+                        continue;
+                    }
+                    let known = phi.args.iter().flatten().find_map(|arg| types.get(arg));
+                    if let Some(bril_type) = known.cloned() {
+                        types.insert(dest, bril_type);
+                        changed = true;
+                    }
+                }
+            }
 
-        let mut stack_of = BTreeMap::<String, Vec<String>>::new();
-        for (def, _) in defs.iter() {
-            stack_of.entry(def.clone()).or_insert(vec![def.clone()]);
+            let block_of_label = blocks
+                .iter()
+                .map(|bb| (self.blocks[*bb].get_label(), *bb))
+                .collect::<BTreeMap<_, _>>();
+            for bb in blocks.iter() {
+                // Copies to do on the edge from each predecessor, as (dest, arg)
+                let mut copies = BTreeMap::<String, Vec<(String, String)>>::new();
+                let instrs = std::mem::take(&mut self.blocks[*bb].instrs);
+                for ilb in instrs {
+                    match &ilb {
+                        InstructionOrLabel::Instruction(i) if i.is_phi() => {
+                            let args = i.args.iter().flatten();
+                            for (arg, label) in args.zip(i.labels.iter().flatten()) {
+                                // A phi reading itself keeps its value on that edge
+                                let defined = arg != UNDEFINED_MARKER && !undefined.contains(arg);
+                                if defined && Some(arg) != i.dest.as_ref() {
+                                    let copy = (i.dest.clone().unwrap(), arg.clone());
+                                    copies.entry(label.clone()).or_default().push(copy);
+                                }
+                            }
+                        }
+                        _ => self.blocks[*bb].instrs.push(ilb),
+                    }
+                }
+
+                for (label, copies) in copies {
+                    // Temporaries are all read first, then the destinations are written
+                    let mut reads = Vec::new();
+                    let mut writes = Vec::new();
+                    let through_temporaries = copies.len() > 1;
+                    for (dest, arg) in copies.iter() {
+                        let Some(bril_type) = types.get(dest) else {
+                            continue;
+                        };
+                        let src = match through_temporaries {
+                            true => {
+                                let temporary = format!("{}.phi", dest);
+                                reads.push(Instruction::new_id_instruction(
+                                    &temporary,
+                                    arg,
+                                    bril_type,
+                                    &mut self.instruction_counter,
+                                ));
+                                temporary
+                            }
+                            false => arg.clone(),
+                        };
+                        writes.push(Instruction::new_id_instruction(
+                            dest,
+                            &src,
+                            bril_type,
+                            &mut self.instruction_counter,
+                        ));
+                    }
+
+                    let pred = &mut self.blocks[block_of_label[&label]];
+                    let position = match pred.ends_with_jmp() || pred.ends_with_br() {
+                        true => pred.instrs.len() - 1,
+                        false => pred.instrs.len(),
+                    };
+                    let mut tail = pred.instrs.split_off(position);
+                    pred.instrs.extend(reads);
+                    pred.instrs.extend(writes);
+                    pred.instrs.append(&mut tail);
+                }
+            }
         }
+    }
 
-        // INFO: A function to rename operands in phi functions
+    pub fn analyze_loop(&mut self) {
+        self.analyze_loop_with(&mut AnalysisManager::new())
+    }
 
-        // FIX: Fix this rename_phi_def leaking out of main into other function
-        //
-        // FIX: Another case is we ssa-lized well with 1 function, but multiple function we suck
-        let mut map_from_id_to_instrs = BTreeMap::<usize, LinkedList<InstructionOrLabel>>::new();
-        for (_, bb) in self.hm.iter() {
-            map_from_id_to_instrs
-                .entry(bb.borrow().id)
-                .or_insert(bb.borrow().instrs.clone());
+    /// Same as `analyze_loop`, reusing the dominance cached in `analyses`. The loops are put in
+    /// simplified form first so that every loop has a preheader to hoist to
+    pub fn analyze_loop_with(&mut self, analyses: &mut AnalysisManager) {
+        if self.simplify_loops() > 0 {
+            analyses.invalidate(&PreservedAnalyses::none());
+        }
+        let dominance = analyses.program_dominance(self);
+        let pure_functions = analyses.purity(self).pure_functions();
+        let mut assignment = DefiniteAssignment::new(self, UndefinedPolicy::Error);
+        self.solve(&mut assignment);
+        let mut loops = Loops::with_dominance(self, &dominance);
+        // Inner loops first, what leaves them may then leave the loops around them
+        loops.loops.sort_by_key(|l| l.loop_nodes.len());
+        for l in loops.loops.iter_mut() {
+            l.pure_functions = pure_functions.clone();
+            l.defined_before = assignment.defined_at_end(l.preheader);
+            l.defined_once = self.defined_once(&self.function_of(l.header));
+            self.solve(l);
+            for node in l.loop_nodes.clone() {
+                l.transform(&mut self.blocks[node]);
+            }
+            self.hoist(l.preheader, std::mem::take(&mut l.hoisted));
         }
+    }
 
-        let mut rename_phi_defs = |stack_of: BTreeMap<String, Vec<String>>| {
-            for (_, bb) in self.hm.iter() {
-                if bb.borrow().func.is_none() {
-                    continue;
-                }
-                let mut new_to_old_names = BTreeMap::<String, String>::new();
-                let mut name_counter = BTreeMap::<String, usize>::new();
-
-                eprintln!(
-                    "PROCESSING A NEW FN BLOCK {} with id {}, that has {} successor",
-                    bb.borrow().func.clone().unwrap().name,
-                    bb.borrow().id,
-                    bb.borrow().successors.len()
-                );
-                eprintln!("fn block processor");
-                for succ in bb.borrow().successors.iter() {
-                    eprint!("{} - ", succ.borrow().id);
+    /// The variables the function made of `blocks` defines exactly once, arguments included
+    fn defined_once(&self, blocks: &[BlockID]) -> BTreeSet<String> {
+        let func = self.blocks[blocks[0]].func.as_ref().unwrap();
+        let arguments = func.args.iter().flatten().map(|arg| &arg.name);
+        let dests = blocks
+            .iter()
+            .flat_map(|id| self.blocks[*id].instrs.iter())
+            .filter_map(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => i.dest.as_ref(),
+                InstructionOrLabel::Label(_) => None,
+            });
+        let mut definitions = BTreeMap::<&String, usize>::new();
+        for name in arguments.chain(dests) {
+            *definitions.entry(name).or_default() += 1;
+        }
+        let once = definitions.into_iter().filter(|(_, count)| *count == 1);
+        once.map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Put the loop invariant definitions `hoisted` at the end of `preheader`, each after the
+    /// hoisted definitions it reads
+    fn hoist(&mut self, preheader: BlockID, mut hoisted: Vec<InstructionOrLabel>) {
+        let mut ordered = Vec::new();
+        while !hoisted.is_empty() {
+            let pending = hoisted
+                .iter()
+                .filter_map(|ilb| match ilb {
+                    InstructionOrLabel::Instruction(i) => i.dest.clone(),
+                    InstructionOrLabel::Label(_) => None,
+                })
+                .collect::<BTreeSet<_>>();
+            let ready = hoisted.iter().position(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => {
+                    i.args.iter().flatten().all(|arg| !pending.contains(arg))
                 }
-                eprintln!();
-                bb.borrow_mut().rename_phi_def(
-                    stack_of.clone(),
-                    &dff.domtree,
-                    &mut name_counter,
-                    &self.id_to_bb,
-                    &mut map_from_id_to_instrs,
-                    &mut new_to_old_names,
-                )
+                InstructionOrLabel::Label(_) => true,
+            });
+            ordered.push(hoisted.remove(ready.unwrap()));
+        }
+        let label = self.blocks[preheader].get_label();
+        for ilb in ordered.iter() {
+            if let InstructionOrLabel::Instruction(i) = ilb {
+                eprintln!("Hoisting {} into .{}", i.dest.as_ref().unwrap(), label);
             }
+        }
+        let bb = &mut self.blocks[preheader];
+        let position = match bb.ends_with_jmp() || bb.ends_with_br() {
+            true => bb.instrs.len() - 1,
+            false => bb.instrs.len(),
         };
-        rename_phi_defs(stack_of);
+        bb.instrs.splice(position..position, ordered);
+    }
+}
 
-        for (_, bb) in self.hm.iter_mut() {
-            let id = bb.borrow().id;
-            bb.borrow_mut().instrs = map_from_id_to_instrs.entry(id).or_default().clone();
+/// INFO: This impl block is denoted to be about profiles
+impl CFG {
+    /// The name a profile gives the `index`-th block of a function (entry block excluded): its
+    /// label if the source had one, `#index` otherwise
+    fn profile_block_name(&self, labels: &BTreeSet<String>, index: usize, id: BlockID) -> String {
+        match self.blocks[id].instrs.first() {
+            Some(InstructionOrLabel::Label(l)) if labels.contains(&l.label) => {
+                format!(".{}", l.label)
+            }
+            _ => format!("#{}", index),
         }
     }
 
-    pub fn analyze_loop(&mut self) {
-        let mut loops = Loops::new(self);
-
-        //for l in loops.loops.iter_mut() {
-        //    self.dataflow(l
-        //}
-        // TODO:
-        //
-        // for l in loops
-        //   cfg.dataflow(&mut l);
+    /// Attach the counts of `profile` to `execution_count` and `edge_counts`. Blocks without a
+    /// label are matched by position, so apply it before any pass adds or moves blocks. The entry
+    /// block runs once per call, functions missing from the profile are left untouched
+    pub fn apply_profile(&mut self, profile: &Profile) {
+        for blocks in self.function_blocks() {
+            let func = self.blocks[blocks[0]].func.as_ref().unwrap();
+            let Some(counts) = profile.functions.get(&func.name) else {
+                continue;
+            };
+            let labels = func
+                .instrs
+                .iter()
+                .filter_map(|instr| match instr {
+                    InstructionOrLabel::Label(l) => Some(l.label.clone()),
+                    _ => None,
+                })
+                .collect::<BTreeSet<_>>();
+            let names = blocks
+                .iter()
+                .enumerate()
+                .skip(1)
+                .map(|(index, id)| (*id, self.profile_block_name(&labels, index - 1, *id)))
+                .collect::<HashMap<_, _>>();
+
+            for id in blocks.iter() {
+                let (count, edge_counts) = match names.get(id) {
+                    None => {
+                        // The entry block only falls through to the first block of the body
+                        let successors = self.blocks[*id].successors.iter();
+                        (
+                            counts.calls,
+                            successors.map(|s| (*s, counts.calls)).collect(),
+                        )
+                    }
+                    Some(name) => {
+                        let edge_counts = self.blocks[*id]
+                            .successors
+                            .iter()
+                            .map(|s| {
+                                let edge = (name.clone(), names[s].clone());
+                                (*s, counts.edges.get(&edge).copied().unwrap_or(0))
+                            })
+                            .collect();
+                        (counts.blocks.get(name).copied().unwrap_or(0), edge_counts)
+                    }
+                };
+                let bb = &mut self.blocks[*id];
+                bb.execution_count = Some(count);
+                bb.edge_counts = edge_counts;
+            }
+        }
     }
 }
@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    aliases::BlockID,
+    basic_block::BasicBlock,
+    bril_syntax::{Instruction, InstructionOrLabel},
+    cfg::CFG,
+    definite_assignment::UNDEFINED_MARKER,
+};
+
+/// Whether control falls off the end of `bb` into the next block in `order`
+pub fn falls_through(bb: &BasicBlock) -> bool {
+    match bb.instrs.last() {
+        Some(InstructionOrLabel::Instruction(i)) => !(i.is_jmp() || i.is_br() || i.is_ret()),
+        _ => true,
+    }
+}
+
+/// INFO: This impl block is denoted to be about editing the CFG. Every edit keeps the edges, the
+/// labels the branches name and the labels of the phis in agreement, and the blocks that fell
+/// through get the jump they need when a block is put after them
+impl CFG {
+    /// The blocks of the function `id` is in, as `function_blocks` groups them
+    pub fn function_of(&self, id: BlockID) -> Vec<BlockID> {
+        let mut functions = self.function_blocks().into_iter();
+        functions.find(|blocks| blocks.contains(&id)).unwrap()
+    }
+
+    /// `base`, or `base` with a number after it if a block of `blocks` already has that label
+    pub fn fresh_label(&self, blocks: &[BlockID], base: &str) -> String {
+        let taken = blocks
+            .iter()
+            .map(|id| self.blocks[*id].get_label())
+            .collect::<BTreeSet<_>>();
+        (0..)
+            .map(|k| match k {
+                0 => base.to_string(),
+                k => format!("{}_{}", base, k),
+            })
+            .find(|label| !taken.contains(label))
+            .unwrap()
+    }
+
+    /// The successors of `id` according to its instructions, in the order `from_program` puts
+    /// them: the fall through or false target first, then the targets of the failing `guard`s
+    fn successors_from_instructions(&self, id: BlockID) -> Vec<BlockID> {
+        let blocks = self.function_of(id);
+        let by_label = |label: &String| {
+            let mut labeled = blocks
+                .iter()
+                .filter(|b| self.blocks[**b].get_label() == *label);
+            *labeled.next().unwrap()
+        };
+        let bb = &self.blocks[id];
+        let mut targets = match bb.instrs.last() {
+            Some(InstructionOrLabel::Instruction(i)) if i.is_br() => {
+                let labels = i.labels.as_ref().unwrap();
+                vec![labels[1].clone(), labels[0].clone()]
+            }
+            Some(InstructionOrLabel::Instruction(i)) if i.is_jmp() => i.labels.clone().unwrap(),
+            Some(InstructionOrLabel::Instruction(i)) if i.is_ret() => Vec::new(),
+            _ => {
+                let position = self.order.iter().position(|o| *o == id).unwrap();
+                let next = self
+                    .order
+                    .get(position + 1)
+                    .filter(|next| blocks.contains(next));
+                next.map(|next| self.blocks[*next].get_label())
+                    .into_iter()
+                    .collect()
+            }
+        };
+        for ilb in bb.instrs.iter() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            let label = i.labels.iter().flatten().next().filter(|_| i.is_guard());
+            if let Some(label) = label.filter(|label| !targets.contains(label)) {
+                targets.push(label.clone());
+            }
+        }
+        targets.iter().map(by_label).collect()
+    }
+
+    /// Give the phis of `block` an entry for `pred` exactly when it is a predecessor. A new entry
+    /// reads `UNDEFINED_MARKER` until the caller says otherwise
+    fn sync_phi_entries(&mut self, block: BlockID, pred: BlockID) {
+        let is_pred = self.blocks[block].predecessors.contains(&pred);
+        let label = self.blocks[pred].get_label();
+        for ilb in self.blocks[block].instrs.iter_mut() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            if !i.is_phi() {
+                continue;
+            }
+            let has_entry = i.labels.iter().flatten().any(|l| *l == label);
+            if is_pred && !has_entry {
+                i.args
+                    .get_or_insert_default()
+                    .push(UNDEFINED_MARKER.to_string());
+                i.labels.get_or_insert_default().push(label.clone());
+            } else if !is_pred && has_entry {
+                let entries = i.args.iter().flatten().zip(i.labels.iter().flatten());
+                let (args, labels) = entries
+                    .filter(|(_, l)| **l != label)
+                    .map(|(arg, l)| (arg.clone(), l.clone()))
+                    .unzip();
+                i.args = Some(args);
+                i.labels = Some(labels);
+            }
+        }
+    }
+
+    /// Make the edges from `from` to `to` go to `new_to` instead, phis aside. Returns the
+    /// profiled count of the edges moved
+    fn retarget(&mut self, from: BlockID, to: BlockID, new_to: BlockID) -> Option<u64> {
+        let to_label = self.blocks[to].get_label();
+        let new_label = self.blocks[new_to].get_label();
+        let mut named = false;
+        for ilb in self.blocks[from].instrs.iter_mut() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            if !(i.is_jmp() || i.is_br() || i.is_guard()) {
+                continue;
+            }
+            for label in i.labels.iter_mut().flatten().filter(|l| **l == to_label) {
+                *label = new_label.clone();
+                named = true;
+            }
+        }
+        let position = self.order.iter().position(|id| *id == from);
+        let next = position.and_then(|p| self.order.get(p + 1));
+        if !named && next != Some(&new_to) {
+            let jmp = Instruction::new_control_instruction(
+                Some(&new_label),
+                &mut self.instruction_counter,
+            );
+            self.blocks[from].push_back(&jmp);
+        }
+
+        let bb = &mut self.blocks[from];
+        let mut moved = 0;
+        for succ in bb.successors.iter_mut().filter(|succ| **succ == to) {
+            *succ = new_to;
+            moved += 1;
+        }
+        let count = bb.edge_counts.remove(&to);
+        if let Some(count) = count {
+            *bb.edge_counts.entry(new_to).or_default() += count;
+        }
+        self.blocks[to].predecessors.retain(|pred| *pred != from);
+        let predecessors = &mut self.blocks[new_to].predecessors;
+        predecessors.extend(std::iter::repeat_n(from, moved));
+        count
+    }
+
+    /// Make `previous` jump explicitly to where it falls through, before a block is put after it
+    fn end_fall_through(&mut self, previous: BlockID) {
+        if !falls_through(&self.blocks[previous]) {
+            return;
+        }
+        let successor = self.blocks[previous].successors.first();
+        let next = successor.map(|id| self.blocks[*id].get_label());
+        let terminator =
+            Instruction::new_control_instruction(next.as_deref(), &mut self.instruction_counter);
+        self.blocks[previous].push_back(&terminator);
+    }
+
+    /// Make the edges from `from` to `to` go to `new_to` instead. The phis of `to` lose their
+    /// entry for `from`, the phis of `new_to` get one reading `UNDEFINED_MARKER` for the caller
+    /// to fill in
+    pub fn redirect_edge(&mut self, from: BlockID, to: BlockID, new_to: BlockID) {
+        self.retarget(from, to, new_to);
+        self.sync_phi_entries(to, from);
+        self.sync_phi_entries(new_to, from);
+    }
+
+    /// Put a new block right before `target` in `order` and make the edges from `sources` to
+    /// `target` go through it. The label is `label`, numbered if the function already has it.
+    /// The entries of the phis of `target` for the sources move to the new block, merged by a
+    /// phi of the new block when they disagree. Returns the new block
+    pub fn insert_block_before(
+        &mut self,
+        target: BlockID,
+        sources: &[BlockID],
+        label: &str,
+    ) -> BlockID {
+        let label = self.fresh_label(&self.function_of(target), label);
+        let new = self.new_block(&label);
+        let position = self.order.iter().position(|id| *id == target).unwrap();
+        let previous = self.order[position - 1];
+        if !sources.contains(&previous) {
+            self.end_fall_through(previous);
+        }
+        self.order.insert(position, new);
+
+        let sources = sources
+            .iter()
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let mut count = None;
+        for source in sources.iter() {
+            if let Some(moved) = self.retarget(*source, target, new) {
+                count = Some(count.unwrap_or(0) + moved);
+            }
+        }
+        self.merge_phi_entries(target, &sources, new);
+        self.blocks[target].predecessors.push(new);
+        let bb = &mut self.blocks[new];
+        bb.successors = vec![target];
+        bb.execution_count = count;
+        if let Some(count) = count {
+            bb.edge_counts = BTreeMap::from([(target, count)]);
+        }
+        new
+    }
+
+    /// Put a new block on the edges from `from` to `to`. Returns the new block
+    pub fn split_edge(&mut self, from: BlockID, to: BlockID) -> BlockID {
+        let label = format!(
+            "{}_{}",
+            self.blocks[from].get_label(),
+            self.blocks[to].get_label()
+        );
+        self.insert_block_before(to, &[from], &label)
+    }
+
+    /// Put a block on every critical edge, from a block with several successors to a block with
+    /// several predecessors. Returns the number of edges split
+    pub fn split_critical_edges(&mut self) -> usize {
+        let mut critical = Vec::new();
+        for id in self.order.iter() {
+            let successors = self.blocks[*id].successors.iter().collect::<BTreeSet<_>>();
+            for succ in successors.iter().filter(|_| successors.len() > 1) {
+                let predecessors = self.blocks[**succ].predecessors.iter();
+                if predecessors.collect::<BTreeSet<_>>().len() > 1 {
+                    critical.push((*id, **succ));
+                }
+            }
+        }
+        for (from, to) in critical.iter() {
+            self.split_edge(*from, *to);
+        }
+        critical.len()
+    }
+
+    /// Move the entries of the phis of `target` coming from `sources` to the block `new` now
+    /// between them. Entries that all agree stay a single entry, others are merged by a new phi
+    fn merge_phi_entries(&mut self, target: BlockID, sources: &[BlockID], new: BlockID) {
+        let source_labels = sources
+            .iter()
+            .map(|id| self.blocks[*id].get_label())
+            .collect::<Vec<_>>();
+        let new_label = self.blocks[new].get_label();
+        let mut merging = Vec::new();
+        for ilb in self.blocks[target].instrs.iter_mut() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            if !i.is_phi() {
+                continue;
+            }
+            let entries = i.args.iter().flatten().zip(i.labels.iter().flatten());
+            let (moved, kept): (Vec<_>, Vec<_>) =
+                entries.partition(|(_, label)| source_labels.contains(label));
+            if moved.is_empty() {
+                continue;
+            }
+            let value = match moved.iter().all(|(arg, _)| *arg == moved[0].0) {
+                true => moved[0].0.clone(),
+                false => {
+                    let name = format!("{}_{}", i.dest.as_ref().unwrap(), new_label);
+                    let mut phi = Instruction::new_phi(name.clone(), &mut self.instruction_counter);
+                    phi.bril_type = i.bril_type.clone();
+                    phi.args = Some(moved.iter().map(|(arg, _)| (*arg).clone()).collect());
+                    phi.labels = Some(moved.iter().map(|(_, label)| (*label).clone()).collect());
+                    merging.push(InstructionOrLabel::Instruction(phi));
+                    name
+                }
+            };
+            let (mut args, mut labels): (Vec<_>, Vec<_>) = kept
+                .into_iter()
+                .map(|(arg, label)| (arg.clone(), label.clone()))
+                .unzip();
+            args.push(value);
+            labels.push(new_label.clone());
+            i.args = Some(args);
+            i.labels = Some(labels);
+        }
+        self.blocks[new].instrs.splice(1..1, merging);
+    }
+
+    /// Take `id`, which nothing may jump to anymore, out of `order`. Its successors lose it as a
+    /// predecessor along with the entries of their phis for it
+    pub fn remove_block(&mut self, id: BlockID) {
+        assert!(
+            self.blocks[id].predecessors.is_empty() && self.blocks[id].func.is_none(),
+            "Only a block nothing jumps to can be removed, and never the entry block"
+        );
+        let successors = std::mem::take(&mut self.blocks[id].successors);
+        for succ in successors.iter().collect::<BTreeSet<_>>() {
+            self.blocks[*succ].predecessors.retain(|pred| *pred != id);
+            self.sync_phi_entries(*succ, id);
+        }
+        self.blocks[id].edge_counts.clear();
+        self.order.retain(|o| *o != id);
+    }
+
+    /// Replace the `jmp`, `br` or `ret` ending `id` with `terminator`, which must be one of them,
+    /// or end `id` with it if it fell through. The successors follow the new labels, and the
+    /// phis of the blocks gained or lost as successors get or lose their entry for `id`
+    pub fn replace_terminator(&mut self, id: BlockID, mut terminator: InstructionOrLabel) {
+        let InstructionOrLabel::Instruction(i) = &mut terminator else {
+            panic!("A terminator is an instruction, not a label");
+        };
+        assert!(i.is_jmp() || i.is_br() || i.is_ret());
+        if i.instruction_id.is_none() {
+            i.instruction_id = Some(self.instruction_counter);
+            self.instruction_counter += 1;
+        }
+        if !falls_through(&self.blocks[id]) {
+            self.blocks[id].instrs.pop();
+        }
+        self.blocks[id].push_back(&terminator);
+
+        let successors = self.successors_from_instructions(id);
+        let old = std::mem::replace(&mut self.blocks[id].successors, successors.clone());
+        for succ in old.iter() {
+            let predecessors = &mut self.blocks[*succ].predecessors;
+            if let Some(position) = predecessors.iter().position(|pred| *pred == id) {
+                predecessors.remove(position);
+            }
+        }
+        for succ in successors.iter() {
+            self.blocks[*succ].predecessors.push(id);
+        }
+        for succ in old.iter().chain(successors.iter()).collect::<BTreeSet<_>>() {
+            self.sync_phi_entries(*succ, id);
+        }
+        let bb = &mut self.blocks[id];
+        bb.edge_counts.retain(|succ, _| successors.contains(succ));
+    }
+}
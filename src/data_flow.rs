@@ -1,12 +1,8 @@
 use crate::bril_syntax::InstructionOrLabel;
 use crate::cfg::CFG;
-use crate::{aliases::BbPtr, basic_block::BasicBlock};
-use std::{
-    cell::RefCell,
-    collections::{HashSet, VecDeque},
-    fmt::Debug,
-    rc::Rc,
-};
+use crate::{aliases::BlockID, basic_block::BasicBlock};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Debug;
 #[derive(Debug, PartialEq)]
 pub enum DataFlowDirection {
     Forward,
@@ -29,84 +25,121 @@ pub enum DataFlowOrder {
     EntryNodesOnly,
     PostOrderDFS,
     BFS,
-    Subset(VecDeque<BbPtr>),
+    Subset(VecDeque<BlockID>),
 }
+/// `meet` and `transfer` only read the blocks, the rewriting happens in `transform` once the
+/// facts are stable
 pub trait DataFlowAnalysis {
-    fn meet(&mut self, bb: &mut BasicBlock);
-    fn transfer(&mut self, bb: &mut BasicBlock) -> TransferResult;
+    fn meet(&mut self, bb: &BasicBlock);
+    fn transfer(&mut self, bb: &BasicBlock) -> TransferResult;
     fn transform(&mut self, bb: &mut BasicBlock);
     fn get_dataflow_direction(&self) -> DataFlowDirection;
     fn get_dataflow_order(&self) -> DataFlowOrder;
 }
 
 pub trait ConditionalDataFlowAnalysis {
-    fn meet(&mut self, bb: &mut BasicBlock);
-    fn transfer(&mut self, bb: &mut BasicBlock) -> ConditionalTransferResult;
+    fn meet(&mut self, bb: &BasicBlock);
+    fn transfer(&mut self, bb: &BasicBlock) -> ConditionalTransferResult;
     fn transform(&mut self, bb: &mut BasicBlock);
 }
 
 impl CFG {
     // fn dfs_children(bb: &mut BasicBlock<T>) {}
-    fn bfs_children(bb: &mut Rc<RefCell<BasicBlock>>) -> VecDeque<Rc<RefCell<BasicBlock>>> {
-        let mut visited = HashSet::<InstructionOrLabel>::default();
-        let mut q = VecDeque::<Rc<RefCell<BasicBlock>>>::default();
-        let mut result = VecDeque::<Rc<RefCell<BasicBlock>>>::default();
+    /// The blocks reachable from `bb` in breadth first order, `bb` itself comes twice
+    pub fn bfs_children(&self, bb: BlockID) -> VecDeque<BlockID> {
+        let mut visited = HashSet::<BlockID>::default();
+        let mut q = VecDeque::<BlockID>::default();
+        let mut result = VecDeque::<BlockID>::default();
 
-        q.push_back(bb.clone());
-        result.push_back(bb.clone());
+        q.push_back(bb);
+        result.push_back(bb);
 
-        visited.insert(bb.borrow().instrs.front().unwrap().clone());
+        visited.insert(bb);
         while !q.is_empty() {
             let visit_bb = q.pop_front().unwrap();
-            result.push_back(visit_bb.clone());
-            for succ in visit_bb.borrow().successors.iter().rev() {
-                let a = succ.borrow().instrs.front().unwrap().clone();
-                if !visited.contains(&a) {
-                    q.push_back(succ.clone());
-                    visited.insert(a);
+            result.push_back(visit_bb);
+            for succ in self.blocks[visit_bb].successors.iter().rev() {
+                if !visited.contains(succ) {
+                    q.push_back(*succ);
+                    visited.insert(*succ);
                 }
             }
         }
 
         result
     }
-    pub fn dataflow_forward_optimistically(&self, d: &mut impl ConditionalDataFlowAnalysis) {
+
+    /// The successor a block ending with a `br` goes to for the given label of the `br`. The
+    /// successors are not kept in the order of the labels
+    fn branch_target(&self, bb: BlockID, which: usize) -> BlockID {
+        let bb = &self.blocks[bb];
+        let Some(InstructionOrLabel::Instruction(br)) = bb.instrs.last() else {
+            panic!("A path was taken out of a block that does not end with a br");
+        };
+        let label = &br.labels.as_ref().expect("A br has labels")[which];
+        *bb.successors
+            .iter()
+            .find(|succ| &self.blocks[**succ].get_label() == label)
+            .expect("The successors include both targets of a br")
+    }
+
+    /// The entry block of every function
+    fn entry_blocks(&self) -> Vec<BlockID> {
+        self.ordered_blocks()
+            .filter(|bb| bb.func.is_some())
+            .map(|bb| bb.id)
+            .collect()
+    }
+
+    pub fn dataflow_forward_optimistically(&mut self, d: &mut impl ConditionalDataFlowAnalysis) {
+        self.solve_optimistically(d);
+        for id in self.order.iter() {
+            d.transform(&mut self.blocks[*id])
+        }
+    }
+
+    /// Run the analysis to its fixpoint along the edges it finds executable, without transforming
+    /// anything
+    pub fn solve_optimistically(&self, d: &mut impl ConditionalDataFlowAnalysis) {
         // do the dataflow optimistically
         //
         //
-        let mut q = VecDeque::<Rc<RefCell<BasicBlock>>>::default();
-        for i in self.hm.clone() {
-            if i.1.borrow_mut().func.is_some() {
-                q.push_back(i.1.clone());
-            }
+        let mut q = VecDeque::<BlockID>::default();
+        for entry in self.entry_blocks() {
+            q.push_back(entry);
             while !q.is_empty() {
-                let visit_bb = q.pop_front().expect("hi").clone();
-                // visit_bb.borrow_mut();
-                d.meet(&mut visit_bb.borrow_mut());
+                let visit_bb = q.pop_front().expect("hi");
+                d.meet(&self.blocks[visit_bb]);
 
-                let transfer_result = d.transfer(&mut visit_bb.borrow_mut());
+                let transfer_result = d.transfer(&self.blocks[visit_bb]);
                 match transfer_result {
                     ConditionalTransferResult::AllPathTaken => {
-                        q.extend(visit_bb.borrow_mut().successors.clone())
+                        q.extend(self.blocks[visit_bb].successors.iter())
                     }
                     ConditionalTransferResult::FirstPathTaken => {
                         //eprintln!("First path taken, from {}", s);
-                        q.push_back(visit_bb.borrow_mut().successors[0].clone())
+                        q.push_back(self.branch_target(visit_bb, 0))
                     }
                     ConditionalTransferResult::SecondPathTaken => {
-                        q.push_back(visit_bb.borrow_mut().successors[1].clone())
+                        q.push_back(self.branch_target(visit_bb, 1))
                     }
                     ConditionalTransferResult::NoPathTaken => continue,
                 };
             }
         }
+    }
 
-        for i in self.hm.iter() {
-            d.transform(&mut i.1.borrow_mut())
+    /// Run the analysis to its fixpoint, then transform every block
+    pub fn dataflow(&mut self, d: &mut impl DataFlowAnalysis) {
+        self.solve(d);
+        for id in self.order.iter() {
+            d.transform(&mut self.blocks[*id])
         }
     }
 
-    pub fn dataflow(&self, d: &mut impl DataFlowAnalysis) {
+    /// Run the analysis to its fixpoint without transforming anything, for analyses that only
+    /// collect facts
+    pub fn solve(&self, d: &mut impl DataFlowAnalysis) {
         // do the dataflow
         //
         //
@@ -115,60 +148,46 @@ impl CFG {
             _ => self.dataflow_normal(d),
         }
     }
-    fn dataflow_subset(&self, d: &mut impl DataFlowAnalysis, subset: &VecDeque<BbPtr>) {
-        let mut q = VecDeque::<BbPtr>::default();
-        q.extend(subset.clone());
+    fn dataflow_subset(&self, d: &mut impl DataFlowAnalysis, subset: &VecDeque<BlockID>) {
+        let mut q = VecDeque::<BlockID>::default();
+        q.extend(subset.iter());
         let mut changed = true;
         while changed {
             changed = false;
             while !q.is_empty() {
-                let visit_bb = q.pop_front().expect("hi").clone();
-                // visit_bb.borrow_mut();
-                d.meet(&mut visit_bb.borrow_mut());
+                let visit_bb = q.pop_front().expect("hi");
+                d.meet(&self.blocks[visit_bb]);
 
-                if d.transfer(&mut visit_bb.borrow_mut()) == TransferResult::Changed {
+                if d.transfer(&self.blocks[visit_bb]) == TransferResult::Changed {
                     changed = true;
                 }
             }
             if changed {
-                q.extend(subset.clone());
+                q.extend(subset.iter());
             }
         }
-        for i in self.hm.iter() {
-            d.transform(&mut i.1.borrow_mut())
-        }
     }
     fn dataflow_normal(&self, d: &mut impl DataFlowAnalysis) {
-        let mut q = VecDeque::<BbPtr>::default();
-        for i in self.hm.clone() {
-            if i.1.borrow_mut().func.is_some() {
-                match d.get_dataflow_order() {
-                    DataFlowOrder::EntryNodesOnly => q.push_back(i.1.clone()),
-                    DataFlowOrder::BFS => q.extend(Self::bfs_children(&mut i.1.clone())),
-                    DataFlowOrder::PostOrderDFS => todo!(),
-                    _ => return,
-                }
-                while !q.is_empty() {
-                    let visit_bb = q.pop_front().expect("hi").clone();
-                    // visit_bb.borrow_mut();
-                    d.meet(&mut visit_bb.borrow_mut());
+        let mut q = VecDeque::<BlockID>::default();
+        for entry in self.entry_blocks() {
+            match d.get_dataflow_order() {
+                DataFlowOrder::EntryNodesOnly => q.push_back(entry),
+                DataFlowOrder::BFS => q.extend(self.bfs_children(entry)),
+                DataFlowOrder::PostOrderDFS => todo!(),
+                _ => return,
+            }
+            while !q.is_empty() {
+                let visit_bb = q.pop_front().expect("hi");
+                d.meet(&self.blocks[visit_bb]);
 
-                    if d.transfer(&mut visit_bb.borrow_mut()) == TransferResult::Changed {
-                        match d.get_dataflow_direction() {
-                            DataFlowDirection::Forward => {
-                                q.extend(visit_bb.borrow_mut().successors.clone())
-                            }
-                            DataFlowDirection::Backward => {
-                                q.extend(visit_bb.borrow_mut().predecessors.clone())
-                            }
-                        }
+                if d.transfer(&self.blocks[visit_bb]) == TransferResult::Changed {
+                    let bb = &self.blocks[visit_bb];
+                    match d.get_dataflow_direction() {
+                        DataFlowDirection::Forward => q.extend(bb.successors.iter()),
+                        DataFlowDirection::Backward => q.extend(bb.predecessors.iter()),
                     }
                 }
             }
         }
-
-        for i in self.hm.iter() {
-            d.transform(&mut i.1.borrow_mut())
-        }
     }
 }
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    aliases::BlockID, analysis_manager::AnalysisManager, bril_syntax::InstructionOrLabel,
+    call_graph::CallGraph, cfg::CFG, liveness::LivenessKind,
+};
+
+/// INFO: This impl block is denoted to be about dead argument elimination
+impl CFG {
+    /// The parameters of the function starting at `blocks[0]` nothing reads, apart from the copy
+    /// `from_program` puts in the entry block when what it copies to is dead. Each comes with
+    /// the instruction id of that copy, if any
+    fn dead_parameters(
+        &self,
+        blocks: &[BlockID],
+        analyses: &mut AnalysisManager,
+    ) -> Vec<(usize, Option<usize>)> {
+        let liveness = analyses.liveness(self, blocks, LivenessKind::Live);
+        let entry = blocks[0];
+        let instructions = blocks
+            .iter()
+            .flat_map(|id| self.blocks[*id].instrs.iter())
+            .filter_map(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => Some(i),
+                InstructionOrLabel::Label(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let func = self.blocks[entry].func.as_ref().unwrap();
+        let mut result = Vec::new();
+        for (position, arg) in func.args.iter().flatten().enumerate() {
+            let readers = instructions
+                .iter()
+                .filter(|i| i.args.iter().flatten().any(|a| *a == arg.name))
+                .collect::<Vec<_>>();
+            match readers[..] {
+                [] => result.push((position, None)),
+                [copy] if copy.is_id() => {
+                    let id = copy.instruction_id.unwrap();
+                    let dest = copy.dest.as_ref().unwrap();
+                    if !liveness.live_after(id).contains(dest) {
+                        result.push((position, Some(id)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Whether every call to `callee` throws its result away
+    fn result_unused(
+        &self,
+        graph: &CallGraph,
+        callee: &str,
+        analyses: &mut AnalysisManager,
+    ) -> bool {
+        graph.calls_to(callee).all(|site| {
+            let InstructionOrLabel::Instruction(call) =
+                &self.blocks[site.block].instrs[site.position]
+            else {
+                unreachable!()
+            };
+            let Some(dest) = &call.dest else {
+                return true;
+            };
+            let liveness =
+                analyses.liveness(self, &graph.functions[&site.caller], LivenessKind::Live);
+            !liveness
+                .live_after(call.instruction_id.unwrap())
+                .contains(dest)
+        })
+    }
+
+    /// Remove the parameters no function reads, with the matching argument of every call, and
+    /// make the functions whose result no call uses return nothing. `@main` is left alone, the
+    /// arguments of the program are its own. Returns the number of parameters and results removed
+    pub fn eliminate_dead_arguments_with(&mut self, analyses: &mut AnalysisManager) -> usize {
+        let graph = CallGraph::new(self);
+        // Everything is decided before anything changes, the liveness and the positions of the
+        // call sites would not hold afterward
+        let mut dead_parameters = BTreeMap::new();
+        let mut dead_results = Vec::new();
+        for (name, blocks) in graph.functions.iter() {
+            if name == "main" {
+                continue;
+            }
+            let func = self.blocks[blocks[0]].func.as_ref().unwrap();
+            let arity = func.args.as_ref().map_or(0, Vec::len);
+            let matching = graph.calls_to(name).all(|site| {
+                match &self.blocks[site.block].instrs[site.position] {
+                    InstructionOrLabel::Instruction(call) => {
+                        call.args.as_ref().map_or(0, Vec::len) == arity
+                    }
+                    InstructionOrLabel::Label(_) => false,
+                }
+            });
+            if !matching {
+                continue;
+            }
+            let dead = self.dead_parameters(blocks, analyses);
+            if !dead.is_empty() {
+                dead_parameters.insert(name.clone(), dead);
+            }
+            if func.bril_type.is_some() && self.result_unused(&graph, name, analyses) {
+                dead_results.push(name.clone());
+            }
+        }
+
+        for site in graph.call_sites.iter() {
+            let InstructionOrLabel::Instruction(call) =
+                &mut self.blocks[site.block].instrs[site.position]
+            else {
+                continue;
+            };
+            if let Some(dead) = dead_parameters.get(&site.callee) {
+                call.args = without_dead(call.args.take(), dead);
+            }
+            if dead_results.contains(&site.callee) {
+                call.dest = None;
+                call.bril_type = None;
+            }
+        }
+
+        let mut removed = 0;
+        for (name, dead) in dead_parameters.iter() {
+            let blocks = &graph.functions[name];
+            eprintln!("Removing {} dead parameters of @{}", dead.len(), name);
+            removed += dead.len();
+            let copies = dead
+                .iter()
+                .filter_map(|(_, copy)| *copy)
+                .collect::<Vec<_>>();
+            self.blocks[blocks[0]].instrs.retain(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => !copies.contains(&i.instruction_id.unwrap()),
+                InstructionOrLabel::Label(_) => true,
+            });
+            let func = self.blocks[blocks[0]].func.as_mut().unwrap();
+            func.args = without_dead(func.args.take(), dead);
+        }
+        for name in dead_results.iter() {
+            let blocks = &graph.functions[name];
+            eprintln!("Removing the unused result of @{}", name);
+            removed += 1;
+            self.blocks[blocks[0]].func.as_mut().unwrap().bril_type = None;
+            for id in blocks.iter() {
+                for ilb in self.blocks[*id].instrs.iter_mut() {
+                    if let InstructionOrLabel::Instruction(i) = ilb {
+                        if i.is_ret() {
+                            i.args = None;
+                        }
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    pub fn eliminate_dead_arguments(&mut self) -> usize {
+        self.eliminate_dead_arguments_with(&mut AnalysisManager::new())
+    }
+}
+
+/// `items` without the ones at the positions of the dead parameters, None if nothing is left
+fn without_dead<T>(items: Option<Vec<T>>, dead: &[(usize, Option<usize>)]) -> Option<Vec<T>> {
+    let kept = items
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter(|(position, _)| dead.iter().all(|(p, _)| p != position))
+        .map(|(_, item)| item)
+        .collect::<Vec<_>>();
+    (!kept.is_empty()).then_some(kept)
+}
@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use crate::{
+    aliases::BlockID,
+    basic_block::BasicBlock,
+    bril_syntax::{Instruction, InstructionOrLabel, Position},
+    cfg::CFG,
+    data_flow::{DataFlowAnalysis, DataFlowDirection, DataFlowOrder, TransferResult},
+};
+
+/// Name used by the Bril SSA tools for a phi argument that has no reaching definition
+pub const UNDEFINED_MARKER: &str = "__undefined";
+
+/// What to do with a phi argument that is not defined at the end of its predecessor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndefinedPolicy {
+    /// Only report it
+    Error,
+    /// Rewrite the argument to `UNDEFINED_MARKER` and don't report it. Reads in ordinary
+    /// instructions have no marker to fall back to, they are always reported
+    InsertMarkers,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UndefinedUse {
+    pub position: Option<Position>,
+    pub instruction_id: usize,
+    pub var: String,
+    /// For a phi argument, the label of the predecessor the argument comes from
+    pub phi_label: Option<String>,
+}
+
+impl Display for UndefinedUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some(pos) => write!(f, "{}: ", pos)?,
+            None => write!(f, "instruction {}: ", self.instruction_id)?,
+        }
+        write!(f, "`{}` may be used before its definition", self.var)?;
+        match &self.phi_label {
+            Some(label) => write!(f, " (phi argument from .{})", label),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Forward must analysis, a variable is in the set if every path from the function entry
+/// defines it. A missing set is top: the block has not been reached (yet)
+pub struct DefiniteAssignment {
+    in_states: BTreeMap<BlockID, Option<BTreeSet<String>>>,
+    out_states: BTreeMap<BlockID, Option<BTreeSet<String>>>,
+    policy: UndefinedPolicy,
+    pub undefined_uses: BTreeSet<UndefinedUse>,
+    // The label of every block, phi arguments name their predecessor by it
+    labels: BTreeMap<BlockID, String>,
+}
+
+impl DefiniteAssignment {
+    pub fn new(cfg: &CFG, policy: UndefinedPolicy) -> DefiniteAssignment {
+        Self {
+            in_states: BTreeMap::default(),
+            out_states: BTreeMap::default(),
+            policy,
+            undefined_uses: BTreeSet::default(),
+            labels: cfg
+                .ordered_blocks()
+                .map(|bb| (bb.id, bb.get_label()))
+                .collect(),
+        }
+    }
+
+    /// Run the analysis over the whole program, with `UndefinedPolicy::InsertMarkers` the phis of
+    /// `cfg` are rewritten in place
+    pub fn check(cfg: &mut CFG, policy: UndefinedPolicy) -> BTreeSet<UndefinedUse> {
+        let mut result = Self::new(cfg, policy);
+        cfg.dataflow(&mut result);
+        result.undefined_uses
+    }
+
+    /// The variables every path defines by the end of `id`, once solved. Empty for a block that
+    /// is unreachable or always returns
+    pub fn defined_at_end(&self, id: BlockID) -> BTreeSet<String> {
+        self.out_states
+            .get(&id)
+            .cloned()
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    fn undefined_use(i: &Instruction, var: &str, phi_label: Option<String>) -> UndefinedUse {
+        UndefinedUse {
+            position: i.position(),
+            instruction_id: i.instruction_id.unwrap(),
+            var: var.to_string(),
+            phi_label,
+        }
+    }
+
+    /// Walk the block from its in state. Returns the out state, None when the block is unreachable
+    /// or always returns
+    fn walk(
+        &self,
+        bb: &BasicBlock,
+        undefined_uses: &mut BTreeSet<UndefinedUse>,
+    ) -> Option<BTreeSet<String>> {
+        let mut defined = self.in_states.get(&bb.id).cloned().flatten()?;
+        for ilb in bb.instrs.iter() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            // Phi arguments are checked against the predecessors in transform
+            if !i.is_phi() {
+                for arg in i.args.iter().flatten() {
+                    if !defined.contains(arg) {
+                        undefined_uses.insert(Self::undefined_use(i, arg, None));
+                    }
+                }
+            }
+            if i.is_ret() {
+                return None;
+            }
+            if let Some(dest) = &i.dest {
+                defined.insert(dest.clone());
+            }
+        }
+        Some(defined)
+    }
+}
+
+impl DataFlowAnalysis for DefiniteAssignment {
+    fn meet(&mut self, bb: &BasicBlock) {
+        let in_state = match &bb.func {
+            Some(func) => Some(
+                func.args
+                    .iter()
+                    .flatten()
+                    .map(|arg| arg.name.clone())
+                    .collect(),
+            ),
+            None => {
+                let mut result: Option<BTreeSet<String>> = None;
+                for pred in bb.predecessors.iter() {
+                    if let Some(Some(pred_state)) = self.out_states.get(pred) {
+                        result = match result {
+                            Some(state) => Some(state.intersection(pred_state).cloned().collect()),
+                            None => Some(pred_state.clone()),
+                        };
+                    }
+                }
+                result
+            }
+        };
+        self.in_states.insert(bb.id, in_state);
+    }
+
+    fn transfer(&mut self, bb: &BasicBlock) -> TransferResult {
+        let out_state = self.walk(bb, &mut BTreeSet::new());
+        match self.out_states.insert(bb.id, out_state.clone()) {
+            Some(initial) if initial == out_state => TransferResult::NonChanged,
+            _ => TransferResult::Changed,
+        }
+    }
+
+    fn transform(&mut self, bb: &mut BasicBlock) {
+        let mut undefined_uses = BTreeSet::new();
+        self.walk(bb, &mut undefined_uses);
+
+        let mut pred_states = BTreeMap::<String, Option<BTreeSet<String>>>::new();
+        for pred in bb.predecessors.iter() {
+            let state = self.out_states.get(pred).cloned().flatten();
+            pred_states.insert(self.labels[pred].clone(), state);
+        }
+        for ilb in bb.instrs.iter_mut() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            if !i.is_phi() {
+                continue;
+            }
+            let labels = i.labels.clone().unwrap_or_default();
+            let (position, instruction_id) = (i.position(), i.instruction_id.unwrap());
+            for (arg, label) in i.args.iter_mut().flatten().zip(labels) {
+                // An unreachable predecessor never hands its argument over
+                let Some(Some(defined)) = pred_states.get(&label) else {
+                    continue;
+                };
+                if defined.contains(arg) {
+                    continue;
+                }
+                match self.policy {
+                    UndefinedPolicy::InsertMarkers => *arg = UNDEFINED_MARKER.to_string(),
+                    UndefinedPolicy::Error => {
+                        undefined_uses.insert(UndefinedUse {
+                            position,
+                            instruction_id,
+                            var: arg.clone(),
+                            phi_label: Some(label),
+                        });
+                    }
+                }
+            }
+        }
+        self.undefined_uses.extend(undefined_uses);
+    }
+
+    fn get_dataflow_direction(&self) -> DataFlowDirection {
+        DataFlowDirection::Forward
+    }
+
+    fn get_dataflow_order(&self) -> DataFlowOrder {
+        DataFlowOrder::BFS
+    }
+}
@@ -1,6 +1,7 @@
-use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
 
 use crate::{
+    aliases::BlockID,
     basic_block::BasicBlock,
     cfg::*,
     data_flow::{DataFlowAnalysis, DataFlowDirection, DataFlowOrder, TransferResult},
@@ -11,43 +12,71 @@ impl DominatorTree {
 }
 pub struct PessimisticConstProp {}
 
+#[derive(Debug, Clone, Default)]
 pub struct DominanceDataFlow {
     pub domset: BTreeMap<usize, BTreeSet<usize>>,
     pub idom: BTreeMap<usize, usize>,
     pub domtree: BTreeMap<usize, usize>,
     pub df: BTreeMap<usize, HashSet<usize>>,
+    // The blocks reachable from the entry when a single function is analyzed
+    subset: Option<VecDeque<BlockID>>,
 }
 
 impl DominanceDataFlow {
+    /// The dominance of every function, each over the blocks its entry reaches
     pub fn new(cfg: &CFG) -> Self {
+        let mut result = Self::default();
+        for blocks in cfg.function_blocks() {
+            result.merge(&Self::for_function(cfg, &blocks));
+        }
+        result
+    }
+
+    /// The dominance of a single function, given its blocks as `CFG::function_blocks` groups them
+    pub fn for_function(cfg: &CFG, blocks: &[BlockID]) -> Self {
+        Self::with_blocks(cfg, blocks, cfg.bfs_children(blocks[0]))
+    }
+
+    /// Put the dominance of another function next to this one, block ids never collide
+    pub fn merge(&mut self, other: &DominanceDataFlow) {
+        self.domset.extend(other.domset.clone());
+        self.idom.extend(other.idom.clone());
+        self.domtree.extend(other.domtree.clone());
+        self.df.extend(other.df.clone());
+    }
+
+    fn with_blocks(cfg: &CFG, blocks: &[BlockID], subset: VecDeque<BlockID>) -> Self {
         // Initially, each node's dominator set is set to the set of all nodes
         let mut result = Self {
-            domset: BTreeMap::default(),
-            idom: BTreeMap::default(),
-            domtree: BTreeMap::default(),
-            df: BTreeMap::default(),
+            subset: Some(subset),
+            ..Self::default()
         };
-        for i in cfg.hm.clone() {
+        for bb in blocks.iter().map(|id| &cfg.blocks[*id]) {
             // INITIALIZE EACH OF THE DOM SET
-            let idb = i.1.borrow().id;
-            if i.1.borrow().func.is_some() {
+            let idb = bb.id;
+            if bb.func.is_some() {
                 result.domset.entry(idb).or_default().insert(idb);
             } else {
-                for ins in 0..cfg.hm.len() {
-                    result.domset.entry(idb).or_default().insert(ins);
-                }
+                result.domset.entry(idb).or_default().extend(blocks.iter());
             }
 
             // INITIALIZE EACH OF THE DOM TREE
             result.domtree.entry(idb).or_insert(idb);
             result.df.entry(idb).or_default();
         }
-        cfg.dataflow(&mut result);
+        cfg.solve(&mut result);
 
         result.infer(cfg);
         result
     }
 
+    /// Whether the entry reaches `block`, always true once functions are merged
+    fn reachable(&self, block: BlockID) -> bool {
+        self.subset
+            .as_ref()
+            .is_none_or(|subset| subset.contains(&block))
+    }
+
     pub fn dom(&self, dominator: usize, dominated: usize) -> bool {
         match self.domset.get(&dominated) {
             Some(set_of_dominator) => set_of_dominator.contains(&dominator),
@@ -135,19 +164,23 @@ impl DominanceDataFlow {
         //
         //
 
-        for (_, node_n) in cfg.hm.iter() {
-            if node_n.borrow().predecessors.len() > 1 {
-                eprintln!("In the node {}", node_n.borrow().id);
-                for pred in node_n.borrow().predecessors.iter() {
-                    let mut runner = pred.borrow().id;
+        for node_n in cfg.ordered_blocks() {
+            if !self.domset.contains_key(&node_n.id) || !self.reachable(node_n.id) {
+                continue;
+            }
+            if node_n.predecessors.len() > 1 {
+                eprintln!("In the node {}", node_n.id);
+                // An unreachable block keeps every block as dominator and its idom chain can loop
+                for pred in node_n.predecessors.iter() {
+                    if !self.reachable(*pred) {
+                        continue;
+                    }
+                    let mut runner = *pred;
                     eprintln!("Setting runner to be {runner}");
-                    while !self.idom(runner, node_n.borrow().id) {
+                    while !self.idom(runner, node_n.id) {
                         eprintln!("Setting runner to be {runner}");
-                        self.df
-                            .entry(runner)
-                            .or_default()
-                            .insert(node_n.borrow().id);
-                        eprintln!("Inserting df of {runner} having {}", node_n.borrow().id);
+                        self.df.entry(runner).or_default().insert(node_n.id);
+                        eprintln!("Inserting df of {runner} having {}", node_n.id);
                         if self.idom.contains_key(&runner) {
                             runner = self.idom[&runner];
                         } else {
@@ -163,17 +196,13 @@ impl DominanceDataFlow {
     }
 }
 impl DataFlowAnalysis for DominanceDataFlow {
-    fn meet(&mut self, _bb: &mut BasicBlock) {}
+    fn meet(&mut self, _bb: &BasicBlock) {}
 
-    fn transfer(&mut self, bb: &mut BasicBlock) -> TransferResult {
+    fn transfer(&mut self, bb: &BasicBlock) -> TransferResult {
         let initial = self.domset.entry(bb.id).or_default().clone();
         //eprintln!("initial of {:?} : {:?}", bb.id, initial);
-        let mut pred_id = vec![];
-
         // Get the ID of predecessors
-        for i in bb.predecessors.iter() {
-            pred_id.push(i.borrow().id);
-        }
+        let pred_id = bb.predecessors.clone();
         //eprintln!("Pred id of {:?} : {:?}", bb.id, pred_id);
 
         let mut result = BTreeSet::<usize>::new();
@@ -201,7 +230,7 @@ impl DataFlowAnalysis for DominanceDataFlow {
         }
     }
 
-    fn transform(&mut self, bb: &mut BasicBlock) {
+    fn transform(&mut self, _bb: &mut BasicBlock) {
         //eprintln!("Dominator of {:?} : {:?}", bb.id, self.domset.get(&bb.id))
     }
 
@@ -210,6 +239,9 @@ impl DataFlowAnalysis for DominanceDataFlow {
     }
 
     fn get_dataflow_order(&self) -> DataFlowOrder {
-        DataFlowOrder::BFS
+        match &self.subset {
+            Some(subset) => DataFlowOrder::Subset(subset.clone()),
+            None => DataFlowOrder::BFS,
+        }
     }
 }
@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    aliases::BlockID,
+    bril_syntax::InstructionOrLabel,
+    call_graph::{CallGraph, CallSite},
+    cfg::CFG,
+    optimistic_const_prop::{LatticeValue, OptimisticConstProp},
+};
+
+/// The most clones made of one function
+pub const MAX_SPECIALIZATIONS: usize = 4;
+
+/// How many times a call must have run, according to the profile, to get a clone of its own
+pub const HOT_CALL_SITE_COUNT: u64 = 8;
+
+/// The constant each argument of a call holds, if any
+type ArgumentPattern = Vec<Option<LatticeValue>>;
+
+/// INFO: This impl block is denoted to be about interprocedural constant propagation
+impl CFG {
+    /// The constant arguments of every call site of `graph`, as sccp finds them without knowing
+    /// anything about the parameters of the caller. Calls that never run pass nothing constant
+    fn argument_patterns(&self, graph: &CallGraph) -> Vec<ArgumentPattern> {
+        let mut sccp = OptimisticConstProp::new();
+        self.solve_optimistically(&mut sccp);
+        graph
+            .call_sites
+            .iter()
+            .map(|site| {
+                let bb = &self.blocks[site.block];
+                let facts = sccp.facts_before(bb, site.position).unwrap_or_default();
+                let InstructionOrLabel::Instruction(call) = &bb.instrs[site.position] else {
+                    unreachable!()
+                };
+                call.args
+                    .iter()
+                    .flatten()
+                    .map(|arg| facts.get(arg).copied().filter(|v| v.literal().is_some()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Where the entry block copies each parameter to the name the body uses
+    fn parameter_copies(&self, entry: BlockID) -> Vec<Option<usize>> {
+        let func = self.blocks[entry].func.as_ref().unwrap();
+        func.args
+            .iter()
+            .flatten()
+            .map(|arg| {
+                self.blocks[entry].instrs.iter().position(|ilb| match ilb {
+                    InstructionOrLabel::Instruction(i) => {
+                        i.is_id() && i.args.as_deref() == Some(std::slice::from_ref(&arg.name))
+                    }
+                    InstructionOrLabel::Label(_) => false,
+                })
+            })
+            .collect()
+    }
+
+    /// Turn the copy of every parameter with a constant in `pattern` into that constant
+    fn bind_parameters(&mut self, entry: BlockID, pattern: &ArgumentPattern) {
+        for (position, value) in self.parameter_copies(entry).into_iter().zip(pattern) {
+            let (Some(position), Some(value)) = (position, value) else {
+                continue;
+            };
+            if let InstructionOrLabel::Instruction(i) = &mut self.blocks[entry].instrs[position] {
+                i.op = "const".to_string();
+                i.args = None;
+                i.value = value.literal();
+            }
+        }
+    }
+
+    /// Copy the function made of `blocks` as a new function `name`, outside of `order`. Labels
+    /// are local to a function so they are kept, the blocks and instructions get new ids and the
+    /// parameters are renamed after the new function. Returns the blocks of the copy, the entry
+    /// block first
+    fn clone_function(&mut self, blocks: &[BlockID], name: &str) -> Vec<BlockID> {
+        let mut func = self.blocks[blocks[0]].func.clone().unwrap();
+        let prefix = format!("{}_", func.name);
+        let mut renamed = BTreeMap::new();
+        for arg in func.args.iter_mut().flatten() {
+            let original = arg.name.strip_prefix(&prefix).unwrap_or(&arg.name);
+            let new = format!("{}_{}", name, original);
+            renamed.insert(std::mem::replace(&mut arg.name, new.clone()), new);
+        }
+        func.name = name.to_string();
+
+        let ids = blocks
+            .iter()
+            .map(|id| (*id, self.new_block("")))
+            .collect::<BTreeMap<_, _>>();
+        for (old, new) in ids.iter() {
+            let mut instrs = self.blocks[*old].instrs.clone();
+            for ilb in instrs.iter_mut() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    continue;
+                };
+                i.instruction_id = Some(self.instruction_counter);
+                self.instruction_counter += 1;
+                for arg in i.args.iter_mut().flatten() {
+                    if let Some(new) = renamed.get(arg) {
+                        *arg = new.clone();
+                    }
+                }
+            }
+            let bb = &self.blocks[*old];
+            let predecessors = bb.predecessors.iter().map(|id| ids[id]).collect();
+            let successors = bb.successors.iter().map(|id| ids[id]).collect();
+            let bb = &mut self.blocks[*new];
+            bb.instrs = instrs;
+            bb.predecessors = predecessors;
+            bb.successors = successors;
+        }
+        self.blocks[ids[&blocks[0]]].func = Some(func);
+        blocks.iter().map(|id| ids[id]).collect()
+    }
+
+    /// Without a profile every call is hot
+    fn hot_call_site(&self, site: &CallSite) -> bool {
+        self.blocks[site.block]
+            .execution_count
+            .is_none_or(|count| count >= HOT_CALL_SITE_COUNT)
+    }
+
+    /// Propagate the constants functions are called with into them. A parameter every call
+    /// agrees on becomes a constant in the function itself, and the hot calls passing other
+    /// constants call a clone of the function specialized for them, at most
+    /// `MAX_SPECIALIZATIONS` per function. The functions changed are then folded with sccp.
+    /// Returns the number of functions changed, clones included
+    pub fn propagate_constants_interprocedurally(&mut self) -> usize {
+        let graph = CallGraph::new(self);
+        let patterns = self.argument_patterns(&graph);
+        let mut taken = graph.functions.keys().cloned().collect::<BTreeSet<_>>();
+        let mut changed = BTreeSet::new();
+        for (name, blocks) in graph.functions.iter() {
+            let arity = self.parameter_copies(blocks[0]).len();
+            let sites = graph
+                .call_sites
+                .iter()
+                .zip(patterns.iter())
+                .filter(|(site, pattern)| site.callee == *name && pattern.len() == arity)
+                .collect::<Vec<_>>();
+            if name == "main" || sites.is_empty() {
+                continue;
+            }
+
+            let agreed = (0..arity)
+                .map(|j| {
+                    let first = sites[0].1[j];
+                    first.filter(|_| sites.iter().all(|(_, pattern)| pattern[j] == first))
+                })
+                .collect::<ArgumentPattern>();
+            if agreed.iter().any(Option::is_some) {
+                eprintln!("Every call passes the same constants to @{}", name);
+                self.bind_parameters(blocks[0], &agreed);
+                changed.insert(blocks[0]);
+            }
+
+            // The calls passing the same other constants share a clone, the hottest ones first
+            let mut groups = Vec::<(ArgumentPattern, Vec<&CallSite>, u64)>::new();
+            for (site, pattern) in sites {
+                let pattern = pattern
+                    .iter()
+                    .zip(agreed.iter())
+                    .map(|(value, agreed)| value.filter(|_| agreed.is_none()))
+                    .collect::<ArgumentPattern>();
+                if pattern.iter().all(Option::is_none) || !self.hot_call_site(site) {
+                    continue;
+                }
+                let weight = self.blocks[site.block].execution_count.unwrap_or(1);
+                match groups.iter_mut().find(|(p, _, _)| *p == pattern) {
+                    Some((_, sites, total)) => {
+                        sites.push(site);
+                        *total += weight;
+                    }
+                    None => groups.push((pattern, vec![site], weight)),
+                }
+            }
+            groups.sort_by_key(|(_, _, weight)| std::cmp::Reverse(*weight));
+            let mut clones = Vec::new();
+            for (pattern, sites, _) in groups.into_iter().take(MAX_SPECIALIZATIONS) {
+                let clone = (0..)
+                    .map(|k| format!("{}.spec{}", name, k))
+                    .find(|clone| taken.insert(clone.clone()))
+                    .unwrap();
+                eprintln!(
+                    "Specializing @{} as @{} for {} calls",
+                    name,
+                    clone,
+                    sites.len()
+                );
+                let clone_blocks = self.clone_function(blocks, &clone);
+                let entry = clone_blocks[0];
+                self.bind_parameters(entry, &pattern);
+                for site in sites {
+                    if let InstructionOrLabel::Instruction(call) =
+                        &mut self.blocks[site.block].instrs[site.position]
+                    {
+                        call.funcs = Some(vec![clone.clone()]);
+                    }
+                }
+                changed.insert(entry);
+                clones.extend(clone_blocks);
+            }
+            // The clones go right after the function, in the order they were made
+            let last = self
+                .order
+                .iter()
+                .position(|id| id == blocks.last().unwrap());
+            let position = last.unwrap() + 1;
+            self.order.splice(position..position, clones);
+        }
+
+        // Fold only the functions changed, by hiding the others from sccp
+        let order = self.order.clone();
+        self.order = self
+            .function_blocks()
+            .into_iter()
+            .filter(|blocks| changed.contains(&blocks[0]))
+            .flatten()
+            .collect();
+        self.dataflow_forward_optimistically(&mut OptimisticConstProp::new());
+        self.order = order;
+        changed.len()
+    }
+}
@@ -0,0 +1,578 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use crate::{
+    aliases::BlockID,
+    basic_block::BasicBlock,
+    bril_syntax::{BrilType, Instruction, InstructionOrLabel},
+    cfg::CFG,
+    data_flow::{DataFlowAnalysis, DataFlowDirection, DataFlowOrder, TransferResult},
+    dominance::DominanceDataFlow,
+    loops::Loops,
+};
+
+/// `i64::MIN` and `i64::MAX` stand for the infinite bounds
+pub const NEG_INF: i64 = i64::MIN;
+pub const POS_INF: i64 = i64::MAX;
+
+/// A block that keeps changing this many times is widened even if it is not a loop header, so
+/// that irreducible control flow still terminates
+pub const MAX_VISITS_BEFORE_WIDENING: usize = 16;
+/// Narrowing only refines, but we stop refining a block after this many visits
+pub const MAX_NARROWING_VISITS: usize = 4;
+
+// Infinite bounds are mapped far away from the i64 range when doing arithmetic in i128
+const EXT_INF: i128 = 1 << 100;
+
+/// A closed range of `int` values. Overflow is only detected between finite bounds (the result is
+/// then the full range, Bril ints wrap), arithmetic on infinite bounds is done on mathematical
+/// integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Interval {
+    pub lo: i64,
+    pub hi: i64,
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.lo {
+            NEG_INF => write!(f, "[-inf, ")?,
+            lo => write!(f, "[{}, ", lo)?,
+        }
+        match self.hi {
+            POS_INF => write!(f, "+inf]"),
+            hi => write!(f, "{}]", hi),
+        }
+    }
+}
+
+impl Interval {
+    pub const TOP: Interval = Interval {
+        lo: NEG_INF,
+        hi: POS_INF,
+    };
+
+    /// None if the range is empty
+    pub fn new(lo: i64, hi: i64) -> Option<Interval> {
+        match lo <= hi {
+            true => Some(Self { lo, hi }),
+            false => None,
+        }
+    }
+
+    pub fn constant(c: i64) -> Interval {
+        Self { lo: c, hi: c }
+    }
+
+    pub fn as_constant(&self) -> Option<i64> {
+        match self.lo == self.hi && self.lo != NEG_INF && self.hi != POS_INF {
+            true => Some(self.lo),
+            false => None,
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.lo != NEG_INF && self.hi != POS_INF
+    }
+
+    pub fn is_non_negative(&self) -> bool {
+        self.lo >= 0
+    }
+
+    pub fn contains(&self, c: i64) -> bool {
+        self.lo <= c && c <= self.hi
+    }
+
+    pub fn join(&self, other: &Interval) -> Interval {
+        Self {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    pub fn intersect(&self, other: &Interval) -> Option<Interval> {
+        Self::new(self.lo.max(other.lo), self.hi.min(other.hi))
+    }
+
+    /// Any bound still moving after an iteration jumps to infinity
+    pub fn widen(&self, next: &Interval) -> Interval {
+        Self {
+            lo: if next.lo < self.lo { NEG_INF } else { self.lo },
+            hi: if next.hi > self.hi { POS_INF } else { self.hi },
+        }
+    }
+
+    /// Only the infinite bounds left by widening are refined
+    pub fn narrow(&self, next: &Interval) -> Interval {
+        Self {
+            lo: if self.lo == NEG_INF { next.lo } else { self.lo },
+            hi: if self.hi == POS_INF { next.hi } else { self.hi },
+        }
+    }
+
+    fn ext(bound: i64) -> i128 {
+        match bound {
+            NEG_INF => -EXT_INF,
+            POS_INF => EXT_INF,
+            b => b as i128,
+        }
+    }
+
+    /// Build an interval out of the extremes of a set of results computed in i128
+    fn from_ext(corners: &[i128], finite_inputs: bool) -> Interval {
+        let lo = *corners.iter().min().unwrap();
+        let hi = *corners.iter().max().unwrap();
+        if finite_inputs && (lo < i64::MIN as i128 || hi > i64::MAX as i128) {
+            return Self::TOP;
+        }
+        Self {
+            lo: if lo <= NEG_INF as i128 {
+                NEG_INF
+            } else {
+                lo as i64
+            },
+            hi: if hi >= POS_INF as i128 {
+                POS_INF
+            } else {
+                hi as i64
+            },
+        }
+    }
+
+    fn combine(&self, other: &Interval, op: impl Fn(i128, i128) -> i128) -> Interval {
+        let corners = [
+            op(Self::ext(self.lo), Self::ext(other.lo)),
+            op(Self::ext(self.lo), Self::ext(other.hi)),
+            op(Self::ext(self.hi), Self::ext(other.lo)),
+            op(Self::ext(self.hi), Self::ext(other.hi)),
+        ];
+        Self::from_ext(&corners, self.is_finite() && other.is_finite())
+    }
+
+    pub fn add(&self, other: &Interval) -> Interval {
+        self.combine(other, |a, b| a.saturating_add(b))
+    }
+
+    pub fn sub(&self, other: &Interval) -> Interval {
+        self.combine(other, |a, b| a.saturating_sub(b))
+    }
+
+    pub fn mul(&self, other: &Interval) -> Interval {
+        self.combine(other, |a, b| a.saturating_mul(b).clamp(-EXT_INF, EXT_INF))
+    }
+
+    /// Division truncates toward zero. A divisor of exactly zero is a runtime error in Bril, so
+    /// the divisor is split around it. None if the divisor can only be zero
+    pub fn div(&self, other: &Interval) -> Option<Interval> {
+        let negative = other.intersect(&Interval {
+            lo: NEG_INF,
+            hi: -1,
+        });
+        let positive = other.intersect(&Interval { lo: 1, hi: POS_INF });
+        [negative, positive]
+            .into_iter()
+            .flatten()
+            .map(|divisor| self.combine(&divisor, |a, b| a / b))
+            .reduce(|a, b| a.join(&b))
+    }
+}
+
+/// Facts at the program point before one instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionRanges {
+    pub args: Vec<Interval>,
+    pub dest: Option<Interval>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntervalPhase {
+    Widening,
+    Narrowing,
+}
+
+/// INFO: A missing variable is top. `conditions` remembers which comparison produced a bool so
+/// that a `br` on it can refine both sides of the comparison
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct State {
+    ranges: BTreeMap<String, Interval>,
+    conditions: BTreeMap<String, (String, String, String)>,
+}
+
+impl State {
+    fn get(&self, var: &String) -> Interval {
+        self.ranges.get(var).cloned().unwrap_or(Interval::TOP)
+    }
+
+    fn join(&self, other: &State) -> State {
+        let mut result = State::default();
+        for (var, range) in self.ranges.iter() {
+            if let Some(other_range) = other.ranges.get(var) {
+                result.ranges.insert(var.clone(), range.join(other_range));
+            }
+        }
+        for (var, condition) in self.conditions.iter() {
+            if other.conditions.get(var) == Some(condition) {
+                result.conditions.insert(var.clone(), condition.clone());
+            }
+        }
+        result
+    }
+
+    fn combine(&self, next: &State, op: impl Fn(&Interval, &Interval) -> Interval) -> State {
+        let mut result = next.clone();
+        for (var, range) in next.ranges.iter() {
+            let combined = op(self.ranges.get(var).unwrap_or(&Interval::TOP), range);
+            result.ranges.insert(var.clone(), combined);
+        }
+        result
+    }
+
+    fn define(&mut self, var: &String, range: Option<Interval>) {
+        self.conditions
+            .retain(|b, (_, x, y)| b != var && x != var && y != var);
+        match range {
+            Some(range) => self.ranges.insert(var.clone(), range),
+            None => self.ranges.remove(var),
+        };
+    }
+
+    /// Refine the state with `x op y` being `taken`, None if that can't happen
+    fn refine(&self, op: &str, x: &String, y: &String, taken: bool) -> Option<State> {
+        let (op, x, y) = match (op, taken) {
+            ("lt", true) | ("ge", false) => ("lt", x, y),
+            ("le", true) | ("gt", false) => ("le", x, y),
+            ("gt", true) | ("le", false) => ("lt", y, x),
+            ("ge", true) | ("lt", false) => ("le", y, x),
+            (op, taken) => (if taken { op } else { "ne" }, x, y),
+        };
+        let (a, b) = (self.get(x), self.get(y));
+        let dec = |bound: i64| match bound {
+            NEG_INF | POS_INF => bound,
+            bound => bound - 1,
+        };
+        let inc = |bound: i64| match bound {
+            NEG_INF | POS_INF => bound,
+            bound => bound + 1,
+        };
+        let (a, b) = match op {
+            "lt" => (
+                a.intersect(&Interval {
+                    lo: NEG_INF,
+                    hi: dec(b.hi),
+                })?,
+                b.intersect(&Interval {
+                    lo: inc(a.lo),
+                    hi: POS_INF,
+                })?,
+            ),
+            "le" => (
+                a.intersect(&Interval {
+                    lo: NEG_INF,
+                    hi: b.hi,
+                })?,
+                b.intersect(&Interval {
+                    lo: a.lo,
+                    hi: POS_INF,
+                })?,
+            ),
+            "eq" => (a.intersect(&b)?, a.intersect(&b)?),
+            _ => match (a.as_constant(), b.as_constant()) {
+                (Some(c), Some(d)) if c == d => return None,
+                _ => (a, b),
+            },
+        };
+        let mut result = self.clone();
+        result.ranges.insert(x.clone(), a);
+        result.ranges.insert(y.clone(), b);
+        Some(result)
+    }
+}
+
+pub struct IntervalAnalysis {
+    pub phase: IntervalPhase,
+    headers: BTreeSet<BlockID>,
+    visits: BTreeMap<BlockID, usize>,
+    in_states: BTreeMap<BlockID, Option<State>>,
+    // Keyed by (from, to). None is bottom: the edge is not taken (yet)
+    edge_states: BTreeMap<(BlockID, BlockID), Option<State>>,
+    facts: BTreeMap<usize, InstructionRanges>,
+    // The label of every block, the branches refine the edge to their label
+    labels: BTreeMap<BlockID, String>,
+}
+
+impl IntervalAnalysis {
+    pub fn new(cfg: &CFG) -> IntervalAnalysis {
+        // Each function is solved over the blocks its entry reaches
+        let headers = cfg
+            .function_blocks()
+            .iter()
+            .flat_map(|blocks| {
+                let dominance = DominanceDataFlow::for_function(cfg, blocks);
+                Loops::back_edges(cfg, &dominance).into_keys()
+            })
+            .collect();
+        Self {
+            phase: IntervalPhase::Widening,
+            headers,
+            visits: BTreeMap::default(),
+            in_states: BTreeMap::default(),
+            edge_states: BTreeMap::default(),
+            facts: BTreeMap::default(),
+            labels: cfg
+                .ordered_blocks()
+                .map(|bb| (bb.id, bb.get_label()))
+                .collect(),
+        }
+    }
+
+    /// Widen until stable, then narrow. Nothing is rewritten, query the result afterward
+    pub fn analyze(cfg: &CFG) -> IntervalAnalysis {
+        let mut result = Self::new(cfg);
+        cfg.solve(&mut result);
+        result.record(cfg);
+        result.phase = IntervalPhase::Narrowing;
+        result.visits.clear();
+        cfg.solve(&mut result);
+        result.record(cfg);
+        result
+    }
+
+    /// The ranges of the arguments before the instruction, and of its destination after it. None
+    /// if the instruction is unreachable
+    pub fn ranges(&self, instruction_id: usize) -> Option<&InstructionRanges> {
+        self.facts.get(&instruction_id)
+    }
+
+    pub fn arg_range(&self, instruction_id: usize, index: usize) -> Option<Interval> {
+        self.facts.get(&instruction_id)?.args.get(index).cloned()
+    }
+
+    pub fn dest_range(&self, instruction_id: usize) -> Option<Interval> {
+        self.facts.get(&instruction_id)?.dest
+    }
+
+    /// The value of a comparison if the ranges of its arguments decide it
+    pub fn fold_comparison(&self, i: &Instruction) -> Option<bool> {
+        let ranges = self.facts.get(&i.instruction_id?)?;
+        let (a, b) = (ranges.args.first()?, ranges.args.get(1)?);
+        match i.op.as_str() {
+            "lt" if a.hi < b.lo => Some(true),
+            "lt" if a.lo >= b.hi => Some(false),
+            "le" if a.hi <= b.lo => Some(true),
+            "le" if a.lo > b.hi => Some(false),
+            "gt" if a.lo > b.hi => Some(true),
+            "gt" if a.hi <= b.lo => Some(false),
+            "ge" if a.lo >= b.hi => Some(true),
+            "ge" if a.hi < b.lo => Some(false),
+            "eq" if a.as_constant().is_some() && a.as_constant() == b.as_constant() => Some(true),
+            "eq" if a.intersect(b).is_none() => Some(false),
+            _ => None,
+        }
+    }
+
+    fn evaluate(i: &Instruction, state: &State) -> Option<Interval> {
+        let args = i.args.clone().unwrap_or_default();
+        if i.is_const() {
+            return i.value.as_ref()?.as_i64().map(Interval::constant);
+        }
+        if i.bril_type != Some(BrilType::Int) {
+            return None;
+        }
+        if i.is_id() {
+            return Some(state.get(&args[0]));
+        }
+        let (a, b) = match args.as_slice() {
+            [a, b] => (state.get(a), state.get(b)),
+            _ => return Some(Interval::TOP),
+        };
+        if i.is_add() {
+            Some(a.add(&b))
+        } else if i.is_sub() {
+            Some(a.sub(&b))
+        } else if i.is_mul() {
+            Some(a.mul(&b))
+        } else if i.is_div() {
+            Some(a.div(&b).unwrap_or(Interval::TOP))
+        } else {
+            Some(Interval::TOP)
+        }
+    }
+
+    /// Walk the block from its in state. Returns the state at the end of the block, None if the
+    /// block is unreachable or returns
+    fn walk(
+        &self,
+        bb: &BasicBlock,
+        mut facts: Option<&mut BTreeMap<usize, InstructionRanges>>,
+    ) -> Option<State> {
+        let mut state = self.in_states.get(&bb.id).cloned().flatten()?;
+        for ilb in bb.instrs.iter() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            let args = i.args.clone().unwrap_or_default();
+            let arg_ranges = args.iter().map(|arg| state.get(arg)).collect();
+            if i.is_ret() {
+                if let Some(facts) = facts.as_mut() {
+                    facts.insert(
+                        i.instruction_id.unwrap(),
+                        InstructionRanges {
+                            args: arg_ranges,
+                            dest: None,
+                        },
+                    );
+                }
+                return None;
+            }
+
+            // Phis were already evaluated on the incoming edges by meet
+            let range = match i.is_phi() {
+                true => i
+                    .dest
+                    .as_ref()
+                    .and_then(|dest| state.ranges.get(dest).cloned()),
+                false => Self::evaluate(i, &state),
+            };
+            if let Some(dest) = &i.dest {
+                if !i.is_phi() {
+                    state.define(dest, range);
+                }
+                if ["lt", "le", "gt", "ge", "eq"].contains(&i.op.as_str()) && args.len() == 2 {
+                    state.conditions.insert(
+                        dest.clone(),
+                        (i.op.clone(), args[0].clone(), args[1].clone()),
+                    );
+                }
+            }
+            if let Some(facts) = facts.as_mut() {
+                facts.insert(
+                    i.instruction_id.unwrap(),
+                    InstructionRanges {
+                        args: arg_ranges,
+                        dest: range,
+                    },
+                );
+            }
+        }
+        Some(state)
+    }
+
+    /// The per instruction ranges of the current facts, nothing is rewritten
+    fn record(&mut self, cfg: &CFG) {
+        for bb in cfg.ordered_blocks() {
+            let mut facts = BTreeMap::new();
+            self.walk(bb, Some(&mut facts));
+            self.facts.extend(facts);
+        }
+    }
+}
+
+impl DataFlowAnalysis for IntervalAnalysis {
+    fn meet(&mut self, bb: &BasicBlock) {
+        let visits = self.visits.entry(bb.id).or_default();
+        *visits += 1;
+        let visits = *visits;
+
+        let mut incoming = BTreeMap::<String, State>::new();
+        let computed = match bb.func {
+            Some(_) => Some(State::default()),
+            None => {
+                let mut result: Option<State> = None;
+                for pred in bb.predecessors.iter() {
+                    let (pred_id, label) = (*pred, &self.labels[pred]);
+                    if let Some(Some(edge)) = self.edge_states.get(&(pred_id, bb.id)) {
+                        result = match result {
+                            Some(state) => Some(state.join(edge)),
+                            None => Some(edge.clone()),
+                        };
+                        incoming.insert(label.clone(), edge.clone());
+                    }
+                }
+                result
+            }
+        };
+        let computed = computed.map(|mut state| {
+            for ilb in bb.instrs.iter() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    continue;
+                };
+                if !i.is_phi() || i.bril_type != Some(BrilType::Int) {
+                    continue;
+                }
+                let labels = i.labels.clone().unwrap_or_default();
+                let range = i
+                    .args
+                    .iter()
+                    .flatten()
+                    .zip(labels.iter())
+                    .filter_map(|(arg, label)| incoming.get(label).map(|edge| edge.get(arg)))
+                    .reduce(|a, b| a.join(&b));
+                state.define(i.dest.as_ref().unwrap(), range);
+            }
+            state
+        });
+
+        let old = self.in_states.get(&bb.id).cloned().flatten();
+        let result = match (old, computed) {
+            (Some(old), Some(computed)) => match self.phase {
+                IntervalPhase::Widening
+                    if self.headers.contains(&bb.id) || visits > MAX_VISITS_BEFORE_WIDENING =>
+                {
+                    Some(old.combine(&computed, Interval::widen))
+                }
+                IntervalPhase::Widening => Some(computed),
+                IntervalPhase::Narrowing if visits > MAX_NARROWING_VISITS => Some(old),
+                IntervalPhase::Narrowing if self.headers.contains(&bb.id) => {
+                    Some(old.combine(&computed, Interval::narrow))
+                }
+                IntervalPhase::Narrowing => Some(computed),
+            },
+            (_, computed) => computed,
+        };
+        self.in_states.insert(bb.id, result);
+    }
+
+    fn transfer(&mut self, bb: &BasicBlock) -> TransferResult {
+        let out_state = self.walk(bb, None);
+
+        let branch = match bb.instrs.last() {
+            Some(InstructionOrLabel::Instruction(i)) if i.is_br() => Some(i.clone()),
+            _ => None,
+        };
+        let mut changed = TransferResult::NonChanged;
+        for succ in bb.successors.iter() {
+            let (succ_id, label) = (*succ, &self.labels[succ]);
+            let edge = match (&out_state, &branch) {
+                (Some(state), Some(br)) => {
+                    let cond = &br.args.as_ref().unwrap()[0];
+                    let labels = br.labels.clone().unwrap_or_default();
+                    match state.conditions.get(cond) {
+                        Some((op, x, y)) if labels[0] != labels[1] => {
+                            state.refine(op, x, y, *label == labels[0])
+                        }
+                        _ => Some(state.clone()),
+                    }
+                }
+                (state, _) => state.clone(),
+            };
+            if self.edge_states.insert((bb.id, succ_id), edge.clone()) != Some(edge) {
+                changed = TransferResult::Changed;
+            }
+        }
+        changed
+    }
+
+    fn transform(&mut self, bb: &mut BasicBlock) {
+        let mut facts = BTreeMap::new();
+        self.walk(bb, Some(&mut facts));
+        self.facts.extend(facts);
+    }
+
+    fn get_dataflow_direction(&self) -> DataFlowDirection {
+        DataFlowDirection::Forward
+    }
+
+    fn get_dataflow_order(&self) -> DataFlowOrder {
+        DataFlowOrder::BFS
+    }
+}
@@ -1,9 +1,32 @@
 pub mod alias_analysis;
 pub mod aliases;
+pub mod analysis_manager;
 pub mod basic_block;
+pub mod block_layout;
 pub mod bril_syntax;
+pub mod call_graph;
 pub mod cfg;
+pub mod cfg_mutation;
 pub mod data_flow;
+pub mod dead_argument_elimination;
+pub mod definite_assignment;
 pub mod dominance;
+pub mod interprocedural_const_prop;
+pub mod interval_analysis;
+pub mod liveness;
+pub mod loop_simplify;
 pub mod loops;
+pub mod mem2reg;
+pub mod memory_safety;
+pub mod optimistic_const_prop;
+pub mod pass_manager;
+pub mod pessimistic_const_prop;
+pub mod profile;
+pub mod purity;
+pub mod register_allocation;
+pub mod scalar_evolution;
 pub mod ssa_graph;
+pub mod tail_call_elimination;
+pub mod trace;
+pub mod translation_validation;
+pub mod verifier;
@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{
+    aliases::BlockID,
+    analysis_manager::AnalysisManager,
+    basic_block::BasicBlock,
+    bril_syntax::{Instruction, InstructionOrLabel},
+    cfg::CFG,
+    data_flow::{DataFlowAnalysis, DataFlowDirection, DataFlowOrder, TransferResult},
+    definite_assignment::UNDEFINED_MARKER,
+    purity::PuritySummaries,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LivenessKind {
+    /// A variable is live if some path reaches a use of it before a redefinition
+    Live,
+    /// Like `Live`, but a use only counts if the instruction has a side effect, changes control
+    /// flow or defines a strongly live variable itself
+    StronglyLive,
+}
+
+/// Backward may analysis computing the live variables at the boundaries of every block and
+/// every instruction.
+///
+/// Phis are handled the SSA way: a phi argument is live-out of the predecessor it comes from and
+/// not live-in of the block holding the phi, while the phi destination is defined at the very
+/// top of that block.
+pub struct Liveness {
+    kind: LivenessKind,
+    pub live_in: BTreeMap<BlockID, BTreeSet<String>>,
+    pub live_out: BTreeMap<BlockID, BTreeSet<String>>,
+    // Live variables right before and right after each instruction, filled once the facts are
+    // stable
+    live_before: BTreeMap<usize, BTreeSet<String>>,
+    live_after: BTreeMap<usize, BTreeSet<String>>,
+    // For a block, the predecessor block behind each of its labels
+    pred_of_label: BTreeMap<BlockID, BTreeMap<String, BlockID>>,
+    // Phi arguments a block needs from each of its predecessors, keyed by (pred, block)
+    phi_uses: BTreeMap<(BlockID, BlockID), BTreeSet<String>>,
+    // The blocks reachable from the entry when a single function is analyzed
+    subset: Option<VecDeque<BlockID>>,
+    // The functions a call to is not critical, see `PuritySummaries::removable_functions`
+    removable_calls: BTreeSet<String>,
+}
+
+impl Liveness {
+    pub fn new(cfg: &CFG, kind: LivenessKind) -> Liveness {
+        Self::with_blocks(cfg, &cfg.order, kind, None)
+    }
+
+    fn with_blocks(
+        cfg: &CFG,
+        blocks: &[BlockID],
+        kind: LivenessKind,
+        subset: Option<VecDeque<BlockID>>,
+    ) -> Liveness {
+        let mut pred_of_label = BTreeMap::<BlockID, BTreeMap<String, BlockID>>::new();
+        for bb in blocks.iter().map(|id| &cfg.blocks[*id]) {
+            let labels = pred_of_label.entry(bb.id).or_default();
+            for pred in bb.predecessors.iter() {
+                labels.insert(cfg.blocks[*pred].get_label(), *pred);
+            }
+        }
+        Self {
+            kind,
+            live_in: BTreeMap::default(),
+            live_out: BTreeMap::default(),
+            live_before: BTreeMap::default(),
+            live_after: BTreeMap::default(),
+            pred_of_label,
+            phi_uses: BTreeMap::default(),
+            subset,
+            removable_calls: BTreeSet::default(),
+        }
+    }
+
+    /// Run the analysis over the whole program
+    pub fn analyze(cfg: &CFG, kind: LivenessKind) -> Liveness {
+        let mut result = Self::new(cfg, kind);
+        cfg.solve(&mut result);
+        for bb in cfg.ordered_blocks() {
+            result.walk(bb, true);
+        }
+        result
+    }
+
+    /// Run the analysis over a single function, given its blocks as `CFG::function_blocks`
+    /// groups them
+    pub fn analyze_function(cfg: &CFG, blocks: &[BlockID], kind: LivenessKind) -> Liveness {
+        Self::analyze_function_with_summaries(cfg, blocks, kind, &PuritySummaries::default())
+    }
+
+    /// Same as `analyze_function`, with the calls to the functions `summaries` finds removable
+    /// treated like any other instruction without side effects
+    pub fn analyze_function_with_summaries(
+        cfg: &CFG,
+        blocks: &[BlockID],
+        kind: LivenessKind,
+        summaries: &PuritySummaries,
+    ) -> Liveness {
+        let subset = cfg.bfs_children(blocks[0]);
+        let mut result = Self::with_blocks(cfg, blocks, kind, Some(subset));
+        result.removable_calls = summaries.removable_functions();
+        cfg.solve(&mut result);
+        for bb in blocks.iter() {
+            result.walk(&cfg.blocks[*bb], true);
+        }
+        result
+    }
+
+    pub fn live_in(&self, bb: BlockID) -> BTreeSet<String> {
+        self.live_in.get(&bb).cloned().unwrap_or_default()
+    }
+
+    pub fn live_out(&self, bb: BlockID) -> BTreeSet<String> {
+        self.live_out.get(&bb).cloned().unwrap_or_default()
+    }
+
+    /// Variables live right before the instruction, phi arguments are never part of it
+    pub fn live_before(&self, instruction_id: usize) -> BTreeSet<String> {
+        self.live_before
+            .get(&instruction_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn live_after(&self, instruction_id: usize) -> BTreeSet<String> {
+        self.live_after
+            .get(&instruction_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether the instruction's destination is used later on. Instructions without a
+    /// destination are never dead
+    pub fn is_dead(&self, i: &Instruction) -> bool {
+        match &i.dest {
+            Some(dest) if !self.is_critical(i) => {
+                !self.live_after(i.instruction_id.unwrap()).contains(dest)
+            }
+            _ => false,
+        }
+    }
+
+    fn is_critical(&self, i: &Instruction) -> bool {
+        if i.is_call() {
+            let callee = i.funcs.iter().flatten().next();
+            return !callee.is_some_and(|callee| self.removable_calls.contains(callee));
+        }
+        i.has_side_effects() || i.is_nonlinear()
+    }
+
+    /// Whether the uses of `i` make its arguments live, given what is live right after it
+    fn uses_count(&self, i: &Instruction, live: &BTreeSet<String>) -> bool {
+        match self.kind {
+            LivenessKind::Live => true,
+            LivenessKind::StronglyLive => {
+                self.is_critical(i) || i.dest.as_ref().is_some_and(|dest| live.contains(dest))
+            }
+        }
+    }
+
+    /// Walk the block backward from its live-out set. Returns the live-in set and the phi
+    /// arguments needed from each predecessor, recording the per instruction sets when asked to
+    fn walk(
+        &mut self,
+        bb: &BasicBlock,
+        record: bool,
+    ) -> (BTreeSet<String>, BTreeMap<BlockID, BTreeSet<String>>) {
+        let mut live = self.live_out(bb.id);
+        let mut phi_uses = BTreeMap::<BlockID, BTreeSet<String>>::new();
+        for ilb in bb.instrs.iter().rev() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                continue;
+            };
+            let instruction_id = i.instruction_id.unwrap();
+            if record {
+                self.live_after.insert(instruction_id, live.clone());
+            }
+            let uses_count = self.uses_count(i, &live);
+            if let Some(dest) = &i.dest {
+                live.remove(dest);
+            }
+            if i.is_phi() {
+                if uses_count {
+                    let args = i.args.iter().flatten();
+                    for (arg, label) in args.zip(i.labels.iter().flatten()) {
+                        let Some(pred) = self.pred_of_label[&bb.id].get(label) else {
+                            continue;
+                        };
+                        if arg != UNDEFINED_MARKER {
+                            phi_uses.entry(*pred).or_default().insert(arg.clone());
+                        }
+                    }
+                }
+            } else if uses_count {
+                live.extend(i.args.iter().flatten().cloned());
+            }
+            if record {
+                self.live_before.insert(instruction_id, live.clone());
+            }
+        }
+        (live, phi_uses)
+    }
+}
+
+impl DataFlowAnalysis for Liveness {
+    /// The live-out set is the union of the live-in sets of the successors, plus the phi
+    /// arguments they take from this block
+    fn meet(&mut self, bb: &BasicBlock) {
+        let mut live_out = BTreeSet::new();
+        for succ in bb.successors.iter() {
+            live_out.extend(self.live_in(*succ));
+            if let Some(uses) = self.phi_uses.get(&(bb.id, *succ)) {
+                live_out.extend(uses.iter().cloned());
+            }
+        }
+        self.live_out.insert(bb.id, live_out);
+    }
+
+    fn transfer(&mut self, bb: &BasicBlock) -> TransferResult {
+        let (live_in, phi_uses) = self.walk(bb, false);
+        let mut result = match self.live_in.insert(bb.id, live_in.clone()) {
+            Some(initial) if initial == live_in => TransferResult::NonChanged,
+            _ => TransferResult::Changed,
+        };
+        for pred in self.pred_of_label[&bb.id].values() {
+            let uses = phi_uses.get(pred).cloned().unwrap_or_default();
+            if self.phi_uses.insert((*pred, bb.id), uses.clone()) != Some(uses) {
+                result = TransferResult::Changed;
+            }
+        }
+        result
+    }
+
+    /// Nothing is rewritten, only the per instruction sets are recorded
+    fn transform(&mut self, bb: &mut BasicBlock) {
+        self.walk(bb, true);
+    }
+
+    fn get_dataflow_direction(&self) -> DataFlowDirection {
+        DataFlowDirection::Backward
+    }
+
+    fn get_dataflow_order(&self) -> DataFlowOrder {
+        match &self.subset {
+            Some(subset) => DataFlowOrder::Subset(subset.clone()),
+            None => DataFlowOrder::BFS,
+        }
+    }
+}
+
+/// INFO: This impl block is denoted to be about dead code elimination on top of liveness
+impl CFG {
+    /// Remove every instruction without side effects whose destination is not strongly live
+    /// afterward, calls to the functions `PuritySummaries` finds removable included. Returns the
+    /// number of removed instructions
+    pub fn eliminate_dead_code(&mut self) -> usize {
+        self.eliminate_dead_code_with(&mut AnalysisManager::new())
+    }
+
+    /// Same as `eliminate_dead_code`, reusing the liveness cached in `analyses`
+    pub fn eliminate_dead_code_with(&mut self, analyses: &mut AnalysisManager) -> usize {
+        let mut removed = 0;
+        for blocks in self.function_blocks() {
+            let liveness = analyses.liveness(self, &blocks, LivenessKind::StronglyLive);
+            for id in blocks.iter() {
+                let bb = &mut self.blocks[*id];
+                let mut kept_instruction = Vec::<InstructionOrLabel>::new();
+                for ilb in bb.instrs.iter() {
+                    match ilb {
+                        InstructionOrLabel::Instruction(i) if liveness.is_dead(i) => {
+                            eprintln!("Removing dead definition of {}", i.dest.clone().unwrap());
+                            removed += 1;
+                        }
+                        _ => kept_instruction.push(ilb.clone()),
+                    }
+                }
+                bb.instrs = kept_instruction;
+            }
+        }
+        removed
+    }
+}
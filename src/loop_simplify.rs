@@ -0,0 +1,212 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    aliases::BlockID, bril_syntax::InstructionOrLabel, cfg::CFG, dominance::DominanceDataFlow,
+    loops::NaturalLoops,
+};
+
+/// The most instructions loop rotation copies out of a header, its label and branch aside
+pub const MAX_ROTATED_HEADER_SIZE: usize = 16;
+
+/// Edges from `sources` to `target` that should go through a new block labeled `label`
+struct Reroute {
+    target: BlockID,
+    sources: Vec<BlockID>,
+    label: String,
+}
+
+/// INFO: This impl block is denoted to be about loop simplification
+impl CFG {
+    /// The natural loops of the function made of `blocks`, leaving out the unreachable blocks
+    /// whose dominators mean nothing
+    fn reachable_loops(&self, blocks: &[BlockID]) -> NaturalLoops {
+        let reachable = self
+            .bfs_children(blocks[0])
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+        let dominance = DominanceDataFlow::for_function(self, blocks);
+        let mut loops = NaturalLoops::new(self, &dominance);
+        for latches in loops.latches.values_mut() {
+            latches.retain(|id| reachable.contains(id));
+        }
+        loops.latches.retain(|_, latches| !latches.is_empty());
+        loops.bodies.retain(|header, _| reachable.contains(header));
+        for body in loops.bodies.values_mut() {
+            body.retain(|id| reachable.contains(id));
+        }
+        loops
+    }
+
+    /// The first thing keeping a loop of the function made of `blocks` from being in simplified
+    /// form: a header without a preheader, more than one latch or an exit block also reached from
+    /// outside of the loop
+    fn next_reroute(&self, blocks: &[BlockID]) -> Option<Reroute> {
+        let loops = self.reachable_loops(blocks);
+        for (header, latches) in loops.latches.iter() {
+            let body = &loops.bodies[header];
+            let header_label = self.blocks[*header].get_label();
+            let outside = self.blocks[*header]
+                .predecessors
+                .iter()
+                .filter(|pred| !body.contains(pred))
+                .copied()
+                .collect::<BTreeSet<_>>();
+            let dedicated = |pred: &BlockID| {
+                let successors = self.blocks[*pred].successors.iter();
+                successors.clone().all(|succ| succ == header)
+            };
+            if !(outside.len() == 1 && outside.iter().all(dedicated)) {
+                return Some(Reroute {
+                    target: *header,
+                    sources: outside.into_iter().collect(),
+                    label: header_label + "_preheader",
+                });
+            }
+            if latches.len() > 1 {
+                return Some(Reroute {
+                    target: *header,
+                    sources: latches.iter().copied().collect(),
+                    label: header_label + "_latch",
+                });
+            }
+            for exit in body
+                .iter()
+                .flat_map(|id| self.blocks[*id].successors.iter())
+            {
+                let predecessors = self.blocks[*exit].predecessors.iter();
+                let (inside, outside) =
+                    predecessors.partition::<BTreeSet<_>, _>(|p| body.contains(p));
+                if body.contains(exit) || outside.is_empty() {
+                    continue;
+                }
+                return Some(Reroute {
+                    target: *exit,
+                    sources: inside.into_iter().copied().collect(),
+                    label: header_label + "_exit",
+                });
+            }
+        }
+        None
+    }
+
+    /// Put the loops of the function starting at `entry` in simplified form. Returns the number
+    /// of blocks inserted
+    fn simplify_loops_of(&mut self, entry: BlockID) -> usize {
+        let mut inserted = 0;
+        while let Some(reroute) = self.next_reroute(&self.function_of(entry)) {
+            let new = self.insert_block_before(reroute.target, &reroute.sources, &reroute.label);
+            eprintln!(
+                "Adding .{} before .{}",
+                self.blocks[new].get_label(),
+                self.blocks[reroute.target].get_label()
+            );
+            inserted += 1;
+        }
+        inserted
+    }
+
+    /// Put every loop in simplified form: a preheader whose only successor is the header, a
+    /// single latch, and exit blocks only reached from inside of the loop. Self loops included.
+    /// Phis are kept up to date so SSA form is kept. Returns the number of blocks inserted
+    pub fn simplify_loops(&mut self) -> usize {
+        let entries = self.function_blocks().into_iter().map(|blocks| blocks[0]);
+        entries
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|entry| self.simplify_loops_of(entry))
+            .sum()
+    }
+
+    /// A loop of the function made of `blocks` in simplified form and shaped like a `while`: the
+    /// header tests whether to leave, the latch jumps back unconditionally. Returns its header,
+    /// preheader and latch
+    fn rotatable_loop(&self, blocks: &[BlockID]) -> Option<(BlockID, BlockID, BlockID)> {
+        let loops = self.reachable_loops(blocks);
+        loops.latches.iter().find_map(|(header, latches)| {
+            let body = &loops.bodies[header];
+            let [latch] = latches.iter().copied().collect::<Vec<_>>()[..] else {
+                return None;
+            };
+            let bb = &self.blocks[*header];
+            let [preheader] = bb
+                .predecessors
+                .iter()
+                .copied()
+                .filter(|pred| !body.contains(pred))
+                .collect::<Vec<_>>()[..]
+            else {
+                return None;
+            };
+            let instrs = bb.instrs[1..].iter().filter_map(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => Some(i),
+                InstructionOrLabel::Label(_) => None,
+            });
+            let copyable = instrs
+                .clone()
+                .all(|i| !(i.is_phi() || i.is_guard() || i.op == "speculate" || i.op == "commit"));
+            let exits = bb.successors.iter().filter(|succ| !body.contains(succ));
+            let while_shaped = bb.ends_with_br()
+                && exits.count() == 1
+                && latch != *header
+                && self.blocks[latch].successors == [*header]
+                && !self.blocks[latch].ends_with_br();
+            (while_shaped && copyable && instrs.count() <= MAX_ROTATED_HEADER_SIZE + 1)
+                .then_some((*header, preheader, latch))
+        })
+    }
+
+    /// Turn the loop of `header` into a `do-while` guarded by its preheader: the instructions of
+    /// the header, its exit test included, replace the jumps to it at the end of the preheader
+    /// and of the latch, and the header goes away
+    fn rotate_loop(&mut self, header: BlockID, preheader: BlockID, latch: BlockID) {
+        let (test, br) = self.blocks[header]
+            .instrs
+            .split_at(self.blocks[header].instrs.len() - 1);
+        let (test, br) = (test[1..].to_vec(), br[0].clone());
+        for block in [preheader, latch] {
+            self.replace_terminator(block, br.clone());
+            let copies = test.iter().map(|ilb| {
+                let mut ilb = ilb.clone();
+                if let InstructionOrLabel::Instruction(i) = &mut ilb {
+                    i.instruction_id = Some(self.instruction_counter);
+                    self.instruction_counter += 1;
+                }
+                ilb
+            });
+            let copies = copies.collect::<Vec<_>>();
+            let instrs = &mut self.blocks[block].instrs;
+            let position = instrs.len() - 1;
+            instrs.splice(position..position, copies);
+        }
+        self.remove_block(header);
+    }
+
+    /// Rotate the `while` shaped loops of the functions not in SSA form, whose header would be
+    /// copied twice, into `do-while` loops guarded by a copy of their exit test. The body then
+    /// runs in a loop whose preheader only runs when it does at least once, which is where loop
+    /// invariant code can safely go. The loops are left in simplified form. Returns the number of
+    /// loops rotated
+    pub fn rotate_loops(&mut self) -> usize {
+        let mut rotated = 0;
+        for blocks in self.function_blocks() {
+            let entry = blocks[0];
+            let ssa = blocks
+                .iter()
+                .flat_map(|id| self.blocks[*id].instrs.iter())
+                .any(|ilb| matches!(ilb, InstructionOrLabel::Instruction(i) if i.is_phi()));
+            if ssa {
+                continue;
+            }
+            self.simplify_loops_of(entry);
+            while let Some((header, preheader, latch)) =
+                self.rotatable_loop(&self.function_of(entry))
+            {
+                eprintln!("Rotating the loop of .{}", self.blocks[header].get_label());
+                self.rotate_loop(header, preheader, latch);
+                rotated += 1;
+                self.simplify_loops_of(entry);
+            }
+        }
+        rotated
+    }
+}
@@ -1,7 +1,7 @@
-use std::collections::{BTreeMap, BTreeSet, LinkedList, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use crate::{
-    aliases::{BbPtr, BlockID},
+    aliases::BlockID,
     basic_block::BasicBlock,
     bril_syntax::InstructionOrLabel,
     cfg::CFG,
@@ -10,20 +10,34 @@ use crate::{
 };
 
 pub struct Loop {
-    pub preheader: BbPtr,
-    pub header: BbPtr,
-    pub latch: BbPtr,
-    pub loop_nodes: VecDeque<BbPtr>,
-    pub exit: Option<BbPtr>,
+    pub preheader: BlockID,
+    pub header: BlockID,
+    pub latch: BlockID,
+    pub loop_nodes: VecDeque<BlockID>,
+    pub exit: Option<BlockID>,
     pub defined_variables: BTreeSet<String>,
 
     // INFO: Dataflow information
     pub invariant_variable_maps: BTreeMap<usize, BTreeSet<String>>,
+    /// The invariant definitions `transform` took out of the loop blocks, they go at the end of
+    /// the preheader
+    pub hoisted: Vec<InstructionOrLabel>,
+    /// The functions a call to is invariant when its arguments are, see
+    /// `PuritySummaries::pure_functions`
+    pub pure_functions: BTreeSet<String>,
+    /// The variables every path defines by the end of the preheader, the only ones from outside
+    /// of the loop a hoisted instruction may read
+    pub defined_before: BTreeSet<String>,
+    /// The variables the function defines exactly once, only their definition can move without
+    /// changing what the other definitions see
+    pub defined_once: BTreeSet<String>,
 }
 
 pub enum PreHeaderCreate {
     Create,
     DontCreate,
+    /// The loop is in simplified form and this is its preheader, see `CFG::simplify_loops`
+    Existing(BlockID),
 }
 impl Loop {
     pub fn new_with_header_and_latch(
@@ -34,35 +48,29 @@ impl Loop {
     ) -> Loop {
         let preheader = match preheader_create {
             PreHeaderCreate::Create => Loop::create_preheader(cfg, header_id),
-            PreHeaderCreate::DontCreate => cfg
-                .id_to_bb
-                .get(header_id)
-                .unwrap()
-                .borrow()
-                .predecessors
-                .first()
-                .unwrap()
-                .clone(),
+            PreHeaderCreate::DontCreate => *cfg.blocks[*header_id].predecessors.first().unwrap(),
+            PreHeaderCreate::Existing(preheader) => preheader,
         };
         let loop_nodes = Self::bfs_from_latches_to_head(cfg, header_id, latch_id);
 
         let mut result = Self {
             preheader,
-            header: cfg.id_to_bb.get(header_id).unwrap().clone(),
-            latch: cfg.id_to_bb.get(latch_id).unwrap().clone(),
+            header: *header_id,
+            latch: *latch_id,
             loop_nodes,
             exit: None,
             defined_variables: BTreeSet::default(),
             invariant_variable_maps: BTreeMap::default(),
+            hoisted: Vec::new(),
+            pure_functions: BTreeSet::default(),
+            defined_before: BTreeSet::default(),
+            defined_once: BTreeSet::default(),
         };
 
         for node in result.loop_nodes.iter() {
-            result
-                .invariant_variable_maps
-                .entry(node.borrow().id)
-                .or_default();
+            result.invariant_variable_maps.entry(*node).or_default();
         }
-        result.defined_variables = result.get_defined_variables();
+        result.defined_variables = result.get_defined_variables(cfg);
 
         result
         // Self{
@@ -72,110 +80,31 @@ impl Loop {
         // }
     }
 
-    pub fn create_preheader(cfg: &mut CFG, header_id: &BlockID) -> BbPtr {
-        // Create a new BbPtr from cfg's basic block counter
-        let label = match cfg
-            .id_to_bb
-            .get(header_id)
-            .unwrap()
-            .borrow()
-            .instrs
-            .front()
-            .unwrap()
-        {
-            InstructionOrLabel::Label(label) => label.label.clone(),
-            _ => panic!("Would never happen"),
-        };
-
-        let internal_counter = cfg.basic_block_counter;
-        let bb =
-            BasicBlock::default_with_label(&mut cfg.basic_block_counter, &(label + "_preheader"));
-
-        eprintln!("{:?}", bb.instrs);
-        eprintln!("BB ptr has id {}", bb.id);
-        let bb_ptr = BbPtr::new(bb.into());
-
-        // locate the header id
-
-        let header_bb_ptr = cfg.id_to_bb.get(header_id).unwrap();
-        eprintln!(
-            "Current header is {:?}",
-            header_bb_ptr.borrow().instrs.front()
-        );
-        // bbptr's successor is header id
-        bb_ptr.borrow_mut().successors.push(header_bb_ptr.clone());
-        //
-
-        // All successor of bb_ptr should now point to bb_ptr instead of header_ptr
-        //
-        for pred in header_bb_ptr.borrow().predecessors.iter() {
-            if header_bb_ptr.borrow().id == pred.borrow().id {
-                panic!("I detect a self loop here, is this valid for a bril IR ?");
-            }
-            let pred_id = pred.borrow().id;
-            eprintln!("Predecessor {}", pred_id);
-            for succ in pred.borrow_mut().successors.iter_mut() {
-                if succ.borrow().id != *header_id {
-                    continue;
-                }
-
-                eprintln!("Predecessor {} has {header_id} before", pred_id);
-                *succ = bb_ptr.clone();
-            }
-            // for succ in pred.borrow().successors.iter() {
-            //     if succ.borrow().id != *header_id {
-            //         continue;
-            //     }
-            //
-            //     eprintln!("Predecessor {} has {} after", pred_id, succ.borrow().id);
-            // }
-        }
-        // any predecessor of header id is now bbptr's
-        bb_ptr
-            .borrow_mut()
+    /// Put a new block before the header taking every edge into it from outside of the loop,
+    /// that is from the predecessors the header cannot reach
+    pub fn create_preheader(cfg: &mut CFG, header_id: &BlockID) -> BlockID {
+        let reachable = cfg.bfs_children(*header_id);
+        let outside = cfg.blocks[*header_id]
             .predecessors
-            .append(&mut header_bb_ptr.borrow_mut().predecessors);
-        // header id's only predecessor is bbptr
-        header_bb_ptr.borrow_mut().predecessors = Vec::new();
-        header_bb_ptr.borrow_mut().predecessors.push(bb_ptr.clone());
-
-        eprintln!("Before, hm has {}", cfg.hm.len());
-        // Now, put this to the hashmap before i forgot
-        cfg.hm.insert(
-            bb_ptr.borrow_mut().instrs.front().unwrap().clone(),
-            bb_ptr.clone(),
-        );
-        cfg.id_to_bb.insert(internal_counter, bb_ptr.clone());
-
-        //let mut tail = self.instrs.split_off(position);
-        //
-        //self.instrs.push_back(ilb.clone()); // Manually walk the iterator to the desired position
-        //self.instrs.append(&mut tail);
-        let mut i: usize = 0;
-        for bb_ptr in cfg.bb_ptr_vec.iter() {
-            if bb_ptr.borrow().id == *header_id {
-                break;
-            }
-            i += 1;
-        }
-        let mut tail = cfg.bb_ptr_vec.split_off(i);
-        cfg.bb_ptr_vec.push_back(bb_ptr.clone());
-
-        cfg.bb_ptr_vec.append(&mut tail);
-        eprintln!("Create a new block with id :  {}", cfg.basic_block_counter);
-
-        eprintln!("After, hm has {}", cfg.hm.len());
-        bb_ptr.clone()
+            .iter()
+            .filter(|pred_id| !reachable.contains(pred_id))
+            .copied()
+            .collect::<Vec<_>>();
+        let label = cfg.blocks[*header_id].get_label() + "_preheader";
+        let bb_id = cfg.insert_block_before(*header_id, &outside, &label);
+        eprintln!("Create a new block with id :  {}", bb_id);
+
+        bb_id
     }
 
-    pub fn get_defined_variables(&self) -> BTreeSet<String> {
+    pub fn get_defined_variables(&self, cfg: &CFG) -> BTreeSet<String> {
         let mut result = BTreeSet::new();
         for node in self.loop_nodes.iter() {
-            for instr in node.borrow().instrs.clone() {
+            for instr in cfg.blocks[*node].instrs.iter() {
                 match instr {
                     InstructionOrLabel::Instruction(i) => {
-                        if i.dest.is_some() {
-                            result.insert(i.dest.unwrap());
+                        if let Some(dest) = &i.dest {
+                            result.insert(dest.clone());
                         }
                     }
                     _ => {
@@ -191,38 +120,35 @@ impl Loop {
         cfg: &mut CFG,
         header_id: &BlockID,
         latch_id: &BlockID,
-    ) -> VecDeque<BbPtr> {
-        let header_bb = cfg.id_to_bb.get(header_id).unwrap();
-        let latch_bb = cfg.id_to_bb.get(latch_id).unwrap();
-
+    ) -> VecDeque<BlockID> {
         let mut q = VecDeque::new();
-        q.push_back(latch_bb.clone());
+        q.push_back(*latch_id);
 
         let mut loop_nodes = VecDeque::new();
-        loop_nodes.push_back(latch_bb.clone());
+        loop_nodes.push_back(*latch_id);
 
         let mut visited = BTreeSet::<BlockID>::new();
-        visited.insert(latch_bb.borrow().id);
+        visited.insert(*latch_id);
 
         eprintln!("Putting in the back the latch : {}", latch_id);
         while !q.is_empty() {
             let a = q.pop_front().unwrap();
 
-            if a.borrow().id == *header_id {
+            if a == *header_id {
                 continue;
             }
 
-            for preq in a.borrow().predecessors.clone() {
-                if !visited.contains(&preq.borrow().id) && preq.borrow().id != *header_id {
-                    q.push_front(preq.clone());
-                    loop_nodes.push_front(preq.clone());
+            for preq in cfg.blocks[a].predecessors.iter() {
+                if preq != header_id && visited.insert(*preq) {
+                    q.push_front(*preq);
+                    loop_nodes.push_front(*preq);
                 }
             }
         }
-        loop_nodes.push_front(header_bb.clone());
+        loop_nodes.push_front(*header_id);
         eprintln!("From start to finish");
         for node in loop_nodes.iter() {
-            eprintln!("Node {}", node.borrow().id);
+            eprintln!("Node {}", node);
         }
 
         loop_nodes
@@ -231,7 +157,7 @@ impl Loop {
 impl Loop {
     fn register_variable(&mut self, bb_id: BlockID, var: &String) -> bool {
         if !self.defined_variables.contains(var) {
-            true
+            self.defined_before.contains(var)
         } else {
             self.invariant_variable_maps
                 .entry(bb_id)
@@ -241,14 +167,12 @@ impl Loop {
     }
 }
 impl DataFlowAnalysis for Loop {
-    fn meet(&mut self, bb: &mut BasicBlock) {
+    fn meet(&mut self, bb: &BasicBlock) {
         // TODO: For all predecessor, union them with the current basic block's facts
 
         let mut keys = BTreeSet::<usize>::new();
         keys.insert(bb.id);
-        for pred in bb.predecessors.iter() {
-            keys.insert(pred.borrow().id);
-        }
+        keys.extend(bb.predecessors.iter());
 
         *self.invariant_variable_maps.entry(bb.id).or_default() =
             keys.iter().fold(BTreeSet::new(), |acc, &key| {
@@ -260,7 +184,7 @@ impl DataFlowAnalysis for Loop {
             });
     }
 
-    fn transfer(&mut self, bb: &mut BasicBlock) -> crate::data_flow::TransferResult {
+    fn transfer(&mut self, bb: &BasicBlock) -> crate::data_flow::TransferResult {
         //  A value (we are in SSA!) is loop invariant if either:
         let clone_state = self
             .invariant_variable_maps
@@ -286,8 +210,25 @@ impl DataFlowAnalysis for Loop {
                         }
                     }
 
-                    if all_arg_invariant || i.is_const() {
-                        if let Some(dest) = &i.dest {
+                    // A call may give another result or do something else every time around.
+                    // The preheader runs even when the body does not, so nothing that may fail
+                    // or read memory moves there
+                    let pure = match i.is_call() {
+                        true => i
+                            .funcs
+                            .iter()
+                            .flatten()
+                            .all(|f| self.pure_functions.contains(f)),
+                        false => {
+                            !(i.has_side_effects()
+                                || i.is_phi()
+                                || i.is_load()
+                                || i.is_div()
+                                || i.is_int2char())
+                        }
+                    };
+                    if (all_arg_invariant || i.is_const()) && pure {
+                        if let Some(dest) = i.dest.iter().find(|d| self.defined_once.contains(*d)) {
                             self.invariant_variable_maps
                                 .entry(bb.id)
                                 .or_default()
@@ -319,7 +260,7 @@ impl DataFlowAnalysis for Loop {
     fn transform(&mut self, bb: &mut BasicBlock) {
         // TODO: All the loop invariant code (in terms of definition), we move to preheader
         //
-        let mut kept_instruction = LinkedList::<InstructionOrLabel>::new();
+        let mut kept_instruction = Vec::<InstructionOrLabel>::new();
         let var_map = self
             .invariant_variable_maps
             .entry(bb.id)
@@ -331,16 +272,16 @@ impl DataFlowAnalysis for Loop {
                     if let Some(dest) = &i.dest {
                         // if it is defined in the loop and it is loop invariant
                         if self.defined_variables.contains(dest) && var_map.contains(dest) {
-                            self.preheader.borrow_mut().instrs.push_back(ilb.clone())
+                            self.hoisted.push(ilb.clone())
                         } else {
-                            kept_instruction.push_back(ilb.clone())
+                            kept_instruction.push(ilb.clone())
                         }
                     } else {
-                        kept_instruction.push_back(ilb.clone())
+                        kept_instruction.push(ilb.clone())
                     }
                 }
 
-                InstructionOrLabel::Label(_) => kept_instruction.push_back(ilb.clone()),
+                InstructionOrLabel::Label(_) => kept_instruction.push(ilb.clone()),
             }
         }
 
@@ -359,32 +300,56 @@ pub struct Loops {
     pub loops: Vec<Loop>,
 }
 
-impl Loops {
-    pub fn new(cfg: &mut CFG) -> Loops {
-        let dominance = DominanceDataFlow::new(cfg);
-        let mut loop_start_end = BTreeMap::<BlockID, BTreeSet<BlockID>>::new();
-        for (dominated, dominator_set) in &dominance.domset {
-            match cfg.id_to_bb.get(dominated) {
-                Some(bbptr) => {
-                    for succ in bbptr.borrow().successors.iter() {
-                        let succ_id = &succ.borrow().id;
-                        if dominator_set.contains(succ_id)
-                            && (cfg.id_to_bb[succ_id].borrow().ends_with_br()
-                                || cfg.id_to_bb[succ_id].borrow().ends_with_br())
-                        {
-                            eprintln!("I see a loop from block {} to block {}", succ_id, dominated);
-                            loop_start_end
-                                .entry(*succ_id)
-                                .or_default()
-                                .insert(*dominated);
-                        }
-                    }
+/// The natural loops of the CFG by header, found without creating preheaders so that they can be
+/// cached as long as the edges stay the same
+#[derive(Debug, Clone, Default)]
+pub struct NaturalLoops {
+    pub latches: BTreeMap<BlockID, BTreeSet<BlockID>>,
+    /// Every block of the loop, header and latches included. Loops sharing a header are merged
+    pub bodies: BTreeMap<BlockID, BTreeSet<BlockID>>,
+}
+
+impl NaturalLoops {
+    pub fn new(cfg: &CFG, dominance: &DominanceDataFlow) -> NaturalLoops {
+        let latches = Loops::back_edges(cfg, dominance);
+        let mut bodies = BTreeMap::<BlockID, BTreeSet<BlockID>>::new();
+        for (header, header_latches) in latches.iter() {
+            // Walk up from the latches, the header stops the walk
+            let body = bodies.entry(*header).or_default();
+            body.insert(*header);
+            let mut work_list = header_latches.iter().copied().collect::<Vec<BlockID>>();
+            while let Some(block) = work_list.pop() {
+                if body.insert(block) {
+                    work_list.extend(cfg.blocks[block].predecessors.iter());
                 }
-                _ => panic!("This should not happen. All block id should be accounted for"),
             }
         }
+        Self { latches, bodies }
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &BlockID> {
+        self.latches.keys()
+    }
+
+    /// Whether `block` is in the loop of `header`
+    pub fn contains(&self, header: BlockID, block: BlockID) -> bool {
+        self.bodies
+            .get(&header)
+            .is_some_and(|body| body.contains(&block))
+    }
+}
 
-        let mut created_header = BTreeSet::<BlockID>::new();
+impl Loops {
+    pub fn new(cfg: &mut CFG) -> Loops {
+        let dominance = DominanceDataFlow::new(cfg);
+        Self::with_dominance(cfg, &dominance)
+    }
+
+    /// Same as `new` with the dominance already computed. The preheaders this creates make it
+    /// stale
+    pub fn with_dominance(cfg: &mut CFG, dominance: &DominanceDataFlow) -> Loops {
+        let mut loop_start_end = Self::back_edges(cfg, dominance);
+        loop_start_end.retain(|header_id, _| cfg.blocks[*header_id].ends_with_br());
 
         let mut loops = Vec::<Loop>::new();
 
@@ -393,15 +358,16 @@ impl Loops {
         //
         // then add them together
         for (header_id, latches) in loop_start_end {
+            let preheader = match Self::existing_preheader(cfg, dominance, header_id) {
+                Some(preheader) => preheader,
+                None => Loop::create_preheader(cfg, &header_id),
+            };
             for latch_id in latches {
-                let precreate: PreHeaderCreate = match created_header.contains(&header_id) {
-                    false => PreHeaderCreate::Create,
-                    true => PreHeaderCreate::DontCreate,
-                };
-                created_header.insert(header_id);
-
                 loops.push(Loop::new_with_header_and_latch(
-                    cfg, &header_id, &latch_id, precreate,
+                    cfg,
+                    &header_id,
+                    &latch_id,
+                    PreHeaderCreate::Existing(preheader),
                 ));
             }
         }
@@ -417,4 +383,46 @@ impl Loops {
         //
         Self { loops }
     }
+
+    /// The only predecessor of `header` from outside of its loop, if it only goes to `header`
+    fn existing_preheader(
+        cfg: &CFG,
+        dominance: &DominanceDataFlow,
+        header: BlockID,
+    ) -> Option<BlockID> {
+        let mut outside = cfg.blocks[header]
+            .predecessors
+            .iter()
+            .filter(|pred| !dominance.dom(header, **pred));
+        match (outside.next(), outside.next()) {
+            (Some(pred), None) if cfg.blocks[*pred].successors == [header] => Some(*pred),
+            _ => None,
+        }
+    }
+
+    /// Every back edge of the CFG, grouped as a map from the header to its latches. A back edge is
+    /// an edge whose target dominates its source. Unlike `Loops::new`, this doesn't touch the CFG
+    pub fn back_edges(
+        cfg: &CFG,
+        dominance: &DominanceDataFlow,
+    ) -> BTreeMap<BlockID, BTreeSet<BlockID>> {
+        let mut loop_start_end = BTreeMap::<BlockID, BTreeSet<BlockID>>::new();
+        for (dominated, dominator_set) in &dominance.domset {
+            match cfg.blocks.get(*dominated) {
+                Some(bb) => {
+                    for succ_id in bb.successors.iter() {
+                        if dominator_set.contains(succ_id) {
+                            eprintln!("I see a loop from block {} to block {}", succ_id, dominated);
+                            loop_start_end
+                                .entry(*succ_id)
+                                .or_default()
+                                .insert(*dominated);
+                        }
+                    }
+                }
+                _ => panic!("This should not happen. All block id should be accounted for"),
+            }
+        }
+        loop_start_end
+    }
 }
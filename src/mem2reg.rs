@@ -0,0 +1,276 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    alias_analysis::AliasAnalysis,
+    aliases::BlockID,
+    analysis_manager::AnalysisManager,
+    basic_block::BasicBlock,
+    bril_syntax::{BrilType, Instruction, InstructionOrLabel},
+    cfg::CFG,
+};
+
+/// Allocations bigger than this are left in memory, even if every access uses a constant index
+pub const MAX_PROMOTED_CELLS: i64 = 8;
+
+/// An `alloc` whose size is a known constant, found while scanning a function
+#[derive(Debug, Clone)]
+pub struct PromotableAlloc {
+    pub ptr: String,
+    pub pointee: BrilType,
+    pub size: i64,
+}
+
+impl PromotableAlloc {
+    /// The name of the scalar variable that replaces the memory cell at `index`
+    pub fn cell_name(&self, index: i64) -> String {
+        format!("{}_cell{}", self.ptr, index)
+    }
+}
+
+/// INFO: Per function state of mem2reg.
+/// A site is identified by the instruction id of its `alloc`, a cell is a pair of a site and a
+/// constant offset into it
+#[derive(Default)]
+struct Mem2Reg {
+    sites: BTreeMap<usize, PromotableAlloc>,
+    cells: BTreeMap<String, (usize, i64)>,
+    escaped: BTreeSet<usize>,
+}
+
+impl Mem2Reg {
+    fn new(cfg: &CFG, blocks: &[BlockID], alias: &AliasAnalysis) -> Self {
+        let mut result = Self::default();
+
+        // Pointers and offsets need to be defined once so that every use of them refers to the
+        // same cell, no matter which path we took to reach it
+        let mut def_count = BTreeMap::<String, usize>::new();
+        let mut const_ints = BTreeMap::<String, i64>::new();
+        if let Some(args) = cfg.blocks[blocks[0]]
+            .func
+            .as_ref()
+            .and_then(|f| f.args.clone())
+        {
+            for arg in args {
+                *def_count.entry(arg.name).or_default() += 1;
+            }
+        }
+        for i in Self::instructions(cfg, blocks) {
+            if let Some(dest) = &i.dest {
+                *def_count.entry(dest.clone()).or_default() += 1;
+                if i.is_const() && i.bril_type == Some(BrilType::Int) {
+                    if let Some(c) = i.value.as_ref().and_then(|v| v.as_i64()) {
+                        const_ints.insert(dest.clone(), c);
+                    }
+                }
+            }
+        }
+        let single_def = |var: &String| def_count.get(var) == Some(&1);
+        let const_int = |var: &String| match single_def(var) {
+            true => const_ints.get(var).cloned(),
+            false => None,
+        };
+
+        for i in Self::instructions(cfg, blocks) {
+            if !i.is_alloc() {
+                continue;
+            }
+            let (Some(dest), Some(BrilType::Ptr(pointee))) = (&i.dest, &i.bril_type) else {
+                continue;
+            };
+            let size = i.args.as_ref().and_then(|args| const_int(&args[0]));
+            match size {
+                Some(size) if single_def(dest) && (1..=MAX_PROMOTED_CELLS).contains(&size) => {
+                    let site = i.instruction_id.unwrap();
+                    result.sites.insert(
+                        site,
+                        PromotableAlloc {
+                            ptr: dest.clone(),
+                            pointee: *pointee.clone(),
+                            size,
+                        },
+                    );
+                    result.cells.insert(dest.clone(), (site, 0));
+                }
+                _ => continue,
+            }
+        }
+
+        // Follow the `id` and `ptradd` by a constant chains until nothing new shows up
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in Self::instructions(cfg, blocks) {
+                if !(i.is_id() || i.is_ptradd()) {
+                    continue;
+                }
+                let (Some(dest), Some(args)) = (&i.dest, &i.args) else {
+                    continue;
+                };
+                if result.cells.contains_key(dest) || !single_def(dest) {
+                    continue;
+                }
+                let Some(&(site, index)) = result.cells.get(&args[0]) else {
+                    continue;
+                };
+                let offset = match i.is_ptradd() {
+                    true => const_int(&args[1]),
+                    false => Some(0),
+                };
+                match offset {
+                    Some(offset) if (0..result.sites[&site].size).contains(&(index + offset)) => {
+                        result.cells.insert(dest.clone(), (site, index + offset));
+                        changed = true;
+                    }
+                    _ => {
+                        result.escaped.insert(site);
+                    }
+                }
+            }
+        }
+
+        // A site escapes as soon as one of its pointers is used for something other than
+        // addressing one of its own cells
+        for i in Self::instructions(cfg, blocks) {
+            let Some(args) = &i.args else {
+                continue;
+            };
+            for (position, arg) in args.iter().enumerate() {
+                let Some(&(site, index)) = result.cells.get(arg) else {
+                    continue;
+                };
+                let allowed = match position {
+                    0 if i.is_load() || i.is_store() => true,
+                    0 if i.is_free() => index == 0,
+                    0 if i.is_id() || i.is_ptradd() => i
+                        .dest
+                        .as_ref()
+                        .is_some_and(|dest| result.cells.contains_key(dest)),
+                    _ => false,
+                };
+                if !allowed {
+                    result.escaped.insert(site);
+                }
+            }
+
+            // Any other pointer that may reach one of our sites means we can't see all the
+            // accesses to it
+            if i.is_load() || i.is_store() || i.is_free() {
+                if result.cells.contains_key(&args[0]) {
+                    continue;
+                }
+                for site in result.sites.keys() {
+                    if alias.may_point_to(&args[0], *site) {
+                        result.escaped.insert(*site);
+                    }
+                }
+            }
+        }
+
+        for site in result.escaped.iter() {
+            eprintln!("Allocation {} escapes", result.sites[site].ptr);
+        }
+        result
+            .cells
+            .retain(|_, (site, _)| !result.escaped.contains(site));
+        result
+            .sites
+            .retain(|site, _| !result.escaped.contains(site));
+        result
+    }
+
+    fn instructions(cfg: &CFG, blocks: &[BlockID]) -> Vec<Instruction> {
+        let mut result = Vec::new();
+        for bb in blocks {
+            for ilb in cfg.blocks[*bb].instrs.iter() {
+                if let InstructionOrLabel::Instruction(i) = ilb {
+                    result.push(i.clone());
+                }
+            }
+        }
+        result
+    }
+
+    fn cell_of(&self, ptr: &String) -> Option<(String, &BrilType)> {
+        self.cells.get(ptr).map(|(site, index)| {
+            let alloc = &self.sites[site];
+            (alloc.cell_name(*index), &alloc.pointee)
+        })
+    }
+
+    fn rewrite(&self, bb: &mut BasicBlock, instruction_counter: &mut usize) {
+        let mut kept_instruction = Vec::<InstructionOrLabel>::new();
+        for ilb in bb.instrs.iter() {
+            let InstructionOrLabel::Instruction(i) = ilb else {
+                kept_instruction.push(ilb.clone());
+                continue;
+            };
+            let ptr = i.args.as_ref().and_then(|args| args.first());
+            let cell = ptr.and_then(|ptr| self.cell_of(ptr));
+
+            if i.is_alloc() || i.is_id() || i.is_ptradd() {
+                // The pointer itself is gone, the cells are plain variables now
+                if i.dest.as_ref().is_some_and(|d| self.cells.contains_key(d)) {
+                    continue;
+                }
+                kept_instruction.push(ilb.clone());
+                continue;
+            }
+            match cell {
+                Some((cell, pointee)) if i.is_store() => {
+                    let value = &i.args.as_ref().unwrap()[1];
+                    kept_instruction.push(Instruction::new_id_instruction(
+                        &cell,
+                        value,
+                        pointee,
+                        instruction_counter,
+                    ));
+                }
+                Some((cell, _)) if i.is_load() => {
+                    let mut load = i.clone();
+                    load.op = "id".to_string();
+                    load.args = Some(vec![cell]);
+                    kept_instruction.push(InstructionOrLabel::Instruction(load));
+                }
+                Some(_) if i.is_free() => continue,
+                _ => kept_instruction.push(ilb.clone()),
+            }
+        }
+        bb.instrs = kept_instruction;
+    }
+}
+
+/// INFO: This impl block is denoted to be about promoting memory to registers
+impl CFG {
+    /// Replace every non-escaping `alloc` of a small constant size, whose cells are only ever
+    /// addressed with constant offsets, by one variable per cell. Loads and stores become `id`s
+    /// and the matching `free` is removed.
+    ///
+    /// The cells are not in SSA form afterward, call `place_phi_functions_and_generate_ssa` to
+    /// get the phis. Returns the promoted allocations.
+    pub fn promote_memory_to_registers(&mut self) -> Vec<PromotableAlloc> {
+        self.promote_memory_to_registers_with(&mut AnalysisManager::new())
+    }
+
+    /// Same as `promote_memory_to_registers`, reusing the points-to sets cached in `analyses`
+    pub fn promote_memory_to_registers_with(
+        &mut self,
+        analyses: &mut AnalysisManager,
+    ) -> Vec<PromotableAlloc> {
+        let mut result = Vec::new();
+        for blocks in self.function_blocks() {
+            let alias = analyses.alias(self, &blocks);
+            let mem2reg = Mem2Reg::new(self, &blocks, &alias);
+            if mem2reg.sites.is_empty() {
+                continue;
+            }
+            for bb in blocks.iter() {
+                mem2reg.rewrite(&mut self.blocks[*bb], &mut self.instruction_counter);
+            }
+            for alloc in mem2reg.sites.values() {
+                eprintln!("Promoted {} with {} cells", alloc.ptr, alloc.size);
+                result.push(alloc.clone());
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use crate::{
+    aliases::BlockID,
+    basic_block::BasicBlock,
+    bril_syntax::{Instruction, InstructionOrLabel, Position},
+    cfg::CFG,
+    data_flow::{DataFlowAnalysis, DataFlowDirection, DataFlowOrder, TransferResult},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SiteState {
+    Live,
+    Freed,
+}
+
+/// Whether a pointer is known to point at the start of its allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Offset {
+    Base,
+    Shifted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryErrorKind {
+    Leak,
+    DoubleFree,
+    UseAfterFree,
+    FreeOfOffsetPointer,
+}
+
+impl Display for MemoryErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryErrorKind::Leak => write!(f, "memory leak"),
+            MemoryErrorKind::DoubleFree => write!(f, "double free"),
+            MemoryErrorKind::UseAfterFree => write!(f, "use after free"),
+            MemoryErrorKind::FreeOfOffsetPointer => write!(f, "free of ptradd-offset pointer"),
+        }
+    }
+}
+
+/// A potential memory error. For leaks the offending instruction is the `alloc` that may never be
+/// freed, otherwise it is the `load`/`store`/`free` itself
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MemoryError {
+    pub kind: MemoryErrorKind,
+    pub position: Option<Position>,
+    pub instruction_id: usize,
+    pub var: String,
+}
+
+impl Display for MemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some(pos) => write!(f, "{}: ", pos)?,
+            None => write!(f, "instruction {}: ", self.instruction_id)?,
+        }
+        match self.kind {
+            MemoryErrorKind::Leak => write!(f, "{}, `{}` may not be freed", self.kind, self.var),
+            _ => write!(f, "{} through `{}`", self.kind, self.var),
+        }
+    }
+}
+
+/// INFO: Facts flowing along the CFG, everything is a may-set.
+/// A site is the instruction id of an `alloc`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct State {
+    points_to: BTreeMap<String, BTreeSet<(usize, Offset)>>,
+    sites: BTreeMap<usize, BTreeSet<SiteState>>,
+    // Sites whose address left the function (call, store, ret), someone else may free them
+    escaped: BTreeSet<usize>,
+    // Variables holding the constant 0 on every path, a `ptradd` by them keeps the base
+    zeros: BTreeSet<String>,
+}
+
+impl State {
+    fn merge(&mut self, other: &State) {
+        for (var, targets) in other.points_to.iter() {
+            self.points_to
+                .entry(var.clone())
+                .or_default()
+                .extend(targets.iter());
+        }
+        for (site, states) in other.sites.iter() {
+            self.sites.entry(*site).or_default().extend(states.iter());
+        }
+        self.escaped.extend(other.escaped.iter());
+        self.zeros = self.zeros.intersection(&other.zeros).cloned().collect();
+    }
+
+    fn targets(&self, var: &String) -> BTreeSet<(usize, Offset)> {
+        self.points_to.get(var).cloned().unwrap_or_default()
+    }
+
+    fn escape(&mut self, var: &String) {
+        for (site, _) in self.targets(var) {
+            self.escaped.insert(site);
+        }
+    }
+}
+
+pub struct MemorySafety {
+    in_states: BTreeMap<BlockID, State>,
+    // A missing out state is bottom, the block has not been reached yet (or always returns)
+    out_states: BTreeMap<BlockID, Option<State>>,
+    // Where each site was allocated, to report leaks on the `alloc`
+    allocations: BTreeMap<usize, (String, Option<Position>)>,
+    pub errors: BTreeSet<MemoryError>,
+}
+
+impl MemorySafety {
+    pub fn new(cfg: &CFG) -> MemorySafety {
+        let mut allocations = BTreeMap::new();
+        for bb in cfg.ordered_blocks() {
+            for instr in bb.instrs.iter() {
+                if let InstructionOrLabel::Instruction(i) = instr {
+                    if i.is_alloc() {
+                        allocations.insert(
+                            i.instruction_id.unwrap(),
+                            (i.dest.clone().unwrap(), i.position()),
+                        );
+                    }
+                }
+            }
+        }
+        Self {
+            in_states: BTreeMap::default(),
+            out_states: BTreeMap::default(),
+            allocations,
+            errors: BTreeSet::default(),
+        }
+    }
+
+    /// Run the analysis over the whole program and return every potential error, sorted by kind
+    pub fn check(cfg: &CFG) -> BTreeSet<MemoryError> {
+        let mut result = Self::new(cfg);
+        cfg.solve(&mut result);
+        for bb in cfg.ordered_blocks() {
+            result.collect_errors(bb);
+        }
+        result.errors
+    }
+
+    fn collect_errors(&mut self, bb: &BasicBlock) {
+        let mut errors = BTreeSet::new();
+        self.walk(bb, &mut errors);
+        self.errors.extend(errors);
+    }
+
+    fn report(errors: &mut BTreeSet<MemoryError>, kind: MemoryErrorKind, i: &Instruction) {
+        errors.insert(MemoryError {
+            kind,
+            position: i.position(),
+            instruction_id: i.instruction_id.unwrap(),
+            var: i.args.as_ref().unwrap()[0].clone(),
+        });
+    }
+
+    /// Whether the site may still be live and nobody else may free it
+    fn may_leak(state: &State, site: usize) -> bool {
+        state
+            .sites
+            .get(&site)
+            .is_some_and(|s| s.contains(&SiteState::Live))
+            && !state.escaped.contains(&site)
+    }
+
+    fn report_leak(&self, site: usize, errors: &mut BTreeSet<MemoryError>) {
+        let (var, position) = self.allocations[&site].clone();
+        errors.insert(MemoryError {
+            kind: MemoryErrorKind::Leak,
+            position,
+            instruction_id: site,
+            var,
+        });
+    }
+
+    fn report_leaks(&self, state: &State, errors: &mut BTreeSet<MemoryError>) {
+        for site in state.sites.keys() {
+            if Self::may_leak(state, *site) {
+                self.report_leak(*site, errors);
+            }
+        }
+    }
+
+    /// Apply one instruction to the state. Returns false once the function returned, nothing after
+    /// a `ret` is reachable from this path
+    fn step(&self, state: &mut State, i: &Instruction, errors: &mut BTreeSet<MemoryError>) -> bool {
+        let args = i.args.clone().unwrap_or_default();
+
+        if i.is_load() || i.is_store() {
+            for (site, _) in state.targets(&args[0]) {
+                if state
+                    .sites
+                    .get(&site)
+                    .is_some_and(|s| s.contains(&SiteState::Freed))
+                {
+                    Self::report(errors, MemoryErrorKind::UseAfterFree, i);
+                }
+            }
+            if i.is_store() {
+                state.escape(&args[1]);
+            }
+        } else if i.is_free() {
+            let targets = state.targets(&args[0]);
+            for (site, offset) in targets.iter() {
+                if *offset == Offset::Shifted {
+                    Self::report(errors, MemoryErrorKind::FreeOfOffsetPointer, i);
+                }
+                if state
+                    .sites
+                    .get(site)
+                    .is_some_and(|s| s.contains(&SiteState::Freed))
+                {
+                    Self::report(errors, MemoryErrorKind::DoubleFree, i);
+                }
+            }
+            // Only a pointer to a single site tells us for sure which allocation is gone
+            let strong_update = targets
+                .iter()
+                .map(|(site, _)| site)
+                .collect::<BTreeSet<_>>();
+            for (site, _) in targets.iter() {
+                let states = state.sites.entry(*site).or_default();
+                if strong_update.len() == 1 {
+                    states.clear();
+                }
+                states.insert(SiteState::Freed);
+            }
+        } else if i.is_call() || i.is_ret() {
+            for arg in args.iter() {
+                state.escape(arg);
+            }
+        }
+
+        if i.is_ret() {
+            self.report_leaks(state, errors);
+            return false;
+        }
+
+        if let Some(dest) = &i.dest {
+            state.zeros.remove(dest);
+            if i.is_alloc() {
+                let site = i.instruction_id.unwrap();
+                // Allocating the site again, in a loop, drops the previous allocation
+                if Self::may_leak(state, site) {
+                    self.report_leak(site, errors);
+                }
+                state
+                    .points_to
+                    .insert(dest.clone(), BTreeSet::from([(site, Offset::Base)]));
+                state.sites.insert(site, BTreeSet::from([SiteState::Live]));
+                state.escaped.remove(&site);
+            } else if i.is_id() {
+                let targets = state.targets(&args[0]);
+                state.points_to.insert(dest.clone(), targets);
+            } else if i.is_ptradd() {
+                let shifted = !state.zeros.contains(&args[1]);
+                let targets = state
+                    .targets(&args[0])
+                    .into_iter()
+                    .map(|(site, offset)| match shifted {
+                        true => (site, Offset::Shifted),
+                        false => (site, offset),
+                    })
+                    .collect();
+                state.points_to.insert(dest.clone(), targets);
+            } else if i.is_phi() {
+                let mut targets = BTreeSet::new();
+                for arg in args.iter() {
+                    targets.extend(state.targets(arg));
+                }
+                state.points_to.insert(dest.clone(), targets);
+            } else {
+                // Pointers coming out of memory or calls are not tracked
+                state.points_to.remove(dest);
+                if i.is_const() && i.value.as_ref().and_then(|v| v.as_i64()) == Some(0) {
+                    state.zeros.insert(dest.clone());
+                }
+            }
+        }
+        true
+    }
+
+    /// Walk the block from its in state, returns the out state (None if every path returned)
+    fn walk(&self, bb: &BasicBlock, errors: &mut BTreeSet<MemoryError>) -> Option<State> {
+        let mut state = self.in_states.get(&bb.id).cloned().unwrap_or_default();
+        for ilb in bb.instrs.iter() {
+            if let InstructionOrLabel::Instruction(i) = ilb {
+                if !self.step(&mut state, i, errors) {
+                    return None;
+                }
+            }
+        }
+        // Falling off the end of the function is an implicit `ret`
+        if bb.successors.is_empty() {
+            self.report_leaks(&state, errors);
+        }
+        Some(state)
+    }
+}
+
+impl DataFlowAnalysis for MemorySafety {
+    fn meet(&mut self, bb: &BasicBlock) {
+        let mut result: Option<State> = None;
+        for pred in bb.predecessors.iter() {
+            if let Some(Some(pred_state)) = self.out_states.get(pred) {
+                match &mut result {
+                    Some(state) => state.merge(pred_state),
+                    None => result = Some(pred_state.clone()),
+                }
+            }
+        }
+        self.in_states.insert(bb.id, result.unwrap_or_default());
+    }
+
+    fn transfer(&mut self, bb: &BasicBlock) -> TransferResult {
+        let out_state = self.walk(bb, &mut BTreeSet::new());
+        match self.out_states.insert(bb.id, out_state.clone()) {
+            Some(initial) if initial == out_state => TransferResult::NonChanged,
+            _ => TransferResult::Changed,
+        }
+    }
+
+    /// Nothing is rewritten, the errors are collected once the facts are stable
+    fn transform(&mut self, bb: &mut BasicBlock) {
+        self.collect_errors(bb);
+    }
+
+    fn get_dataflow_direction(&self) -> DataFlowDirection {
+        DataFlowDirection::Forward
+    }
+
+    fn get_dataflow_order(&self) -> DataFlowOrder {
+        DataFlowOrder::BFS
+    }
+}
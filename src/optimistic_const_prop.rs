@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+
+use crate::basic_block::BasicBlock;
+use crate::bril_syntax::{BrilType, Instruction, InstructionOrLabel};
+use crate::data_flow::{ConditionalDataFlowAnalysis, ConditionalTransferResult};
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Copy)]
+pub enum LatticeValue {
+    Dominator,
+    Dominated,
+    ConstantInt(i64),
+    ConstantBool(bool),
+    /// Bit pattern of the float, so that the lattice keeps `Eq` and `Hash` and NaN equals itself
+    ConstantFloat(u64),
+    ConstantChar(char),
+}
+
+impl LatticeValue {
+    pub fn float(f: f64) -> Self {
+        Self::ConstantFloat(f.to_bits())
+    }
+
+    /// The literal of a const instruction holding this value. NaN and infinities have no literal
+    pub fn literal(&self) -> Option<serde_json::Value> {
+        match self {
+            LatticeValue::ConstantInt(c) => Some(serde_json::json!(c)),
+            LatticeValue::ConstantBool(c) => Some(serde_json::json!(c)),
+            LatticeValue::ConstantFloat(c) => {
+                let f = f64::from_bits(*c);
+                f.is_finite().then(|| serde_json::json!(f))
+            }
+            LatticeValue::ConstantChar(c) => Some(serde_json::json!(c.to_string())),
+            LatticeValue::Dominator | LatticeValue::Dominated => None,
+        }
+    }
+
+    /// The value of a const instruction, None for types without constants in the lattice
+    pub fn from_const(instr: &Instruction) -> Option<Self> {
+        let value = instr.value.as_ref()?;
+        match instr.bril_type.as_ref()? {
+            BrilType::Int => Some(LatticeValue::ConstantInt(value.as_i64()?)),
+            BrilType::Bool => Some(LatticeValue::ConstantBool(value.as_bool()?)),
+            BrilType::Float => Some(LatticeValue::float(value.as_f64()?)),
+            BrilType::Char => Some(LatticeValue::ConstantChar(value.as_str()?.chars().next()?)),
+            BrilType::Ptr(_) => None,
+        }
+    }
+}
+
+pub struct OptimisticConstProp {
+    /// The facts at the end of each block
+    pub facts: HashMap<usize, HashMap<String, LatticeValue>>,
+    // The facts at the top of each block, from the last meet
+    in_facts: HashMap<usize, HashMap<String, LatticeValue>>,
+}
+impl Default for OptimisticConstProp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OptimisticConstProp {
+    pub fn new() -> Self {
+        Self {
+            facts: HashMap::default(),
+            in_facts: HashMap::default(),
+        }
+    }
+    /// Combine lattice value based on the lattice value type
+    /// This is called in a meet function on each instruction
+    pub fn lattice_value_meet(
+        &self,
+        q: Option<&LatticeValue>,
+        p: Option<&LatticeValue>,
+    ) -> LatticeValue {
+        //eprintln!("{:?} : {:?} : {:?}", meet_value, q, p);
+        match (q, p) {
+            (Some(a), Some(b)) => match (a, b) {
+                (LatticeValue::Dominator, _) | (_, LatticeValue::Dominator) => {
+                    LatticeValue::Dominator
+                }
+                (LatticeValue::Dominated, c) | (c, LatticeValue::Dominated) => *c,
+                (c, d) if c == d => *c,
+                _ => LatticeValue::Dominator,
+            },
+            (Some(c), None) | (None, Some(c)) => *c,
+            (None, None) => LatticeValue::Dominated,
+        }
+    }
+
+    /// Combine lattice value based on the instruction type and the facts we have had
+    /// This is called in a transfer function on each instruction
+    pub fn lattice_value_transfer(
+        &self,
+        instr: &Instruction,
+        facts: &HashMap<String, LatticeValue>,
+    ) -> Option<(String, LatticeValue)> {
+        let a = if instr.is_const() {
+            Some((instr.clone().dest?, LatticeValue::from_const(instr)?))
+        } else if instr.is_id() {
+            match facts.get(&instr.args.clone().unwrap()[0]) {
+                Some(LatticeValue::Dominator | LatticeValue::Dominated) | None => None,
+                Some(c) => Some((instr.clone().dest?, *c)),
+            }
+        } else if instr.is_add() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => Some((
+                    instr.clone().dest?,
+                    LatticeValue::ConstantInt(c.wrapping_add(*d)),
+                )),
+                _ => None,
+            }
+        } else if instr.is_sub() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => Some((
+                    instr.clone().dest?,
+                    LatticeValue::ConstantInt(c.wrapping_sub(*d)),
+                )),
+                _ => None,
+            }
+        } else if instr.is_mul() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => Some((
+                    instr.clone().dest?,
+                    LatticeValue::ConstantInt(c.wrapping_mul(*d)),
+                )),
+                _ => None,
+            }
+        } else if instr.is_div() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => Some((
+                    instr.clone().dest?,
+                    LatticeValue::ConstantInt(c.checked_div(*d)?),
+                )),
+                _ => None,
+            }
+        } else if instr.is_le() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => {
+                    Some((instr.clone().dest?, LatticeValue::ConstantBool(c <= d)))
+                }
+                _ => None,
+            }
+        } else if instr.is_ge() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => {
+                    Some((instr.clone().dest?, LatticeValue::ConstantBool(c >= d)))
+                }
+                _ => None,
+            }
+        } else if instr.is_lt() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => {
+                    Some((instr.clone().dest?, LatticeValue::ConstantBool(c < d)))
+                }
+                _ => None,
+            }
+        } else if instr.is_gt() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => {
+                    Some((instr.clone().dest?, LatticeValue::ConstantBool(c > d)))
+                }
+                _ => None,
+            }
+        } else if instr.is_eq() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantInt(c)), Some(LatticeValue::ConstantInt(d))) => {
+                    Some((instr.clone().dest?, LatticeValue::ConstantBool(c == d)))
+                }
+                _ => None,
+            }
+        } else if instr.is_float_operation() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantFloat(c)), Some(LatticeValue::ConstantFloat(d))) => {
+                    let (c, d) = (f64::from_bits(*c), f64::from_bits(*d));
+                    Some((instr.clone().dest?, Self::fold_float(instr, c, d)?))
+                }
+                _ => None,
+            }
+        } else if instr.is_char_comparison() {
+            let args = instr.args.clone().unwrap();
+            let a = facts.get(&args[0]);
+            let b = facts.get(&args[1]);
+
+            match (a, b) {
+                (Some(LatticeValue::ConstantChar(c)), Some(LatticeValue::ConstantChar(d))) => {
+                    Some((instr.clone().dest?, Self::fold_char(instr, *c, *d)?))
+                }
+                _ => None,
+            }
+        } else if instr.is_char2int() {
+            match facts.get(&instr.args.clone().unwrap()[0]) {
+                Some(LatticeValue::ConstantChar(c)) => {
+                    Some((instr.clone().dest?, LatticeValue::ConstantInt(*c as i64)))
+                }
+                _ => None,
+            }
+        } else if instr.is_int2char() {
+            // Out of range code points are a runtime error, they are left to the interpreter
+            match facts.get(&instr.args.clone().unwrap()[0]) {
+                Some(LatticeValue::ConstantInt(c)) => {
+                    let c = char::from_u32(u32::try_from(*c).ok()?)?;
+                    Some((instr.clone().dest?, LatticeValue::ConstantChar(c)))
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        // A definition replaces whatever the destination held before
+        a
+    }
+
+    /// The facts right before the instruction at `position` in `bb`, None if `bb` was never reached
+    pub fn facts_before(
+        &self,
+        bb: &BasicBlock,
+        position: usize,
+    ) -> Option<HashMap<String, LatticeValue>> {
+        let mut facts = self.in_facts.get(&bb.id)?.clone();
+        for instr_label in bb.instrs[..position].iter() {
+            if let InstructionOrLabel::Instruction(instr) = instr_label {
+                self.step(instr, &mut facts);
+            }
+        }
+        Some(facts)
+    }
+
+    /// Meet the facts at the end of the predecessors. A predecessor that was not reached yet
+    /// brings no facts
+    fn predecessor_facts(&self, bb: &BasicBlock) -> HashMap<String, LatticeValue> {
+        let mut hs = HashMap::<String, LatticeValue>::new();
+        for pred in bb.predecessors.iter() {
+            let Some(pred_facts) = self.facts.get(pred) else {
+                continue;
+            };
+            for (var, value) in pred_facts.iter() {
+                let res = self.lattice_value_meet(hs.get(var), Some(value));
+                hs.insert(var.clone(), res);
+            }
+        }
+        hs
+    }
+
+    /// Update the facts with one instruction, a destination that can't be folded is unknown
+    fn step(&self, instr: &Instruction, facts: &mut HashMap<String, LatticeValue>) {
+        match self.lattice_value_transfer(instr, facts) {
+            Some((dest, value)) => {
+                facts.insert(dest, value);
+            }
+            None => {
+                if let Some(dest) = &instr.dest {
+                    facts.insert(dest.clone(), LatticeValue::Dominator);
+                }
+            }
+        }
+    }
+}
+impl OptimisticConstProp {
+    /// Float arithmetic and comparisons with IEEE semantics, as brilirs evaluates them
+    fn fold_float(instr: &Instruction, c: f64, d: f64) -> Option<LatticeValue> {
+        let result = if instr.is_fadd() {
+            LatticeValue::float(c + d)
+        } else if instr.is_fsub() {
+            LatticeValue::float(c - d)
+        } else if instr.is_fmul() {
+            LatticeValue::float(c * d)
+        } else if instr.is_fdiv() {
+            LatticeValue::float(c / d)
+        } else if instr.is_feq() {
+            LatticeValue::ConstantBool(c == d)
+        } else if instr.is_flt() {
+            LatticeValue::ConstantBool(c < d)
+        } else if instr.is_fgt() {
+            LatticeValue::ConstantBool(c > d)
+        } else if instr.is_fle() {
+            LatticeValue::ConstantBool(c <= d)
+        } else if instr.is_fge() {
+            LatticeValue::ConstantBool(c >= d)
+        } else {
+            return None;
+        };
+        Some(result)
+    }
+
+    fn fold_char(instr: &Instruction, c: char, d: char) -> Option<LatticeValue> {
+        let result = if instr.is_ceq() {
+            c == d
+        } else if instr.is_clt() {
+            c < d
+        } else if instr.is_cgt() {
+            c > d
+        } else if instr.is_cle() {
+            c <= d
+        } else if instr.is_cge() {
+            c >= d
+        } else {
+            return None;
+        };
+        Some(LatticeValue::ConstantBool(result))
+    }
+}
+
+impl ConditionalDataFlowAnalysis for OptimisticConstProp {
+    /// Meet all the successor block based on the instruction's dest and LatticeValue
+    fn meet(&mut self, bb: &BasicBlock) {
+        let hs = self.predecessor_facts(bb);
+        self.in_facts.insert(bb.id, hs);
+    }
+
+    /// Transfer the facts in the block forwards
+    fn transfer(&mut self, bb: &BasicBlock) -> ConditionalTransferResult {
+        let mut facts = self.in_facts[&bb.id].clone();
+        for instr_label in bb.instrs.iter() {
+            if let InstructionOrLabel::Instruction(instr) = instr_label {
+                self.step(instr, &mut facts);
+            }
+        }
+        // The first visit of a block always reaches its successors
+        let unchanged = self.facts.insert(bb.id, facts.clone()).as_ref() == Some(&facts);
+
+        let result = match bb.instrs.iter().last() {
+            Some(instr_lb) => match instr_lb {
+                InstructionOrLabel::Label(_) => match unchanged {
+                    true => ConditionalTransferResult::NoPathTaken,
+                    false => ConditionalTransferResult::AllPathTaken,
+                },
+
+                InstructionOrLabel::Instruction(instruction) => {
+                    if instruction.is_br() {
+                        match self.facts[&bb.id].get(&instruction.args.clone().unwrap().clone()[0])
+                        {
+                            Some(LatticeValue::ConstantBool(true)) => {
+                                ConditionalTransferResult::FirstPathTaken
+                            }
+                            Some(LatticeValue::ConstantBool(false)) => {
+                                ConditionalTransferResult::SecondPathTaken
+                            }
+                            _ => match unchanged {
+                                true => ConditionalTransferResult::NoPathTaken,
+                                false => ConditionalTransferResult::AllPathTaken,
+                            },
+                        }
+                    } else {
+                        match unchanged {
+                            true => ConditionalTransferResult::NoPathTaken,
+                            false => ConditionalTransferResult::AllPathTaken,
+                        }
+                    }
+                }
+            },
+            None => panic!("Logically cannot happen"),
+        };
+
+        //eprintln!("Result {:?}", result);
+        result
+    }
+
+    /// Transform a basic block based on the fact it has acquired, this is only after fix-point.
+    /// The facts are walked again from the top of the block, so that every instruction is
+    /// rewritten with the value it defines and not the one the name holds at the end
+    fn transform(&mut self, bb: &mut BasicBlock) {
+        let Some(mut facts) = self.in_facts.get(&bb.id).cloned() else {
+            return;
+        };
+        for instr_label in bb.instrs.iter_mut() {
+            if let InstructionOrLabel::Instruction(instr) = instr_label {
+                self.step(instr, &mut facts);
+                if instr.is_nonlinear() {
+                    continue;
+                }
+                let Some(dest) = &instr.dest else {
+                    continue;
+                };
+                if let Some(literal) = facts.get(dest).and_then(LatticeValue::literal) {
+                    instr.value = Some(literal);
+                    instr.args = None;
+                    instr.op = "const".to_string();
+                    instr.funcs = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use crate::{
+    alias_analysis::AliasAnalysis,
+    analysis_manager::{AnalysisManager, PreservedAnalyses},
+    bril_syntax::InstructionOrLabel,
+    cfg::{SsaMode, CFG},
+    optimistic_const_prop::OptimisticConstProp,
+    pessimistic_const_prop::PessimisticConstProp,
+    register_allocation::DEFAULT_REGISTERS,
+};
+
+/// How a pass changes the SSA form of the program, used to pick the invariants to verify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SsaEffect {
+    /// SSA form stays as it was, in or out
+    Preserves,
+    /// The program is in SSA form afterward
+    Constructs,
+    /// Names may be defined more than once afterward
+    Destructs,
+}
+
+/// A transformation run in place on the whole program. Analyses are taken from `analyses` so
+/// that the ones an earlier pass preserved are not computed again
+pub trait Pass {
+    fn name(&self) -> &str;
+    fn run(&mut self, cfg: &mut CFG, analyses: &mut AnalysisManager);
+    fn ssa_effect(&self) -> SsaEffect {
+        SsaEffect::Preserves
+    }
+    /// Whether the pass is only sound on a program in SSA form
+    fn requires_ssa(&self) -> bool {
+        false
+    }
+    /// The cached analyses still valid after the pass
+    fn preserved_analyses(&self) -> PreservedAnalyses {
+        PreservedAnalyses::none()
+    }
+}
+
+type PassFn = fn(&mut CFG, &mut AnalysisManager);
+
+/// A pass without state of its own, most of our passes are a single `CFG` method
+pub struct FnPass {
+    name: &'static str,
+    run: PassFn,
+    ssa_effect: SsaEffect,
+    requires_ssa: bool,
+    preserved_analyses: PreservedAnalyses,
+}
+
+impl FnPass {
+    pub fn new(name: &'static str, run: PassFn) -> Self {
+        Self {
+            name,
+            run,
+            ssa_effect: SsaEffect::Preserves,
+            requires_ssa: false,
+            preserved_analyses: PreservedAnalyses::none(),
+        }
+    }
+
+    pub fn with_requires_ssa(mut self) -> Self {
+        self.requires_ssa = true;
+        self
+    }
+
+    pub fn with_ssa_effect(mut self, ssa_effect: SsaEffect) -> Self {
+        self.ssa_effect = ssa_effect;
+        self
+    }
+
+    pub fn with_preserved_analyses(mut self, preserved_analyses: PreservedAnalyses) -> Self {
+        self.preserved_analyses = preserved_analyses;
+        self
+    }
+}
+
+impl Pass for FnPass {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run(&mut self, cfg: &mut CFG, analyses: &mut AnalysisManager) {
+        (self.run)(cfg, analyses)
+    }
+
+    fn ssa_effect(&self) -> SsaEffect {
+        self.ssa_effect
+    }
+
+    fn requires_ssa(&self) -> bool {
+        self.requires_ssa
+    }
+
+    fn preserved_analyses(&self) -> PreservedAnalyses {
+        self.preserved_analyses.clone()
+    }
+}
+
+type PassConstructor = Box<dyn Fn() -> Box<dyn Pass>>;
+
+/// Passes by the name used in pipelines
+pub struct PassRegistry {
+    constructors: BTreeMap<String, PassConstructor>,
+}
+
+impl Default for PassRegistry {
+    /// The registry with every built-in pass
+    fn default() -> Self {
+        let mut result = Self::new();
+        // Only licm, loop-simplify, loop-rotate, split-critical-edges, tce, trace and ipcp change
+        // the blocks, every other pass keeps the dominance and the loops valid
+        let builtins: [(&'static str, PassFn, SsaEffect, PreservedAnalyses); 20] = [
+            (
+                "remove-nop",
+                |cfg, _| remove_nops(cfg),
+                SsaEffect::Preserves,
+                PreservedAnalyses::all(),
+            ),
+            (
+                "ssa",
+                CFG::place_phi_functions_and_generate_ssa_with,
+                SsaEffect::Constructs,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "ssa-minimal",
+                |cfg, analyses| {
+                    cfg.place_phi_functions_and_generate_ssa_in(SsaMode::Minimal, analyses)
+                },
+                SsaEffect::Constructs,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "ssa-semi-pruned",
+                |cfg, analyses| {
+                    cfg.place_phi_functions_and_generate_ssa_in(SsaMode::SemiPruned, analyses)
+                },
+                SsaEffect::Constructs,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "from-ssa",
+                |cfg, _| cfg.destruct_ssa(),
+                SsaEffect::Destructs,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "phi-to-id",
+                |cfg, _| single_argument_phis_to_ids(cfg),
+                SsaEffect::Preserves,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "const-prop",
+                |cfg, _| cfg.dataflow(&mut PessimisticConstProp::new()),
+                SsaEffect::Preserves,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "sccp",
+                |cfg, _| cfg.dataflow_forward_optimistically(&mut OptimisticConstProp::new()),
+                SsaEffect::Preserves,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "licm",
+                CFG::analyze_loop_with,
+                SsaEffect::Preserves,
+                PreservedAnalyses::none(),
+            ),
+            (
+                "loop-simplify",
+                |cfg, _| {
+                    cfg.simplify_loops();
+                },
+                SsaEffect::Preserves,
+                PreservedAnalyses::none(),
+            ),
+            (
+                "loop-rotate",
+                |cfg, _| {
+                    cfg.rotate_loops();
+                },
+                SsaEffect::Preserves,
+                PreservedAnalyses::none(),
+            ),
+            (
+                "split-critical-edges",
+                |cfg, _| {
+                    cfg.split_critical_edges();
+                },
+                SsaEffect::Preserves,
+                PreservedAnalyses::none(),
+            ),
+            (
+                "tce",
+                |cfg, _| {
+                    cfg.eliminate_tail_calls();
+                },
+                SsaEffect::Preserves,
+                PreservedAnalyses::none(),
+            ),
+            (
+                "ipcp",
+                |cfg, _| {
+                    cfg.propagate_constants_interprocedurally();
+                },
+                SsaEffect::Preserves,
+                PreservedAnalyses::none(),
+            ),
+            (
+                "dae",
+                |cfg, analyses| {
+                    cfg.eliminate_dead_arguments_with(analyses);
+                },
+                SsaEffect::Preserves,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "mem2reg",
+                |cfg, analyses| {
+                    cfg.promote_memory_to_registers_with(analyses);
+                },
+                SsaEffect::Destructs,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "dce",
+                |cfg, analyses| {
+                    cfg.eliminate_dead_code_with(analyses);
+                },
+                SsaEffect::Preserves,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "trace",
+                |cfg, _| {
+                    cfg.form_traces();
+                },
+                // The trace defines again every name of the blocks it copies
+                SsaEffect::Destructs,
+                PreservedAnalyses::none(),
+            ),
+            (
+                "block-layout",
+                CFG::layout_blocks_with,
+                SsaEffect::Preserves,
+                PreservedAnalyses::cfg(),
+            ),
+            (
+                "regalloc",
+                |cfg, _| {
+                    cfg.allocate_registers(DEFAULT_REGISTERS);
+                },
+                SsaEffect::Destructs,
+                PreservedAnalyses::cfg(),
+            ),
+        ];
+        for (name, run, ssa_effect, preserved_analyses) in builtins {
+            result.register(name, move || {
+                Box::new(
+                    FnPass::new(name, run)
+                        .with_ssa_effect(ssa_effect)
+                        .with_preserved_analyses(preserved_analyses.clone()),
+                )
+            });
+        }
+        // The alias analysis behind dead store elimination is only sound in SSA form
+        result.register("dse", || {
+            Box::new(
+                FnPass::new("dse", |cfg, _| {
+                    let mut alias = AliasAnalysis::new(cfg);
+                    cfg.dataflow(&mut alias)
+                })
+                .with_requires_ssa()
+                .with_preserved_analyses(PreservedAnalyses::cfg()),
+            )
+        });
+        result
+    }
+}
+
+impl PassRegistry {
+    /// An empty registry
+    pub fn new() -> Self {
+        Self {
+            constructors: BTreeMap::default(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, constructor: impl Fn() -> Box<dyn Pass> + 'static) {
+        self.constructors
+            .insert(name.to_string(), Box::new(constructor));
+    }
+
+    pub fn create(&self, name: &str) -> Option<Box<dyn Pass>> {
+        self.constructors.get(name).map(|constructor| constructor())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.constructors.keys().cloned().collect()
+    }
+}
+
+/// How long a pass took and how it changed the program size
+#[derive(Debug, Clone)]
+pub struct PassTiming {
+    pub name: String,
+    pub duration: Duration,
+    pub instructions_before: usize,
+    pub instructions_after: usize,
+}
+
+/// Runs a pipeline of passes on one `CFG`, in order
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    // Pass names after which the program is dumped to stderr, "all" for every pass
+    print_after: BTreeSet<String>,
+    // Verify the IR after every pass, on by default in debug builds
+    verify_each: bool,
+    // Whether the program is in SSA form, the SSA invariants are only verified then
+    ssa: bool,
+    pub timings: Vec<PassTiming>,
+    pub analyses: AnalysisManager,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self {
+            passes: Vec::default(),
+            print_after: BTreeSet::default(),
+            verify_each: cfg!(debug_assertions),
+            ssa: false,
+            timings: Vec::default(),
+            analyses: AnalysisManager::default(),
+        }
+    }
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn verify_each(&mut self, verify_each: bool) {
+        self.verify_each = verify_each;
+    }
+
+    /// Build the pipeline from a comma separated list of pass names, as in `ssa,sccp,from-ssa`.
+    /// A pass that requires SSA form must come after a pass constructing it
+    pub fn from_pipeline(registry: &PassRegistry, pipeline: &str) -> Result<Self, String> {
+        let mut result = Self::new();
+        let mut ssa = result.ssa;
+        for name in pipeline.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match registry.create(name) {
+                Some(pass) if pass.requires_ssa() && !ssa => {
+                    return Err(format!(
+                        "pass `{}` requires SSA form, run `ssa` before it",
+                        name
+                    ))
+                }
+                Some(pass) => {
+                    match pass.ssa_effect() {
+                        SsaEffect::Preserves => {}
+                        SsaEffect::Constructs => ssa = true,
+                        SsaEffect::Destructs => ssa = false,
+                    }
+                    result.add_pass(pass)
+                }
+                None => {
+                    return Err(format!(
+                        "unknown pass `{}`, available passes: {}",
+                        name,
+                        registry.names().join(", ")
+                    ))
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn add_pass(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    pub fn print_after(&mut self, name: &str) {
+        self.print_after.insert(name.to_string());
+    }
+
+    pub fn run(&mut self, cfg: &mut CFG) {
+        // Whatever is cached belongs to the previous CFG
+        self.analyses.clear();
+        for pass in self.passes.iter_mut() {
+            let instructions_before = instruction_count(cfg);
+            let start = Instant::now();
+            pass.run(cfg, &mut self.analyses);
+            let duration = start.elapsed();
+            self.analyses.invalidate(&pass.preserved_analyses());
+            self.timings.push(PassTiming {
+                name: pass.name().to_string(),
+                duration,
+                instructions_before,
+                instructions_after: instruction_count(cfg),
+            });
+
+            if self.print_after.contains("all") || self.print_after.contains(pass.name()) {
+                eprintln!("*** IR after {} ***", pass.name());
+                cfg.to_program().stderr();
+            }
+
+            match pass.ssa_effect() {
+                SsaEffect::Preserves => {}
+                SsaEffect::Constructs => self.ssa = true,
+                SsaEffect::Destructs => self.ssa = false,
+            }
+            if self.verify_each {
+                if let Err(errors) = cfg.verify_with(self.ssa, &mut self.analyses) {
+                    for error in errors.iter() {
+                        eprintln!("{}", error);
+                    }
+                    panic!("IR verification failed after pass {}", pass.name());
+                }
+            }
+        }
+    }
+
+    /// One line per pass run, with its share of the total time
+    pub fn timing_report(&self) -> String {
+        let total = self.timings.iter().map(|t| t.duration).sum::<Duration>();
+        let mut result = String::new();
+        for timing in self.timings.iter() {
+            let share = match total.is_zero() {
+                true => 0.0,
+                false => 100.0 * timing.duration.as_secs_f64() / total.as_secs_f64(),
+            };
+            result += &format!(
+                "{:>12.3?} {:>5.1}%  {:<12} {} -> {} instructions\n",
+                timing.duration,
+                share,
+                timing.name,
+                timing.instructions_before,
+                timing.instructions_after
+            );
+        }
+        result += &format!("{:>12.3?} total\n", total);
+        result
+    }
+}
+
+fn instruction_count(cfg: &CFG) -> usize {
+    cfg.ordered_blocks()
+        .map(|bb| {
+            let instrs = bb.instrs.iter();
+            instrs
+                .filter(|ilb| matches!(ilb, InstructionOrLabel::Instruction(_)))
+                .count()
+        })
+        .sum()
+}
+
+fn remove_nops(cfg: &mut CFG) {
+    for id in cfg.order.iter() {
+        let bb = &mut cfg.blocks[*id];
+        let instrs = std::mem::take(&mut bb.instrs);
+        bb.instrs = instrs
+            .into_iter()
+            .filter(|ilb| !matches!(ilb, InstructionOrLabel::Instruction(i) if i.is_nop()))
+            .collect::<Vec<_>>();
+    }
+}
+
+fn single_argument_phis_to_ids(cfg: &mut CFG) {
+    for id in cfg.order.iter() {
+        for ilb in cfg.blocks[*id].instrs.iter_mut() {
+            if let InstructionOrLabel::Instruction(i) = ilb {
+                if i.is_phi() && i.args.as_ref().is_some_and(|args| args.len() == 1) {
+                    i.op = "id".to_string();
+                    i.labels = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+/// The counts of one function in a profile written by `brilirs --block-profile`. Blocks are named
+/// `.label`, or `#i` for the i-th block of the function when it has no label
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    pub blocks: BTreeMap<String, u64>,
+    pub edges: BTreeMap<(String, String), u64>,
+}
+
+/// A per function, per block and per edge execution count profile, see `CFG::apply_profile`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub functions: BTreeMap<String, FunctionProfile>,
+}
+
+impl Profile {
+    /// Parse the text `brilirs --block-profile` writes, one count per line:
+    /// `function <name> <calls>`, `block <name> <block> <count>` or
+    /// `edge <name> <from> <to> <count>`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut profile = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let count = |word: &str| {
+                word.parse::<u64>()
+                    .map_err(|_| format!("line {}: `{}` is not a count", number + 1, word))
+            };
+            match words.as_slice() {
+                [] => {}
+                ["function", name, calls] => {
+                    profile.function_mut(name).calls = count(calls)?;
+                }
+                ["block", name, block, n] => {
+                    let n = count(n)?;
+                    profile
+                        .function_mut(name)
+                        .blocks
+                        .insert(block.to_string(), n);
+                }
+                ["edge", name, from, to, n] => {
+                    let n = count(n)?;
+                    profile
+                        .function_mut(name)
+                        .edges
+                        .insert((from.to_string(), to.to_string()), n);
+                }
+                _ => return Err(format!("line {}: malformed `{}`", number + 1, line)),
+            }
+        }
+        Ok(profile)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&text)
+    }
+
+    fn function_mut(&mut self, name: &str) -> &mut FunctionProfile {
+        self.functions.entry(name.to_string()).or_default()
+    }
+
+    /// How many times `function` was called, 0 if it is not in the profile
+    pub fn call_count(&self, function: &str) -> u64 {
+        self.functions.get(function).map_or(0, |f| f.calls)
+    }
+}
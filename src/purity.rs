@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    aliases::BlockID,
+    bril_syntax::{Instruction, InstructionOrLabel},
+    call_graph::CallGraph,
+    cfg::CFG,
+};
+
+/// What a call to a function may do besides returning its result, through its callees too
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionSummary {
+    pub prints: bool,
+    /// Runs an `alloc` or a `free`
+    pub allocates: bool,
+    /// Runs a `load`. A function that does not allocate only reaches the memory reachable from
+    /// its arguments
+    pub reads_memory: bool,
+    /// Runs a `store`
+    pub writes_memory: bool,
+    /// Has a loop or is recursive, nothing tries to prove that it terminates
+    pub may_not_terminate: bool,
+    /// Runs a `div` or an `int2char`, which stop the program on some arguments
+    pub may_trap: bool,
+}
+
+impl FunctionSummary {
+    /// What a call to a function outside of the program may do
+    pub const UNKNOWN: Self = Self {
+        prints: true,
+        allocates: true,
+        reads_memory: true,
+        writes_memory: true,
+        may_not_terminate: true,
+        may_trap: true,
+    };
+
+    /// Whether a call whose result is unused can be removed
+    pub fn is_removable(&self) -> bool {
+        !(self.prints || self.allocates || self.writes_memory || self.may_not_terminate)
+    }
+
+    /// Whether the result of a call only depends on its arguments, so that it can be moved and
+    /// two calls with the same arguments give the same result. Like a `div`, a call that may trap
+    /// is removable but not moved to where it may run when it did not before
+    pub fn is_pure(&self) -> bool {
+        self.is_removable() && !self.reads_memory && !self.may_trap
+    }
+
+    fn join(&mut self, other: &Self) {
+        self.prints |= other.prints;
+        self.allocates |= other.allocates;
+        self.reads_memory |= other.reads_memory;
+        self.writes_memory |= other.writes_memory;
+        self.may_not_terminate |= other.may_not_terminate;
+        self.may_trap |= other.may_trap;
+    }
+
+    /// The effects of one instruction, calls left aside
+    fn of_instruction(i: &Instruction) -> Self {
+        Self {
+            prints: i.is_print(),
+            allocates: i.is_alloc() || i.is_free(),
+            reads_memory: i.is_load(),
+            writes_memory: i.is_store(),
+            may_not_terminate: false,
+            may_trap: i.is_div() || i.is_int2char(),
+        }
+    }
+}
+
+/// The summary of every function of the program, the effects of the callees included
+#[derive(Debug, Clone, Default)]
+pub struct PuritySummaries {
+    pub functions: BTreeMap<String, FunctionSummary>,
+}
+
+impl PuritySummaries {
+    pub fn new(cfg: &CFG) -> Self {
+        let graph = CallGraph::new(cfg);
+        let mut functions = BTreeMap::new();
+        for (name, blocks) in graph.functions.iter() {
+            let mut summary = FunctionSummary::default();
+            for ilb in blocks.iter().flat_map(|id| cfg.blocks[*id].instrs.iter()) {
+                if let InstructionOrLabel::Instruction(i) = ilb {
+                    summary.join(&FunctionSummary::of_instruction(i));
+                }
+            }
+            summary.may_not_terminate =
+                has_cycle(cfg, blocks[0]) || graph.reachable_from(name).contains(name.as_str());
+            functions.insert(name.clone(), summary);
+        }
+
+        // The callees are summarized before their callers are final, go on until nothing changes
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for site in graph.call_sites.iter() {
+                let callee = functions
+                    .get(&site.callee)
+                    .copied()
+                    .unwrap_or(FunctionSummary::UNKNOWN);
+                let caller = functions.get_mut(&site.caller).unwrap();
+                let before = *caller;
+                caller.join(&callee);
+                changed |= before != *caller;
+            }
+        }
+        Self { functions }
+    }
+
+    pub fn summary(&self, function: &str) -> FunctionSummary {
+        self.functions
+            .get(function)
+            .copied()
+            .unwrap_or(FunctionSummary::UNKNOWN)
+    }
+
+    /// The functions a call to can be removed when its result is unused
+    pub fn removable_functions(&self) -> BTreeSet<String> {
+        let removable = self.functions.iter().filter(|(_, s)| s.is_removable());
+        removable.map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn pure_functions(&self) -> BTreeSet<String> {
+        let pure = self.functions.iter().filter(|(_, s)| s.is_pure());
+        pure.map(|(name, _)| name.clone()).collect()
+    }
+}
+
+/// Whether a cycle can be reached from `entry`, found as an edge back to a block still on the
+/// stack of a depth first search
+fn has_cycle(cfg: &CFG, entry: BlockID) -> bool {
+    let mut on_stack = BTreeMap::<BlockID, bool>::from([(entry, true)]);
+    let mut stack = vec![(entry, 0)];
+    while let Some((id, next)) = stack.pop() {
+        let Some(succ) = cfg.blocks[id].successors.get(next).copied() else {
+            on_stack.insert(id, false);
+            continue;
+        };
+        stack.push((id, next + 1));
+        match on_stack.get(&succ) {
+            Some(true) => return true,
+            Some(false) => {}
+            None => {
+                on_stack.insert(succ, true);
+                stack.push((succ, 0));
+            }
+        }
+    }
+    false
+}
@@ -0,0 +1,548 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json::Value;
+
+use crate::{
+    aliases::BlockID,
+    bril_syntax::{BrilType, Instruction, InstructionOrLabel},
+    cfg::CFG,
+    liveness::{Liveness, LivenessKind},
+};
+
+/// Registers available when nothing else is asked for
+pub const DEFAULT_REGISTERS: usize = 8;
+/// A binary instruction may need two reloaded operands and a spilled destination at once
+pub const MIN_REGISTERS: usize = 3;
+
+/// Name of the `int` constant 1 used as the size of every spill slot
+const SPILL_SLOT_SIZE: &str = "spill_slot_size";
+
+/// The outcome of register allocation for one function
+#[derive(Debug, Clone, Default)]
+pub struct FunctionAllocation {
+    pub name: String,
+    /// Register index of every variable left in the function, spill slots live on the stack and
+    /// have none
+    pub assignment: BTreeMap<String, usize>,
+    /// Variables that were moved to memory
+    pub spilled: BTreeSet<String>,
+    /// Number of `id` copies removed by coalescing
+    pub coalesced: usize,
+}
+
+impl FunctionAllocation {
+    pub fn register_name(register: usize) -> String {
+        format!("r{}", register)
+    }
+
+    pub fn register_of(&self, var: &str) -> Option<String> {
+        self.assignment.get(var).map(|r| Self::register_name(*r))
+    }
+
+    pub fn registers_used(&self) -> usize {
+        self.assignment.values().collect::<BTreeSet<_>>().len()
+    }
+}
+
+/// INFO: Per function state of the allocator.
+/// Frame variables (spill slots and their size, arguments passed on the stack) are not allocated
+/// to registers, they stand for stack locations. Variables introduced by spill code are never
+/// spilled again
+struct RegisterAllocator {
+    registers: usize,
+    blocks: Vec<BlockID>,
+    types: BTreeMap<String, BrilType>,
+    frame: BTreeSet<String>,
+    unspillable: BTreeSet<String>,
+    interference: BTreeMap<String, BTreeSet<String>>,
+    // `id` copies as (dest, src)
+    moves: Vec<(String, String)>,
+    spill_counter: usize,
+    result: FunctionAllocation,
+}
+
+impl RegisterAllocator {
+    fn new(cfg: &CFG, blocks: Vec<BlockID>, registers: usize) -> Self {
+        let func = cfg.blocks[blocks[0]].func.clone().unwrap();
+        let mut types = BTreeMap::new();
+        for arg in func.args.iter().flatten() {
+            types.insert(arg.name.clone(), arg.fn_type.clone());
+        }
+        for i in Self::instructions(cfg, &blocks) {
+            if let (Some(dest), Some(bril_type)) = (&i.dest, &i.bril_type) {
+                types.insert(dest.clone(), bril_type.clone());
+            }
+        }
+        Self {
+            registers,
+            blocks,
+            types,
+            frame: BTreeSet::default(),
+            unspillable: BTreeSet::default(),
+            interference: BTreeMap::default(),
+            moves: Vec::default(),
+            spill_counter: 0,
+            result: FunctionAllocation {
+                name: func.name,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn instructions(cfg: &CFG, blocks: &[BlockID]) -> Vec<Instruction> {
+        let mut result = Vec::new();
+        for bb in blocks {
+            for ilb in cfg.blocks[*bb].instrs.iter() {
+                if let InstructionOrLabel::Instruction(i) = ilb {
+                    result.push(i.clone());
+                }
+            }
+        }
+        result
+    }
+
+    fn args(&self, cfg: &CFG) -> Vec<String> {
+        let entry = &cfg.blocks[self.blocks[0]];
+        let args = entry.func.as_ref().unwrap().args.iter().flatten();
+        args.map(|arg| arg.name.clone()).collect()
+    }
+
+    fn add_node(&mut self, var: &String) {
+        if !self.frame.contains(var) {
+            self.interference.entry(var.clone()).or_default();
+        }
+    }
+
+    fn add_edge(&mut self, a: &String, b: &String) {
+        if a == b || self.frame.contains(a) || self.frame.contains(b) {
+            return;
+        }
+        self.interference
+            .entry(a.clone())
+            .or_default()
+            .insert(b.clone());
+        self.interference
+            .entry(b.clone())
+            .or_default()
+            .insert(a.clone());
+    }
+
+    /// A destination interferes with everything live right after its definition, except the
+    /// source of a copy. The arguments are all defined together at the function entry
+    fn build(&mut self, cfg: &CFG, liveness: &Liveness) {
+        self.interference.clear();
+        self.moves.clear();
+
+        let mut defined_at_entry = self.args(cfg);
+        defined_at_entry.extend(liveness.live_in(self.blocks[0]));
+        for a in defined_at_entry.iter() {
+            self.add_node(a);
+            for b in defined_at_entry.iter() {
+                self.add_edge(a, b);
+            }
+        }
+
+        for i in Self::instructions(cfg, &self.blocks) {
+            for arg in i.args.iter().flatten() {
+                self.add_node(arg);
+            }
+            let Some(dest) = &i.dest else {
+                continue;
+            };
+            self.add_node(dest);
+            let copy_source = match i.is_id() {
+                true => i.args.as_ref().map(|args| args[0].clone()),
+                false => None,
+            };
+            for live in liveness.live_after(i.instruction_id.unwrap()) {
+                if Some(&live) != copy_source.as_ref() {
+                    self.add_edge(dest, &live);
+                }
+            }
+            if let Some(src) = copy_source {
+                self.moves.push((dest.clone(), src));
+            }
+        }
+    }
+
+    /// Conservative (Briggs) coalescing: two copy-related variables are merged when the merged
+    /// node has fewer than K neighbors of significant degree, so it is still colorable.
+    /// Returns the renaming to apply, the source of the copy is the name that is kept
+    fn coalesce(&mut self) -> BTreeMap<String, String> {
+        let mut alias = BTreeMap::<String, String>::new();
+        let find = |alias: &BTreeMap<String, String>, var: &String| {
+            let mut var = var.clone();
+            while let Some(next) = alias.get(&var) {
+                var = next.clone();
+            }
+            var
+        };
+
+        for (dest, src) in self.moves.clone() {
+            let (dest, src) = (find(&alias, &dest), find(&alias, &src));
+            if dest == src
+                || self.frame.contains(&dest)
+                || self.frame.contains(&src)
+                || self.unspillable.contains(&dest)
+                || self.unspillable.contains(&src)
+                || self.types.get(&dest) != self.types.get(&src)
+                || self.interference[&dest].contains(&src)
+            {
+                continue;
+            }
+            let neighbors = &self.interference[&dest] | &self.interference[&src];
+            let significant = neighbors
+                .iter()
+                .filter(|n| self.interference[*n].len() >= self.registers)
+                .count();
+            if significant >= self.registers {
+                continue;
+            }
+            for n in neighbors.iter() {
+                let adjacent = self.interference.get_mut(n).unwrap();
+                adjacent.remove(&dest);
+                adjacent.insert(src.clone());
+            }
+            self.interference.remove(&dest);
+            self.interference.insert(src.clone(), neighbors);
+            alias.insert(dest, src);
+        }
+
+        alias
+            .keys()
+            .map(|var| (var.clone(), find(&alias, var)))
+            .collect()
+    }
+
+    /// Rename the variables of the function and drop the copies that became `x = id x`
+    fn rename(&mut self, cfg: &mut CFG, renaming: &BTreeMap<String, String>) {
+        let renamed = |var: &mut String| {
+            if let Some(to) = renaming.get(var) {
+                *var = to.clone();
+            }
+        };
+        if let Some(func) = cfg.blocks[self.blocks[0]].func.as_mut() {
+            for arg in func.args.iter_mut().flatten() {
+                renamed(&mut arg.name);
+            }
+        }
+        for bb in self.blocks.iter() {
+            let bb = &mut cfg.blocks[*bb];
+            let mut kept_instruction = Vec::<InstructionOrLabel>::new();
+            for ilb in bb.instrs.iter() {
+                let mut ilb = ilb.clone();
+                if let InstructionOrLabel::Instruction(i) = &mut ilb {
+                    i.dest.iter_mut().for_each(renamed);
+                    i.args.iter_mut().flatten().for_each(renamed);
+                    if i.is_id() && i.dest.as_ref() == i.args.as_ref().map(|args| &args[0]) {
+                        self.result.coalesced += 1;
+                        continue;
+                    }
+                }
+                kept_instruction.push(ilb);
+            }
+            bb.instrs = kept_instruction;
+        }
+    }
+
+    /// Chaitin-Briggs simplify and select with optimistic coloring. Returns the variables that
+    /// could not get a register
+    fn color(&mut self) -> BTreeSet<String> {
+        let mut graph = self.interference.clone();
+        let mut stack = Vec::<String>::new();
+        while !graph.is_empty() {
+            let trivial = graph
+                .iter()
+                .find(|(_, adjacent)| adjacent.len() < self.registers)
+                .map(|(var, _)| var.clone());
+            // Nothing is trivially colorable, the variable with the most neighbors may still
+            // get a register, otherwise it is spilled
+            let var = trivial.unwrap_or_else(|| {
+                graph
+                    .iter()
+                    .max_by_key(|(var, adjacent)| {
+                        (self.is_spillable(var), adjacent.len(), (*var).clone())
+                    })
+                    .map(|(var, _)| var.clone())
+                    .unwrap()
+            });
+            for adjacent in graph.remove(&var).unwrap() {
+                graph.get_mut(&adjacent).unwrap().remove(&var);
+            }
+            stack.push(var);
+        }
+
+        let mut spilled = BTreeSet::new();
+        self.result.assignment.clear();
+        while let Some(var) = stack.pop() {
+            let taken = self.interference[&var]
+                .iter()
+                .filter_map(|n| self.result.assignment.get(n))
+                .collect::<BTreeSet<_>>();
+            match (0..self.registers).find(|r| !taken.contains(r)) {
+                Some(register) => {
+                    self.result.assignment.insert(var, register);
+                }
+                None => {
+                    spilled.insert(var);
+                }
+            }
+        }
+        spilled
+    }
+
+    fn is_spillable(&self, var: &String) -> bool {
+        !self.unspillable.contains(var) && self.types.contains_key(var)
+    }
+
+    fn slot_name(var: &str) -> String {
+        format!("{}_slot", var)
+    }
+
+    fn fresh(&mut self, var: &str, what: &str) -> String {
+        self.spill_counter += 1;
+        format!("{}_{}{}", var, what, self.spill_counter)
+    }
+
+    fn new_instruction(
+        op: &str,
+        dest: Option<&str>,
+        bril_type: Option<BrilType>,
+        args: Vec<String>,
+        instruction_counter: &mut usize,
+    ) -> InstructionOrLabel {
+        let result = InstructionOrLabel::Instruction(Instruction {
+            op: op.to_string(),
+            dest: dest.map(|dest| dest.to_string()),
+            args: Some(args),
+            bril_type,
+            value: Default::default(),
+            funcs: Default::default(),
+            labels: Default::default(),
+            instruction_id: Some(*instruction_counter),
+            other_fields: Default::default(),
+        });
+        *instruction_counter += 1;
+        result
+    }
+
+    /// Give every spilled variable a one cell stack slot allocated at the function entry and
+    /// freed on the way out. Every use reloads into a fresh variable and every definition writes
+    /// a fresh variable stored right away
+    fn spill(&mut self, cfg: &mut CFG, spilled: &BTreeSet<String>) {
+        let instruction_counter = &mut cfg.instruction_counter;
+        for var in spilled.iter() {
+            if !self.is_spillable(var) {
+                panic!(
+                    "{} registers are not enough to allocate {} in @{}",
+                    self.registers, var, self.result.name
+                );
+            }
+            eprintln!("Spilling {} in @{}", var, self.result.name);
+        }
+        self.result.spilled.extend(spilled.iter().cloned());
+
+        let slot_of = |var: &String| (Self::slot_name(var), self.types[var].clone());
+        let new_slots = spilled.iter().map(slot_of).collect::<Vec<_>>();
+
+        for bb in self.blocks.clone().iter() {
+            let bb = &mut cfg.blocks[*bb];
+            let mut kept_instruction = Vec::<InstructionOrLabel>::new();
+            for ilb in bb.instrs.iter() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    kept_instruction.push(ilb.clone());
+                    continue;
+                };
+                assert!(!i.is_phi(), "register allocation expects code out of SSA");
+                let mut i = i.clone();
+                let on_stack = i.is_call() || i.is_print();
+                for arg in i.args.iter_mut().flatten() {
+                    if spilled.contains(arg) {
+                        let reload = self.fresh(arg, "reload");
+                        kept_instruction.push(Self::new_instruction(
+                            "load",
+                            Some(&reload),
+                            Some(self.types[arg].clone()),
+                            vec![Self::slot_name(arg)],
+                            instruction_counter,
+                        ));
+                        self.types.insert(reload.clone(), self.types[arg].clone());
+                        // Spilled arguments of calls and prints are passed on the stack, both
+                        // may take more arguments than there are registers
+                        match on_stack {
+                            true => self.frame.insert(reload.clone()),
+                            false => self.unspillable.insert(reload.clone()),
+                        };
+                        *arg = reload;
+                    }
+                }
+                // Slots of earlier rounds are already freed here
+                if i.is_ret() {
+                    for (slot, _) in new_slots.iter() {
+                        kept_instruction.push(Self::new_instruction(
+                            "free",
+                            None,
+                            None,
+                            vec![slot.clone()],
+                            instruction_counter,
+                        ));
+                    }
+                }
+                let mut store = None;
+                if let Some(dest) = i.dest.as_mut() {
+                    if spilled.contains(dest) {
+                        let spill = self.fresh(dest, "spill");
+                        store = Some(Self::new_instruction(
+                            "store",
+                            None,
+                            None,
+                            vec![Self::slot_name(dest), spill.clone()],
+                            instruction_counter,
+                        ));
+                        self.types.insert(spill.clone(), self.types[dest].clone());
+                        self.unspillable.insert(spill.clone());
+                        *dest = spill;
+                    }
+                }
+                kept_instruction.push(InstructionOrLabel::Instruction(i));
+                kept_instruction.extend(store);
+            }
+
+            // Falling off the end of the function is an implicit `ret`
+            let returns = matches!(
+                kept_instruction.last(),
+                Some(InstructionOrLabel::Instruction(i)) if i.is_ret()
+            );
+            if bb.successors.is_empty() && !returns {
+                for (slot, _) in new_slots.iter() {
+                    kept_instruction.push(Self::new_instruction(
+                        "free",
+                        None,
+                        None,
+                        vec![slot.clone()],
+                        instruction_counter,
+                    ));
+                }
+            }
+            bb.instrs = kept_instruction;
+        }
+
+        // The slots are allocated right after the entry label, followed by the stores of the
+        // spilled arguments
+        let args = self.args(cfg);
+        let entry = &mut cfg.blocks[self.blocks[0]];
+        let instruction_counter = &mut cfg.instruction_counter;
+        let mut prologue = Vec::<InstructionOrLabel>::new();
+        // Slots of later rounds go after the size constant defined by the first one
+        let mut position = 2;
+        if !self.frame.contains(SPILL_SLOT_SIZE) {
+            position = 1;
+            let mut size = Self::new_instruction(
+                "const",
+                Some(SPILL_SLOT_SIZE),
+                Some(BrilType::Int),
+                vec![],
+                instruction_counter,
+            );
+            if let InstructionOrLabel::Instruction(i) = &mut size {
+                i.args = None;
+                i.value = Some(Value::from(1));
+            }
+            prologue.push(size);
+            self.frame.insert(SPILL_SLOT_SIZE.to_string());
+        }
+        for (slot, pointee) in new_slots.iter() {
+            prologue.push(Self::new_instruction(
+                "alloc",
+                Some(slot),
+                Some(BrilType::Ptr(Box::new(pointee.clone()))),
+                vec![SPILL_SLOT_SIZE.to_string()],
+                instruction_counter,
+            ));
+            self.frame.insert(slot.clone());
+        }
+        // A spilled argument is passed on the stack, it doesn't need a register anymore
+        for arg in args.iter().filter(|arg| spilled.contains(*arg)) {
+            self.frame.insert(arg.clone());
+            prologue.push(Self::new_instruction(
+                "store",
+                None,
+                None,
+                vec![Self::slot_name(arg), arg.clone()],
+                instruction_counter,
+            ));
+        }
+        let mut rest = entry.instrs.split_off(position);
+        entry.instrs.append(&mut prologue);
+        entry.instrs.append(&mut rest);
+    }
+
+    /// Record the registers of the destination and the arguments of every instruction in its
+    /// `reg` and `arg_regs` fields. Frame variables get `null`
+    fn annotate(&self, cfg: &mut CFG) {
+        let register_of = |var: &String| match self.result.register_of(var) {
+            Some(register) => Value::from(register),
+            None => Value::Null,
+        };
+        for bb in self.blocks.iter() {
+            for ilb in cfg.blocks[*bb].instrs.iter_mut() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    continue;
+                };
+                if !i.other_fields.is_object() {
+                    i.other_fields = Value::Object(Default::default());
+                }
+                let fields = i.other_fields.as_object_mut().unwrap();
+                if let Some(dest) = &i.dest {
+                    fields.insert("reg".to_string(), register_of(dest));
+                }
+                if let Some(args) = &i.args {
+                    let regs = args.iter().map(register_of).collect::<Vec<_>>();
+                    fields.insert("arg_regs".to_string(), Value::from(regs));
+                }
+            }
+        }
+    }
+}
+
+/// INFO: This impl block is denoted to be about register allocation
+impl CFG {
+    /// Assign one of `registers` registers to every variable, coalescing `id` copies and
+    /// spilling to memory with `alloc`/`store`/`load` when the interference graph can't be
+    /// colored. The code must be out of SSA.
+    ///
+    /// Instructions are annotated with their registers, see `FunctionAllocation` for the
+    /// assignment and spills of each function
+    pub fn allocate_registers(&mut self, registers: usize) -> Vec<FunctionAllocation> {
+        assert!(
+            registers >= MIN_REGISTERS,
+            "at least {} registers are needed",
+            MIN_REGISTERS
+        );
+        let mut allocators = self
+            .function_blocks()
+            .into_iter()
+            .map(|blocks| RegisterAllocator::new(self, blocks, registers))
+            .collect::<Vec<_>>();
+
+        for allocator in allocators.iter_mut() {
+            loop {
+                let liveness =
+                    Liveness::analyze_function(self, &allocator.blocks, LivenessKind::Live);
+                allocator.build(self, &liveness);
+                let renaming = allocator.coalesce();
+                if !renaming.is_empty() {
+                    allocator.rename(self, &renaming);
+                    continue;
+                }
+                let spilled = allocator.color();
+                if spilled.is_empty() {
+                    break;
+                }
+                allocator.spill(self, &spilled);
+            }
+            allocator.annotate(self);
+        }
+        allocators.into_iter().map(|a| a.result).collect()
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+use crate::{
+    aliases::BlockID,
+    analysis_manager::AnalysisManager,
+    bril_syntax::{BrilType, Instruction, InstructionOrLabel},
+    cfg::CFG,
+    dominance::DominanceDataFlow,
+    loops::NaturalLoops,
+};
+
+/// An integer value of a function as a function of the iterations of the loops around it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scev {
+    Constant(i64),
+    /// The value of a name nothing more is known about
+    Value(String),
+    Add(Box<Scev>, Box<Scev>),
+    Mul(Box<Scev>, Box<Scev>),
+    /// `{start, +, step}<header>`: `start` on the first iteration of the loop of `header`, plus
+    /// `step` on every iteration after. Both do not change in that loop, `start` may be a
+    /// recurrence of an outer loop
+    AddRec {
+        start: Box<Scev>,
+        step: Box<Scev>,
+        header: BlockID,
+    },
+}
+
+impl Scev {
+    pub fn as_constant(&self) -> Option<i64> {
+        match self {
+            Scev::Constant(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    /// The value on iteration `iteration` of its loop, counting from 0, when it is constant there
+    pub fn value_at(&self, iteration: i64) -> Option<i64> {
+        match self {
+            Scev::Constant(c) => Some(*c),
+            Scev::AddRec { start, step, .. } => {
+                let (start, step) = (start.as_constant()?, step.as_constant()?);
+                start.checked_add(step.checked_mul(iteration)?)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How many times the exit test of a loop lets it go on, assuming nothing overflows and the
+/// loop terminates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TripCount {
+    Constant(u64),
+    /// `(end - start) / step` rounded up, 0 if it is negative
+    Symbolic {
+        start: Scev,
+        end: Scev,
+        step: i64,
+    },
+}
+
+/// The scalar evolution of the integer values of one function in SSA form: induction variables
+/// as add-recurrences of the natural loops they change in, and the trip counts of the loops with
+/// a single exit comparing one of them to a value the loop does not change. Meant for strength
+/// reduction, unrolling and reasoning about bounds
+pub struct ScalarEvolution {
+    loops: Rc<NaturalLoops>,
+    dominance: Rc<DominanceDataFlow>,
+    /// The blocks defining each name, more than one outside of SSA form
+    def_blocks: BTreeMap<String, Vec<BlockID>>,
+    definitions: BTreeMap<String, Instruction>,
+    /// The label of every block and the label its predecessors are known by
+    labels: BTreeMap<BlockID, String>,
+    branches: BTreeMap<BlockID, Instruction>,
+    successors: BTreeMap<BlockID, Vec<BlockID>>,
+    predecessors: BTreeMap<BlockID, Vec<BlockID>>,
+    evolutions: BTreeMap<String, Scev>,
+    // Names whose evolution is being computed, a name met again is left as a `Value`
+    in_progress: BTreeSet<String>,
+}
+
+impl ScalarEvolution {
+    pub fn new(cfg: &CFG, blocks: &[BlockID], analyses: &mut AnalysisManager) -> Self {
+        let mut result = Self {
+            loops: analyses.loops(cfg, blocks),
+            dominance: analyses.dominance(cfg, blocks),
+            def_blocks: BTreeMap::new(),
+            definitions: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            branches: BTreeMap::new(),
+            successors: BTreeMap::new(),
+            predecessors: BTreeMap::new(),
+            evolutions: BTreeMap::new(),
+            in_progress: BTreeSet::new(),
+        };
+        for id in blocks.iter() {
+            let bb = &cfg.blocks[*id];
+            result.labels.insert(*id, bb.get_label());
+            result.successors.insert(*id, bb.successors.clone());
+            result.predecessors.insert(*id, bb.predecessors.clone());
+            for ilb in bb.instrs.iter() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    continue;
+                };
+                if i.is_br() {
+                    result.branches.insert(*id, i.clone());
+                }
+                if let Some(dest) = &i.dest {
+                    result.def_blocks.entry(dest.clone()).or_default().push(*id);
+                    result.definitions.insert(dest.clone(), i.clone());
+                }
+            }
+        }
+        let names = result.definitions.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            result.evolution(&name);
+        }
+        result
+    }
+
+    /// The loop headers of the function
+    pub fn headers(&self) -> Vec<BlockID> {
+        self.loops.headers().copied().collect()
+    }
+
+    /// The evolution of `name`, a `Value` if nothing is known about it
+    pub fn scev(&self, name: &str) -> Scev {
+        self.evolutions
+            .get(name)
+            .cloned()
+            .unwrap_or(Scev::Value(name.to_string()))
+    }
+
+    /// The names changing by a constant or invariant step on every iteration of the loop of
+    /// `header`, candidates for strength reduction
+    pub fn induction_variables(&self, header: BlockID) -> Vec<(String, Scev)> {
+        self.evolutions
+            .iter()
+            .filter(|(_, scev)| matches!(scev, Scev::AddRec { header: h, .. } if *h == header))
+            .map(|(name, scev)| (name.clone(), scev.clone()))
+            .collect()
+    }
+
+    /// The smallest and largest values of `name` on the iterations the exit test of its loop lets
+    /// go on, when its start, step and the trip count are constant
+    pub fn range(&self, name: &str) -> Option<(i64, i64)> {
+        let scev = self.scev(name);
+        let Scev::AddRec { header, .. } = &scev else {
+            let c = scev.as_constant()?;
+            return Some((c, c));
+        };
+        let Some(TripCount::Constant(count)) = self.trip_count(*header) else {
+            return None;
+        };
+        let first = scev.value_at(0)?;
+        let last = scev.value_at(i64::try_from(count).ok()?.checked_sub(1)?)?;
+        Some((first.min(last), first.max(last)))
+    }
+
+    /// The trip count of the loop of `header`. The loop must leave from a single block running
+    /// on every iteration, through a `br` on a comparison of a recurrence of the loop with a
+    /// constant step and a value the loop does not change
+    pub fn trip_count(&self, header: BlockID) -> Option<TripCount> {
+        let body = self.loops.bodies.get(&header)?;
+        let mut exiting = body.iter().filter(|id| {
+            let successors = self.successors[id].iter();
+            successors.clone().any(|succ| !body.contains(succ))
+        });
+        let (Some(exiting), None) = (exiting.next(), exiting.next()) else {
+            return None;
+        };
+        let latches = &self.loops.latches[&header];
+        if !latches
+            .iter()
+            .all(|latch| self.dominance.dom(*exiting, *latch))
+        {
+            return None;
+        }
+
+        let br = self.branches.get(exiting)?;
+        let labels = br.labels.as_ref()?;
+        let inside = |label: &String| {
+            let successors = self.successors[exiting].iter();
+            successors
+                .clone()
+                .find(|succ| self.labels[succ] == *label)
+                .is_some_and(|succ| body.contains(succ))
+        };
+        let stay_if = match (inside(&labels[0]), inside(&labels[1])) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => return None,
+        };
+        let condition = self.definitions.get(&br.args.as_ref()?[0])?;
+        let mut predicate = condition.op.as_str();
+        if !stay_if {
+            predicate = negate(predicate)?;
+        }
+        let [left, right] = condition.args.as_deref()? else {
+            return None;
+        };
+        let (left, right) = (self.scev(left), self.scev(right));
+        let (recurrence, bound) = match (&left, &right) {
+            (Scev::AddRec { header: h, .. }, _) if *h == header => (left.clone(), right.clone()),
+            (_, Scev::AddRec { header: h, .. }) if *h == header => {
+                predicate = swap(predicate)?;
+                (right.clone(), left.clone())
+            }
+            _ => return None,
+        };
+        if !self.invariant_in(&bound, header) {
+            return None;
+        }
+        let Scev::AddRec { start, step, .. } = recurrence else {
+            unreachable!()
+        };
+        let step = step.as_constant()?;
+        let end = match (predicate, step.signum()) {
+            ("lt", 1) | ("gt", -1) => bound,
+            ("le", 1) => self.add(bound, Scev::Constant(1)),
+            ("ge", -1) => self.add(bound, Scev::Constant(-1)),
+            ("ne", _) if step.abs() == 1 => bound,
+            _ => return None,
+        };
+        match (start.as_constant(), end.as_constant()) {
+            (Some(start), Some(end)) => {
+                let distance = (i128::from(end) - i128::from(start)) * i128::from(step.signum());
+                let step = i128::from(step.abs());
+                let count = (distance.max(0) + step - 1) / step;
+                Some(TripCount::Constant(u64::try_from(count).ok()?))
+            }
+            _ => Some(TripCount::Symbolic {
+                start: *start,
+                end,
+                step,
+            }),
+        }
+    }
+
+    /// `scev` the way LLVM prints it, loops are named after the label of their header
+    pub fn describe(&self, scev: &Scev) -> String {
+        match scev {
+            Scev::Constant(c) => c.to_string(),
+            Scev::Value(name) => name.clone(),
+            Scev::Add(a, b) => format!("({} + {})", self.describe(a), self.describe(b)),
+            Scev::Mul(a, b) => format!("({} * {})", self.describe(a), self.describe(b)),
+            Scev::AddRec {
+                start,
+                step,
+                header,
+            } => format!(
+                "{{{},+,{}}}<.{}>",
+                self.describe(start),
+                self.describe(step),
+                self.labels[header]
+            ),
+        }
+    }
+
+    pub fn describe_trip_count(&self, count: &TripCount) -> String {
+        match count {
+            TripCount::Constant(c) => c.to_string(),
+            TripCount::Symbolic { start, end, step } => {
+                let distance = self.sub(end.clone(), start.clone());
+                format!("ceil({} / {})", self.describe(&distance), step)
+            }
+        }
+    }
+
+    /// Whether the value of `scev` stays the same during the loop of `header`
+    fn invariant_in(&self, scev: &Scev, header: BlockID) -> bool {
+        let body = &self.loops.bodies[&header];
+        match scev {
+            Scev::Constant(_) => true,
+            Scev::Value(name) => self
+                .def_blocks
+                .get(name)
+                .is_none_or(|blocks| blocks.iter().all(|id| !body.contains(id))),
+            Scev::Add(a, b) | Scev::Mul(a, b) => {
+                self.invariant_in(a, header) && self.invariant_in(b, header)
+            }
+            Scev::AddRec { header: h, .. } => !body.contains(h),
+        }
+    }
+
+    fn add(&self, a: Scev, b: Scev) -> Scev {
+        match (a, b) {
+            (Scev::Constant(x), Scev::Constant(y)) => Scev::Constant(x.wrapping_add(y)),
+            (Scev::Constant(0), other) | (other, Scev::Constant(0)) => other,
+            (
+                Scev::AddRec {
+                    start: s1,
+                    step: t1,
+                    header: h1,
+                },
+                Scev::AddRec {
+                    start: s2,
+                    step: t2,
+                    header: h2,
+                },
+            ) if h1 == h2 => Scev::AddRec {
+                start: Box::new(self.add(*s1, *s2)),
+                step: Box::new(self.add(*t1, *t2)),
+                header: h1,
+            },
+            // A recurrence of an outer loop does not change in an inner one, whose start takes it in
+            (
+                Scev::AddRec {
+                    start,
+                    step,
+                    header,
+                },
+                other,
+            )
+            | (
+                other,
+                Scev::AddRec {
+                    start,
+                    step,
+                    header,
+                },
+            ) if self.invariant_in(&other, header) => Scev::AddRec {
+                start: Box::new(self.add(*start, other)),
+                step,
+                header,
+            },
+            (a, b) => Scev::Add(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn mul(&self, a: Scev, b: Scev) -> Scev {
+        match (a, b) {
+            (Scev::Constant(x), Scev::Constant(y)) => Scev::Constant(x.wrapping_mul(y)),
+            (Scev::Constant(0), _) | (_, Scev::Constant(0)) => Scev::Constant(0),
+            (Scev::Constant(1), other) | (other, Scev::Constant(1)) => other,
+            (
+                Scev::AddRec {
+                    start,
+                    step,
+                    header,
+                },
+                other,
+            )
+            | (
+                other,
+                Scev::AddRec {
+                    start,
+                    step,
+                    header,
+                },
+            ) if self.invariant_in(&other, header) => Scev::AddRec {
+                start: Box::new(self.mul(*start, other.clone())),
+                step: Box::new(self.mul(*step, other)),
+                header,
+            },
+            (a, b) => Scev::Mul(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn sub(&self, a: Scev, b: Scev) -> Scev {
+        let negated = self.mul(b, Scev::Constant(-1));
+        self.add(a, negated)
+    }
+
+    /// Compute the evolution of `name` and the names it depends on
+    fn evolution(&mut self, name: &str) -> Scev {
+        if let Some(scev) = self.evolutions.get(name) {
+            return scev.clone();
+        }
+        let opaque = Scev::Value(name.to_string());
+        let single_definition = self.def_blocks.get(name).is_some_and(|b| b.len() == 1);
+        if !single_definition || !self.in_progress.insert(name.to_string()) {
+            return opaque;
+        }
+        let i = self.definitions[name].clone();
+        let args = i.args.clone().unwrap_or_default();
+        let scev = if i.bril_type != Some(BrilType::Int) {
+            opaque
+        } else if i.is_const() {
+            match i.value.as_ref().and_then(|v| v.as_i64()) {
+                Some(c) => Scev::Constant(c),
+                None => opaque,
+            }
+        } else if i.is_id() {
+            self.evolution(&args[0])
+        } else if i.is_add() || i.is_sub() || i.is_mul() {
+            let (a, b) = (self.evolution(&args[0]), self.evolution(&args[1]));
+            match i.op.as_str() {
+                "add" => self.add(a, b),
+                "sub" => self.sub(a, b),
+                _ => self.mul(a, b),
+            }
+        } else if i.is_phi() {
+            let block = self.def_blocks[name][0];
+            self.phi_recurrence(name, &i, block).unwrap_or(opaque)
+        } else {
+            opaque
+        };
+        self.in_progress.remove(name);
+        self.evolutions.insert(name.to_string(), scev.clone());
+        scev
+    }
+
+    /// The recurrence of a phi of a loop header, taking the same value from outside of the loop
+    /// and adding the same invariant step to itself along every back edge
+    fn phi_recurrence(&mut self, phi: &str, i: &Instruction, header: BlockID) -> Option<Scev> {
+        let body = self.loops.bodies.get(&header)?.clone();
+        let mut start = None;
+        let mut step = None;
+        let args = i.args.iter().flatten();
+        for (arg, label) in args.zip(i.labels.iter().flatten()) {
+            let pred = *self.predecessors[&header]
+                .iter()
+                .find(|pred| self.labels[pred] == *label)?;
+            let (slot, value) = match body.contains(&pred) {
+                true => (&mut step, self.step_from(arg, phi, header)?),
+                false => (&mut start, arg.clone()),
+            };
+            match slot {
+                Some(known) if *known != value => return None,
+                _ => *slot = Some(value),
+            }
+        }
+        let start = self.evolution(&start?);
+        let step = self.evolution_of_step(step?)?;
+        if !self.invariant_in(&step, header) {
+            return None;
+        }
+        Some(Scev::AddRec {
+            start: Box::new(start),
+            step: Box::new(step),
+            header,
+        })
+    }
+
+    /// What `name` adds to `phi`, written as the names added and subtracted, when `name` is
+    /// `phi` plus or minus values defined by adds, subs and copies in the loop
+    fn step_from(&mut self, name: &str, phi: &str, header: BlockID) -> Option<String> {
+        if name == phi {
+            return Some(String::new());
+        }
+        let i = self.definitions.get(name)?.clone();
+        let args = i.args.clone().unwrap_or_default();
+        let [block] = self.def_blocks[name][..] else {
+            return None;
+        };
+        if !self.loops.contains(header, block) {
+            return None;
+        }
+        if i.is_id() {
+            return self.step_from(&args[0], phi, header);
+        }
+        let steps = match i.op.as_str() {
+            "add" => [(&args[0], &args[1], '+'), (&args[1], &args[0], '+')].to_vec(),
+            "sub" => [(&args[0], &args[1], '-')].to_vec(),
+            _ => return None,
+        };
+        for (from, other, sign) in steps {
+            if let Some(rest) = self.step_from(from, phi, header) {
+                return Some(format!("{}{}{} ", rest, sign, other));
+            }
+        }
+        None
+    }
+
+    /// The sum `step_from` wrote down
+    fn evolution_of_step(&mut self, step: String) -> Option<Scev> {
+        let mut result = Scev::Constant(0);
+        for term in step.split_whitespace() {
+            let (sign, name) = term.split_at(1);
+            let value = self.evolution(name);
+            result = match sign {
+                "+" => self.add(result, value),
+                _ => self.sub(result, value),
+            };
+        }
+        Some(result)
+    }
+}
+
+/// The comparison true when `predicate` is false
+fn negate(predicate: &str) -> Option<&'static str> {
+    Some(match predicate {
+        "lt" => "ge",
+        "le" => "gt",
+        "gt" => "le",
+        "ge" => "lt",
+        "eq" => "ne",
+        "ne" => "eq",
+        _ => return None,
+    })
+}
+
+/// The comparison that holds with the operands swapped
+fn swap(predicate: &str) -> Option<&'static str> {
+    Some(match predicate {
+        "lt" => "gt",
+        "le" => "ge",
+        "gt" => "lt",
+        "ge" => "le",
+        "eq" => "eq",
+        "ne" => "ne",
+        _ => return None,
+    })
+}
@@ -1,5 +1,6 @@
 use std::collections::{BTreeMap, BTreeSet};
 
+#[derive(Default)]
 pub struct SSAGraph {
     // INFO: Gives a querying
     //
@@ -9,7 +10,11 @@ pub struct SSAGraph {
 }
 
 impl SSAGraph {
-    pub fn new() {} // TODO:
+    pub fn new() -> Self {
+        Self {
+            mappings: BTreeMap::default(),
+        }
+    } // TODO:
 
     pub fn ta() {} // TODO:
 }
@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    aliases::BlockID,
+    bril_syntax::{BrilType, Instruction, InstructionOrLabel},
+    cfg::CFG,
+};
+
+/// INFO: This impl block is denoted to be about tail call elimination
+impl CFG {
+    /// The arguments of the call to `function` ending `id`, when its result (or nothing, for a
+    /// void call) is returned right after
+    fn tail_call_args(&self, id: BlockID, function: &str) -> Option<Vec<String>> {
+        let [.., InstructionOrLabel::Instruction(call), InstructionOrLabel::Instruction(ret)] =
+            &self.blocks[id].instrs[..]
+        else {
+            return None;
+        };
+        let callee = call.funcs.as_ref().and_then(|funcs| funcs.first());
+        if !call.is_call() || !ret.is_ret() || callee.map(String::as_str) != Some(function) {
+            return None;
+        }
+        let returned = ret.args.as_ref().and_then(|args| args.first());
+        if returned != call.dest.as_ref() {
+            return None;
+        }
+        Some(call.args.clone().unwrap_or_default())
+    }
+
+    /// The parameters of the function starting at `entry` as its body names them, that is the
+    /// destinations of the `id`s `from_program` puts in the entry block
+    fn parameters(&self, entry: BlockID) -> Vec<(String, BrilType)> {
+        let func = self.blocks[entry].func.as_ref().unwrap();
+        let mut result = Vec::new();
+        for arg in func.args.iter().flatten() {
+            let copy = self.blocks[entry].instrs.iter().find_map(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i)
+                    if i.is_id() && i.args.as_deref() == Some(std::slice::from_ref(&arg.name)) =>
+                {
+                    i.dest.clone()
+                }
+                _ => None,
+            });
+            result.push((copy.unwrap_or(arg.name.clone()), arg.fn_type.clone()));
+        }
+        result
+    }
+
+    /// Whether no name is defined twice in `blocks`, in which case the function is taken to be
+    /// in SSA form and is kept that way
+    fn defines_names_once(&self, blocks: &[BlockID]) -> bool {
+        let mut defined = BTreeSet::new();
+        blocks
+            .iter()
+            .flat_map(|id| self.blocks[*id].instrs.iter())
+            .all(|ilb| match ilb {
+                InstructionOrLabel::Instruction(i) => i
+                    .dest
+                    .as_ref()
+                    .is_none_or(|dest| defined.insert(dest.clone())),
+                InstructionOrLabel::Label(_) => true,
+            })
+    }
+
+    /// Whether an argument reads a parameter that comes before its own, which is assigned first
+    fn reads_earlier_parameter(parameters: &[(String, BrilType)], args: &[String]) -> bool {
+        args.iter()
+            .enumerate()
+            .any(|(j, arg)| parameters[..j].iter().any(|(name, _)| name == arg))
+    }
+
+    /// Replace the `call` and `ret` ending `site` by a jump to `target`
+    fn jump_instead_of_tail_call(&mut self, site: BlockID, target: BlockID) {
+        let instrs = &mut self.blocks[site].instrs;
+        instrs.remove(instrs.len() - 2);
+        let label = self.blocks[target].get_label();
+        let jmp = Instruction::new_control_instruction(Some(&label), &mut self.instruction_counter);
+        self.replace_terminator(site, jmp);
+    }
+
+    /// Outside of SSA the parameters are reassigned, through copies when an argument reads a
+    /// parameter assigned before it, and the call jumps to the block after the entry block
+    fn eliminate_tail_calls_by_reassigning(
+        &mut self,
+        entry: BlockID,
+        parameters: &[(String, BrilType)],
+        sites: &[(BlockID, Vec<String>)],
+    ) {
+        let head = self.blocks[entry].successors[0];
+        for (site, args) in sites.iter() {
+            let clobbered = Self::reads_earlier_parameter(parameters, args);
+            let mut copies = Vec::new();
+            for ((name, bril_type), arg) in parameters.iter().zip(args.iter()) {
+                if !clobbered && name == arg {
+                    continue;
+                }
+                let dest = match clobbered {
+                    true => format!("{}_tail", name),
+                    false => name.clone(),
+                };
+                copies.push(Instruction::new_id_instruction(
+                    &dest,
+                    arg,
+                    bril_type,
+                    &mut self.instruction_counter,
+                ));
+            }
+            for (name, bril_type) in parameters.iter().filter(|_| clobbered) {
+                let copy = format!("{}_tail", name);
+                copies.push(Instruction::new_id_instruction(
+                    name,
+                    &copy,
+                    bril_type,
+                    &mut self.instruction_counter,
+                ));
+            }
+            let instrs = &mut self.blocks[*site].instrs;
+            let position = instrs.len() - 2;
+            instrs.splice(position..position, copies);
+            self.jump_instead_of_tail_call(*site, head);
+        }
+    }
+
+    /// In SSA a new loop header goes between the entry block and the rest of the function, with
+    /// one phi per parameter merging the value from the entry block and the arguments of every
+    /// tail call. Every use of a parameter below the entry block reads the phi instead
+    fn eliminate_tail_calls_with_phis(
+        &mut self,
+        blocks: &[BlockID],
+        parameters: &[(String, BrilType)],
+        sites: &[BlockID],
+    ) {
+        let entry = blocks[0];
+        let head = self.blocks[entry].successors[0];
+        let name = self.blocks[entry].func.as_ref().unwrap().name.clone();
+        let entry_label = self.blocks[entry].get_label();
+        let header_label = self.blocks[head].get_label() + "_tailrec";
+        let header = self.new_block(&header_label);
+        let position = self.order.iter().position(|id| *id == entry).unwrap();
+        self.order.insert(position + 1, header);
+        self.blocks[entry].successors = vec![header];
+        self.blocks[header].predecessors = vec![entry];
+        self.blocks[header].successors = vec![head];
+        for pred in self.blocks[head].predecessors.iter_mut() {
+            if *pred == entry {
+                *pred = header;
+            }
+        }
+
+        let renamed = parameters
+            .iter()
+            .map(|(name, _)| (name.clone(), format!("{}_tail", name)))
+            .collect::<BTreeMap<_, _>>();
+        for id in blocks.iter().skip(1) {
+            for ilb in self.blocks[*id].instrs.iter_mut() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    continue;
+                };
+                for arg in i.args.iter_mut().flatten() {
+                    if let Some(new) = renamed.get(arg) {
+                        *arg = new.clone();
+                    }
+                }
+                // Whatever came from the entry block now comes through the header
+                if i.is_phi() {
+                    for label in i.labels.iter_mut().flatten() {
+                        if *label == entry_label {
+                            *label = header_label.clone();
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut phis = parameters
+            .iter()
+            .map(|(name, bril_type)| {
+                let mut phi =
+                    Instruction::new_phi(renamed[name].clone(), &mut self.instruction_counter);
+                phi.bril_type = Some(bril_type.clone());
+                phi.args = Some(vec![name.clone()]);
+                phi.labels = Some(vec![entry_label.clone()]);
+                phi
+            })
+            .collect::<Vec<_>>();
+        let phi_names = parameters
+            .iter()
+            .map(|(name, bril_type)| (renamed[name].clone(), bril_type.clone()))
+            .collect::<Vec<_>>();
+        for site in sites.iter() {
+            // The arguments are read after the renaming above
+            let mut args = self.tail_call_args(*site, &name).unwrap();
+            let label = self.blocks[*site].get_label();
+            // brilirs runs the phis of a block one after the other, a phi reading an earlier
+            // one would see its new value
+            if Self::reads_earlier_parameter(&phi_names, &args) {
+                let mut copies = Vec::new();
+                for arg in args.iter_mut() {
+                    if let Some((_, bril_type)) = phi_names.iter().find(|(phi, _)| phi == arg) {
+                        let copy = format!("{}_{}", arg, label);
+                        copies.push(Instruction::new_id_instruction(
+                            &copy,
+                            arg,
+                            bril_type,
+                            &mut self.instruction_counter,
+                        ));
+                        *arg = copy;
+                    }
+                }
+                let instrs = &mut self.blocks[*site].instrs;
+                let position = instrs.len() - 2;
+                instrs.splice(position..position, copies);
+            }
+            for (phi, arg) in phis.iter_mut().zip(args) {
+                phi.args.as_mut().unwrap().push(arg);
+                phi.labels.as_mut().unwrap().push(label.clone());
+            }
+            self.jump_instead_of_tail_call(*site, header);
+        }
+        for phi in phis {
+            self.blocks[header].push_back(&InstructionOrLabel::Instruction(phi));
+        }
+    }
+
+    /// Turn every call of a function to itself whose result is returned right away into a jump
+    /// back to the start of the function, reassigning its parameters first. Functions in SSA form
+    /// get phis instead. Returns the number of calls removed
+    pub fn eliminate_tail_calls(&mut self) -> usize {
+        let mut removed = 0;
+        for blocks in self.function_blocks() {
+            let entry = blocks[0];
+            let name = self.blocks[entry].func.as_ref().unwrap().name.clone();
+            let parameters = self.parameters(entry);
+            let sites = blocks
+                .iter()
+                .filter_map(|id| Some((*id, self.tail_call_args(*id, &name)?)))
+                .filter(|(_, args)| args.len() == parameters.len())
+                .collect::<Vec<_>>();
+            if sites.is_empty() || self.blocks[entry].successors.is_empty() {
+                continue;
+            }
+            eprintln!("Eliminating {} tail calls in @{}", sites.len(), name);
+            removed += sites.len();
+            if self.defines_names_once(&blocks) {
+                let sites = sites.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                self.eliminate_tail_calls_with_phis(&blocks, &parameters, &sites);
+            } else {
+                self.eliminate_tail_calls_by_reassigning(entry, &parameters, &sites);
+            }
+        }
+        removed
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    aliases::BlockID,
+    bril_syntax::{Instruction, InstructionOrLabel},
+    cfg::CFG,
+};
+
+/// The most blocks one trace strings together
+pub const MAX_TRACE_LENGTH: usize = 16;
+
+/// INFO: This impl block is denoted to be about traces
+impl CFG {
+    /// The successor of `id` taken the most according to the profile, if any was taken at all
+    fn hottest_successor(&self, id: BlockID) -> Option<BlockID> {
+        let bb = &self.blocks[id];
+        bb.successors
+            .iter()
+            .map(|succ| (*succ, bb.edge_counts.get(succ).copied().unwrap_or(0)))
+            .filter(|(_, count)| *count > 0)
+            // The first of the hottest, so ties keep the order of the successors
+            .min_by_key(|(_, count)| std::cmp::Reverse(*count))
+            .map(|(succ, _)| succ)
+    }
+
+    /// Whether `id` can go in a trace: running it again after a failed `guard` rolled the
+    /// variables back must be harmless, and it must have a successor that ran
+    fn traceable(&self, id: BlockID) -> bool {
+        let harmless = self.blocks[id].instrs.iter().all(|ilb| match ilb {
+            InstructionOrLabel::Instruction(i) => !i.is_phi() && !i.has_side_effects(),
+            InstructionOrLabel::Label(_) => true,
+        });
+        harmless && self.hottest_successor(id).is_some()
+    }
+
+    /// The hottest path of the function, from its hottest traceable block and following the
+    /// hottest edges until it comes back to a block it went through, or meets a block that
+    /// cannot go in a trace. Every block comes with the successor the path takes out of it
+    fn hot_path(&self, blocks: &[BlockID]) -> Option<Vec<(BlockID, BlockID)>> {
+        let start = *blocks
+            .iter()
+            .skip(1)
+            .filter(|id| self.traceable(**id))
+            .min_by_key(|id| std::cmp::Reverse(self.blocks[**id].execution_count))?;
+        let mut path = Vec::new();
+        let mut id = start;
+        loop {
+            let next = self.hottest_successor(id).unwrap();
+            path.push((id, next));
+            if path.len() == MAX_TRACE_LENGTH
+                || path.iter().any(|(visited, _)| *visited == next)
+                || !self.traceable(next)
+            {
+                return Some(path);
+            }
+            id = next;
+        }
+    }
+
+    /// The instructions of `path` as one straight line between `speculate` and `commit`. Jumps
+    /// disappear and every branch becomes a `guard` on the direction the path takes, failing to
+    /// the start of the path
+    fn trace_instructions(&mut self, path: &[(BlockID, BlockID)]) -> Vec<InstructionOrLabel> {
+        let start_label = self.blocks[path[0].0].get_label();
+        let mut result = vec![Instruction::new_effect_instruction(
+            "speculate",
+            Vec::new(),
+            Vec::new(),
+            &mut self.instruction_counter,
+        )];
+        for (id, next) in path.iter() {
+            let next_label = self.blocks[*next].get_label();
+            for ilb in self.blocks[*id].instrs.clone() {
+                let InstructionOrLabel::Instruction(mut i) = ilb else {
+                    continue;
+                };
+                if i.is_jmp() {
+                    continue;
+                }
+                if !i.is_br() {
+                    // A copy, every instruction keeps an id of its own
+                    i.instruction_id = Some(self.instruction_counter);
+                    self.instruction_counter += 1;
+                    result.push(InstructionOrLabel::Instruction(i));
+                    continue;
+                }
+                let labels = i.labels.unwrap();
+                let mut condition = i.args.unwrap()[0].clone();
+                if labels[0] == labels[1] {
+                    continue;
+                }
+                if labels[0] != next_label {
+                    let negated = format!("{}_not", condition);
+                    result.push(Instruction::new_not_instruction(
+                        &negated,
+                        &condition,
+                        &mut self.instruction_counter,
+                    ));
+                    condition = negated;
+                }
+                result.push(Instruction::new_effect_instruction(
+                    "guard",
+                    vec![condition],
+                    vec![start_label.clone()],
+                    &mut self.instruction_counter,
+                ));
+            }
+        }
+        result.push(Instruction::new_effect_instruction(
+            "commit",
+            Vec::new(),
+            Vec::new(),
+            &mut self.instruction_counter,
+        ));
+        result
+    }
+
+    /// Put the trace of `path` in a new block right before the start of the path, and make every
+    /// predecessor of the start go to the trace instead. A failed `guard` runs the original blocks
+    fn insert_trace(&mut self, path: &[(BlockID, BlockID)]) {
+        let start = path[0].0;
+        let start_label = self.blocks[start].get_label();
+        let instrs = self.trace_instructions(path);
+        let guarded = instrs.iter().any(|ilb| match ilb {
+            InstructionOrLabel::Instruction(i) => i.is_guard(),
+            InstructionOrLabel::Label(_) => false,
+        });
+        let label = start_label.clone() + "_trace";
+        let trace = self.new_block(&label);
+        for ilb in instrs.iter() {
+            self.blocks[trace].push_back(ilb);
+        }
+        // A path closing on its start loops on the trace
+        let exit = match path.last().unwrap().1 {
+            next if next == start => trace,
+            next => next,
+        };
+        let exit_label = self.blocks[exit].get_label();
+        let jmp =
+            Instruction::new_control_instruction(Some(&exit_label), &mut self.instruction_counter);
+        self.blocks[trace].push_back(&jmp);
+
+        let predecessors = std::mem::take(&mut self.blocks[start].predecessors);
+        for pred in predecessors.iter() {
+            let bb = &mut self.blocks[*pred];
+            if let Some(InstructionOrLabel::Instruction(i)) = bb.instrs.last_mut() {
+                if i.is_jmp() || i.is_br() {
+                    for l in i.labels.iter_mut().flatten() {
+                        if *l == start_label {
+                            *l = label.clone();
+                        }
+                    }
+                }
+            }
+            for succ in bb.successors.iter_mut() {
+                if *succ == start {
+                    *succ = trace;
+                }
+            }
+            if let Some(count) = bb.edge_counts.remove(&start) {
+                bb.edge_counts.insert(trace, count);
+            }
+        }
+        self.blocks[trace].predecessors = predecessors;
+        self.blocks[trace].successors = vec![exit];
+        self.blocks[exit].predecessors.push(trace);
+        if guarded {
+            self.blocks[trace].successors.push(start);
+            self.blocks[start].predecessors.push(trace);
+        }
+        let count = self.blocks[start].execution_count;
+        self.blocks[trace].execution_count = count;
+        self.blocks[trace].edge_counts = BTreeMap::from([(exit, count.unwrap_or(0))]);
+
+        let position = self.order.iter().position(|id| *id == start).unwrap();
+        self.order.insert(position, trace);
+    }
+
+    /// Speculate along the hottest path of every function a profile was applied to: the path is
+    /// copied into a straight-line trace wrapped in `speculate` and `commit`, with a `guard` for
+    /// every branch, and the trace runs in place of the original blocks which only run when a
+    /// `guard` fails. Functions with phis or that already speculate are left alone. The trace
+    /// defines again the names of the blocks it copies, so the program leaves SSA form. Returns
+    /// the number of traces formed
+    pub fn form_traces(&mut self) -> usize {
+        let mut formed = 0;
+        for blocks in self.function_blocks() {
+            let entry = blocks[0];
+            let phis_or_speculating = blocks
+                .iter()
+                .flat_map(|id| self.blocks[*id].instrs.iter())
+                .any(|ilb| match ilb {
+                    InstructionOrLabel::Instruction(i) => i.is_phi() || i.is_guard(),
+                    InstructionOrLabel::Label(_) => false,
+                });
+            if self.blocks[entry].execution_count.is_none() || phis_or_speculating {
+                continue;
+            }
+            let Some(path) = self.hot_path(&blocks) else {
+                continue;
+            };
+            eprintln!(
+                "Forming a trace of {} blocks from .{} in @{}",
+                path.len(),
+                self.blocks[path[0].0].get_label(),
+                self.blocks[entry].func.as_ref().unwrap().name
+            );
+            self.insert_trace(&path);
+            formed += 1;
+        }
+        formed
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use brilirs::{basic_block::BBProgram, check::type_check, interp::execute_main};
+
+use crate::{
+    bril_syntax::Program,
+    cfg::CFG,
+    pass_manager::{PassManager, PassRegistry},
+};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(20);
+const STACK_SIZE: usize = 256 << 20;
+
+/// What one run of a program through brilirs did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    pub stdout: String,
+    /// The interpreter error, without its source position since passes move instructions around
+    pub error: Option<String>,
+    pub dynamic_instructions: Option<u64>,
+}
+
+impl Execution {
+    /// Describe the first difference with `other`, None when both runs behaved the same
+    pub fn difference(&self, other: &Execution) -> Option<String> {
+        if self.error != other.error {
+            return Some(format!(
+                "error differs: {:?} vs {:?}",
+                self.error.as_deref().unwrap_or("none"),
+                other.error.as_deref().unwrap_or("none")
+            ));
+        }
+        if self.stdout != other.stdout {
+            let mut lines = self.stdout.lines().zip(other.stdout.lines()).enumerate();
+            return Some(match lines.find(|(_, (a, b))| a != b) {
+                Some((line, (a, b))) => {
+                    format!("stdout differs at line {}: `{}` vs `{}`", line + 1, a, b)
+                }
+                None => format!(
+                    "stdout differs in length: {} vs {} lines",
+                    self.stdout.lines().count(),
+                    other.stdout.lines().count()
+                ),
+            });
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Same output and same error, if any
+    Equivalent,
+    /// The optimized program behaves differently, with the first difference
+    Mismatch(String),
+    /// The pipeline panicked or did not finish
+    PassFailed(String),
+    /// The original program could not be run, there is nothing to compare against
+    Skipped(String),
+}
+
+/// The outcome of validating the pipeline on one file
+#[derive(Debug, Clone)]
+pub struct Validation {
+    pub file: PathBuf,
+    pub args: Vec<String>,
+    pub original: Option<Execution>,
+    pub optimized: Option<Execution>,
+    pub verdict: Verdict,
+}
+
+impl Validation {
+    fn new(file: &Path, args: Vec<String>, verdict: Verdict) -> Self {
+        Self {
+            file: file.to_path_buf(),
+            args,
+            original: None,
+            optimized: None,
+            verdict,
+        }
+    }
+
+    /// Dynamic instruction counts before and after the pipeline
+    pub fn dynamic_instructions(&self) -> Option<(u64, u64)> {
+        let before = self.original.as_ref()?.dynamic_instructions?;
+        let after = self.optimized.as_ref()?.dynamic_instructions?;
+        Some((before, after))
+    }
+}
+
+impl Display for Validation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = self.file.display();
+        match &self.verdict {
+            Verdict::Equivalent => {
+                write!(f, "ok       {}", file)?;
+                match self.dynamic_instructions() {
+                    Some((before, after)) => write!(
+                        f,
+                        ": {} -> {} dynamic instructions ({})",
+                        before,
+                        after,
+                        percentage(before, after)
+                    ),
+                    None => Ok(()),
+                }
+            }
+            Verdict::Mismatch(reason) => write!(f, "MISMATCH {}: {}", file, reason),
+            Verdict::PassFailed(reason) => write!(f, "FAILED   {}: {}", file, reason),
+            Verdict::Skipped(reason) => write!(f, "skipped  {}: {}", file, reason),
+        }
+    }
+}
+
+/// Relative change from `before` to `after`, as in `-12.5%`
+pub fn percentage(before: u64, after: u64) -> String {
+    match before {
+        0 => "n/a".to_string(),
+        _ => format!(
+            "{:+.1}%",
+            100.0 * (after as f64 - before as f64) / before as f64
+        ),
+    }
+}
+
+/// The arguments of the `# ARGS:` comment of a textual program, as the benchmarks use them
+pub fn parse_args(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .find_map(|line| line.trim().strip_prefix('#')?.trim().strip_prefix("ARGS:"))
+        .map(|args| args.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Run `f` on its own thread, giving up after `timeout`. A panic is reported as an error
+fn with_timeout<T: Send + 'static>(
+    timeout: Duration,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, String> {
+    let (sender, receiver) = mpsc::channel();
+    // Interpreting deep recursion needs more than the default thread stack
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+            let _ = sender.send(result.map_err(|payload| {
+                match (
+                    payload.downcast_ref::<&str>(),
+                    payload.downcast_ref::<String>(),
+                ) {
+                    (Some(message), _) => format!("panicked: {}", message),
+                    (_, Some(message)) => format!("panicked: {}", message),
+                    _ => "panicked".to_string(),
+                }
+            }));
+        })
+        .expect("Failed to spawn a thread");
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(format!("timed out after {:?}", timeout)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err("panicked".to_string()),
+    }
+}
+
+/// Run a JSON program through the brilirs interpreter, in process
+fn execute(json: &str, args: &[String]) -> Execution {
+    let mut stdout = Vec::<u8>::new();
+    let mut profile = Vec::<u8>::new();
+    let result = (|| -> Result<(), String> {
+        let abstract_program = bril_rs::load_abstract_program_from_read(json.as_bytes());
+        let program: bril_rs::Program = abstract_program
+            .try_into()
+            .map_err(|e: bril_rs::conversion::PositionalConversionError| e.e.to_string())?;
+        let bb_program = BBProgram::new(program).map_err(|e| e.to_string())?;
+        type_check(&bb_program).map_err(|e| e.e.to_string())?;
+        execute_main(&bb_program, &mut stdout, args, true, &mut profile)
+            .map_err(|e| e.e.to_string())
+    })();
+    let profile = String::from_utf8_lossy(&profile);
+    Execution {
+        stdout: String::from_utf8_lossy(&stdout).to_string(),
+        error: result.err(),
+        dynamic_instructions: profile
+            .lines()
+            .find_map(|line| line.strip_prefix("total_dyn_inst: "))
+            .and_then(|count| count.trim().parse().ok()),
+    }
+}
+
+/// Runs every program before and after a pass pipeline and compares what they do
+pub struct TranslationValidator {
+    pipeline: String,
+    timeout: Duration,
+}
+
+impl TranslationValidator {
+    /// Fails on the same unknown pass names `PassManager::from_pipeline` does
+    pub fn new(pipeline: &str) -> Result<Self, String> {
+        PassManager::from_pipeline(&PassRegistry::default(), pipeline)?;
+        Ok(Self {
+            pipeline: pipeline.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// How long each interpretation and the pipeline itself may take
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Validate one textual `.bril` file, with the arguments of its `# ARGS:` comment
+    pub fn validate_file(&self, file: &Path) -> Validation {
+        let source = match std::fs::read_to_string(file) {
+            Ok(source) => source,
+            Err(e) => return Validation::new(file, Vec::new(), Verdict::Skipped(e.to_string())),
+        };
+        let args = parse_args(&source);
+        let name = file.display().to_string();
+        let json = match with_timeout(self.timeout, move || {
+            let program = bril2json::parse_abstract_program_from_read(
+                source.as_bytes(),
+                true,
+                true,
+                Some(name),
+            );
+            serde_json::to_string(&program).unwrap()
+        }) {
+            Ok(json) => json,
+            Err(e) => return Validation::new(file, args, Verdict::Skipped(format!("parse {}", e))),
+        };
+
+        let original = {
+            let (json, run_args) = (json.clone(), args.clone());
+            match with_timeout(self.timeout, move || execute(&json, &run_args)) {
+                Ok(execution) => execution,
+                Err(e) => {
+                    return Validation::new(file, args, Verdict::Skipped(format!("original {}", e)))
+                }
+            }
+        };
+
+        let pipeline = self.pipeline.clone();
+        let optimized_json = match with_timeout(self.timeout, move || {
+            let mut program: Program = serde_json::from_str(&json).unwrap();
+            let mut cfg = CFG::from_program(&mut program);
+            PassManager::from_pipeline(&PassRegistry::default(), &pipeline)
+                .unwrap()
+                .run(&mut cfg);
+            serde_json::to_string(&cfg.to_program()).unwrap()
+        }) {
+            Ok(json) => json,
+            Err(e) => {
+                let mut result = Validation::new(file, args, Verdict::PassFailed(e));
+                result.original = Some(original);
+                return result;
+            }
+        };
+
+        let optimized = {
+            let args = args.clone();
+            with_timeout(self.timeout, move || execute(&optimized_json, &args)).unwrap_or_else(
+                |e| Execution {
+                    stdout: String::new(),
+                    error: Some(e),
+                    dynamic_instructions: None,
+                },
+            )
+        };
+        let verdict = match original.difference(&optimized) {
+            None => Verdict::Equivalent,
+            Some(difference) => Verdict::Mismatch(difference),
+        };
+        Validation {
+            file: file.to_path_buf(),
+            args,
+            original: Some(original),
+            optimized: Some(optimized),
+            verdict,
+        }
+    }
+
+    /// Validate every `.bril` file under `path`, or `path` itself if it is a file, in path order
+    pub fn validate_path(&self, path: &Path) -> Vec<Validation> {
+        let mut files = Vec::new();
+        collect_bril_files(path, &mut files);
+        files.sort();
+        files.iter().map(|file| self.validate_file(file)).collect()
+    }
+}
+
+fn collect_bril_files(path: &Path, files: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+            collect_bril_files(&entry.path(), files);
+        }
+    } else if path
+        .extension()
+        .is_some_and(|extension| extension == "bril")
+    {
+        files.push(path.to_path_buf());
+    }
+}
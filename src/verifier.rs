@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Display;
+
+use crate::{
+    aliases::BlockID,
+    analysis_manager::AnalysisManager,
+    basic_block::BasicBlock,
+    bril_syntax::{Instruction, InstructionOrLabel},
+    cfg::CFG,
+    definite_assignment::UNDEFINED_MARKER,
+    dominance::DominanceDataFlow,
+};
+
+/// A broken invariant, located at the block it was found in
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VerifierError {
+    pub function: String,
+    pub block: BlockID,
+    pub label: String,
+    pub message: String,
+}
+
+impl Display for VerifierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "@{}, block {} (.{}): {}",
+            self.function, self.block, self.label, self.message
+        )
+    }
+}
+
+/// Where a variable of an SSA function is defined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Definition {
+    Argument,
+    Instruction { block: BlockID, position: usize },
+}
+
+/// Checks one function at a time, the blocks of the function are the ones `CFG::function_blocks`
+/// groups together
+struct Verifier<'a> {
+    function: String,
+    cfg: &'a CFG,
+    blocks: &'a [BlockID],
+    errors: Vec<VerifierError>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(cfg: &'a CFG, blocks: &'a [BlockID]) -> Self {
+        let function = match &cfg.blocks[blocks[0]].func {
+            Some(func) => func.name.clone(),
+            None => "<unknown>".to_string(),
+        };
+        Self {
+            function,
+            cfg,
+            blocks,
+            errors: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, id: BlockID, message: String) {
+        self.errors.push(VerifierError {
+            function: self.function.clone(),
+            block: id,
+            label: self.cfg.blocks[id].get_label(),
+            message,
+        });
+    }
+
+    fn function_blocks(&self) -> impl Iterator<Item = &'a BasicBlock> {
+        let (cfg, blocks) = (self.cfg, self.blocks);
+        blocks.iter().map(move |id| &cfg.blocks[*id])
+    }
+
+    /// Every `jmp`/`br` target is a label of this function
+    fn check_labels(&mut self) {
+        let mut labels = BTreeSet::new();
+        for bb in self.function_blocks() {
+            for ilb in bb.instrs.iter() {
+                if let InstructionOrLabel::Label(l) = ilb {
+                    labels.insert(l.label.clone());
+                }
+            }
+        }
+        for bb in self.function_blocks() {
+            let mut missing = Vec::new();
+            for ilb in bb.instrs.iter() {
+                if let InstructionOrLabel::Instruction(i) = ilb {
+                    if i.is_jmp() || i.is_br() {
+                        let targets = i.labels.iter().flatten();
+                        missing.extend(targets.filter(|l| !labels.contains(*l)).cloned());
+                    }
+                }
+            }
+            for label in missing {
+                self.error(bb.id, format!("jump to undefined label .{}", label));
+            }
+        }
+    }
+
+    /// `a` is a successor of `b` exactly when `b` is a predecessor of `a`, and edges stay inside
+    /// the function
+    fn check_edges(&mut self) {
+        let function_ids = self.blocks.iter().copied().collect::<BTreeSet<BlockID>>();
+        for bb in self.function_blocks() {
+            let mut messages = Vec::new();
+            for succ in bb.successors.iter() {
+                if !function_ids.contains(succ) {
+                    messages.push(format!("successor {} is outside the function", succ));
+                }
+                if !self.cfg.blocks[*succ].predecessors.contains(&bb.id) {
+                    messages.push(format!(
+                        "successor {} does not list it as a predecessor",
+                        succ
+                    ));
+                }
+            }
+            for pred in bb.predecessors.iter() {
+                if !function_ids.contains(pred) {
+                    messages.push(format!("predecessor {} is outside the function", pred));
+                }
+                if !self.cfg.blocks[*pred].successors.contains(&bb.id) {
+                    messages.push(format!(
+                        "predecessor {} does not list it as a successor",
+                        pred
+                    ));
+                }
+            }
+            for message in messages {
+                self.error(bb.id, message);
+            }
+        }
+    }
+
+    /// A `jmp`, `br` or `ret` can only be the last instruction of its block
+    fn check_terminators(&mut self) {
+        for bb in self.function_blocks() {
+            let instructions = bb
+                .instrs
+                .iter()
+                .filter_map(|ilb| match ilb {
+                    InstructionOrLabel::Instruction(i) => Some(i.clone()),
+                    InstructionOrLabel::Label(_) => None,
+                })
+                .collect::<Vec<Instruction>>();
+            let terminators = instructions
+                .iter()
+                .filter(|i| i.is_jmp() || i.is_br() || i.is_ret())
+                .count();
+            let ends_with_terminator = instructions
+                .last()
+                .is_some_and(|i| i.is_jmp() || i.is_br() || i.is_ret());
+            if terminators > 1 || (terminators == 1 && !ends_with_terminator) {
+                self.error(
+                    bb.id,
+                    format!(
+                        "{} terminators, only the last instruction may be one",
+                        terminators
+                    ),
+                );
+            }
+        }
+    }
+
+    /// The blocks reachable from the function entry, uses in the other blocks never execute
+    fn reachable(&self) -> BTreeSet<BlockID> {
+        let mut result = BTreeSet::new();
+        let mut queue = VecDeque::from([self.blocks[0]]);
+        while let Some(id) = queue.pop_front() {
+            if result.insert(id) {
+                queue.extend(self.cfg.blocks[id].successors.iter());
+            }
+        }
+        result
+    }
+
+    /// Every name is defined once, definitions dominate their uses, and the phi labels are
+    /// exactly the labels of the predecessors
+    fn check_ssa(&mut self, dominance: &DominanceDataFlow) {
+        let mut definitions = BTreeMap::<String, Definition>::new();
+        if let Some(func) = &self.cfg.blocks[self.blocks[0]].func {
+            for arg in func.args.iter().flatten() {
+                definitions.insert(arg.name.clone(), Definition::Argument);
+            }
+        }
+        for bb in self.function_blocks() {
+            let mut redefined = Vec::new();
+            for (position, ilb) in bb.instrs.iter().enumerate() {
+                let InstructionOrLabel::Instruction(i) = ilb else {
+                    continue;
+                };
+                let Some(dest) = &i.dest else {
+                    continue;
+                };
+                let definition = Definition::Instruction {
+                    block: bb.id,
+                    position,
+                };
+                if definitions.insert(dest.clone(), definition).is_some() {
+                    redefined.push(dest.clone());
+                }
+            }
+            for dest in redefined {
+                self.error(bb.id, format!("`{}` is defined more than once", dest));
+            }
+        }
+
+        let reachable = self.reachable();
+        // Whether the definition is available at the given position of the block
+        let available = |definition: &Definition, block: BlockID, position: usize| match definition
+        {
+            Definition::Argument => true,
+            Definition::Instruction {
+                block: def_block,
+                position: def_position,
+            } => match *def_block == block {
+                true => *def_position < position,
+                false => dominance.dom(*def_block, block),
+            },
+        };
+        for bb in self.function_blocks() {
+            let mut messages = Vec::new();
+            {
+                let mut pred_of_label = BTreeMap::<String, BlockID>::new();
+                for pred in bb.predecessors.iter() {
+                    pred_of_label.insert(self.cfg.blocks[*pred].get_label(), *pred);
+                }
+                for (position, ilb) in bb.instrs.iter().enumerate() {
+                    let InstructionOrLabel::Instruction(i) = ilb else {
+                        continue;
+                    };
+                    if !i.is_phi() {
+                        for arg in i.args.iter().flatten() {
+                            match definitions.get(arg) {
+                                None => messages.push(format!("`{}` is never defined", arg)),
+                                Some(definition)
+                                    if reachable.contains(&bb.id)
+                                        && !available(definition, bb.id, position) =>
+                                {
+                                    messages.push(format!(
+                                        "definition of `{}` does not dominate its use",
+                                        arg
+                                    ))
+                                }
+                                _ => {}
+                            }
+                        }
+                        continue;
+                    }
+
+                    let dest = i.dest.clone().unwrap_or_default();
+                    let labels = i.labels.clone().unwrap_or_default();
+                    let args = i.args.clone().unwrap_or_default();
+                    if labels.len() != args.len() {
+                        messages.push(format!(
+                            "phi for `{}` has {} arguments but {} labels",
+                            dest,
+                            args.len(),
+                            labels.len()
+                        ));
+                    }
+                    let phi_labels = labels.iter().cloned().collect::<BTreeSet<String>>();
+                    let pred_labels = pred_of_label.keys().cloned().collect::<BTreeSet<String>>();
+                    if phi_labels != pred_labels || phi_labels.len() != labels.len() {
+                        messages.push(format!(
+                            "phi for `{}` has labels {:?} but the predecessors are {:?}",
+                            dest, labels, pred_labels
+                        ));
+                    }
+                    for (arg, label) in args.iter().zip(labels.iter()) {
+                        if arg == UNDEFINED_MARKER {
+                            continue;
+                        }
+                        // A phi argument is read at the end of its predecessor
+                        let Some(pred) = pred_of_label.get(label) else {
+                            continue;
+                        };
+                        match definitions.get(arg) {
+                            None => messages.push(format!("`{}` is never defined", arg)),
+                            Some(definition)
+                                if reachable.contains(pred)
+                                    && !available(definition, *pred, usize::MAX) =>
+                            {
+                                messages.push(format!(
+                                    "definition of `{}` does not dominate the end of .{}",
+                                    arg, label
+                                ))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            for message in messages {
+                self.error(bb.id, message);
+            }
+        }
+    }
+}
+
+/// INFO: This impl block is denoted to be about verifying the IR invariants
+impl CFG {
+    /// Check that jump targets exist, predecessor and successor lists agree and terminators only
+    /// end blocks. With `ssa`, also check single definitions, dominance of definitions over uses
+    /// and that phi labels match the predecessors. Returns every broken invariant found
+    pub fn verify(&self, ssa: bool) -> Result<(), Vec<VerifierError>> {
+        self.verify_with(ssa, &mut AnalysisManager::new())
+    }
+
+    /// Same as `verify`, reusing the dominance cached in `analyses`
+    pub fn verify_with(
+        &self,
+        ssa: bool,
+        analyses: &mut AnalysisManager,
+    ) -> Result<(), Vec<VerifierError>> {
+        let mut errors = Vec::new();
+        for blocks in self.function_blocks().iter() {
+            let mut verifier = Verifier::new(self, blocks);
+            verifier.check_labels();
+            verifier.check_edges();
+            verifier.check_terminators();
+            if ssa {
+                verifier.check_ssa(&analyses.dominance(self, blocks));
+            }
+            errors.append(&mut verifier.errors);
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use goldentests::TestConfig;
+
+/// Set for the runs of this executable that act as the golden driver
+const DRIVER: &str = "GOLDEN_DRIVER";
+
+/// Every file in tests/golden runs one binary of src/bin through the golden driver, which is this
+/// same executable started again with GOLDEN_DRIVER set. Run with GOLDEN_OVERWRITE=1 to regenerate
+/// the expectations, then review the diff
+fn main() {
+    if std::env::var_os(DRIVER).is_some() {
+        drive();
+    }
+    std::env::set_var(DRIVER, "1");
+    let current_exe = std::env::current_exe().expect("Failed to locate the golden tests");
+    let config = TestConfig::new(current_exe, "tests/golden", "# ");
+    let result = config.and_then(|mut config| {
+        config.overwrite_tests = std::env::var_os("GOLDEN_OVERWRITE").is_some();
+        config.run_tests()
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn drive() -> ! {
+    // [--path] [--stderr=<text>...] <binary> [binary args...] <file.bril>, as the golden files
+    // give it. The textual program is turned into JSON with bril2json and piped into <binary>, or
+    // with --path the file path is passed as the last argument instead. A program printed on
+    // stdout is shown as text, any other stdout as is. Of stderr only the lines containing one of
+    // the --stderr texts are kept, the analyses print their results among their debug output.
+    // Exits with the status of <binary>
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+    let mut pass_path = false;
+    let mut stderr_texts = Vec::<String>::new();
+    while let Some(flag) = args.first() {
+        if flag == "--path" {
+            pass_path = true;
+        } else if let Some(text) = flag.strip_prefix("--stderr=") {
+            stderr_texts.push(text.to_string());
+        } else {
+            break;
+        }
+        args.remove(0);
+    }
+    if args.len() < 2 {
+        eprintln!("usage: golden [--path] [--stderr=<text>...] <binary> [binary args...] <file>");
+        std::process::exit(2);
+    }
+    let file = args.pop().unwrap();
+    let binary = args.remove(0);
+
+    // The binaries of the crate are all built next to this one
+    let bin_dir = Path::new(env!("CARGO_BIN_EXE_bril-opt")).parent().unwrap();
+    let binary_path = bin_dir.join(&binary);
+    let mut command = Command::new(&binary_path);
+    command.args(args.iter()).stderr(Stdio::piped());
+
+    let output = if pass_path {
+        command.arg(&file).output()
+    } else {
+        let source = std::fs::read_to_string(&file).expect("Failed to read the test file");
+        let program =
+            bril2json::parse_abstract_program_from_read(source.as_bytes(), true, false, None);
+        let json = serde_json::to_string(&program).expect("Failed to write JSON");
+        command.stdin(Stdio::piped()).stdout(Stdio::piped());
+        command.spawn().and_then(|mut child| {
+            child.stdin.take().unwrap().write_all(json.as_bytes())?;
+            child.wait_with_output()
+        })
+    };
+    let output = output.unwrap_or_else(|e| {
+        eprintln!("golden: cannot run {}: {}", binary_path.display(), e);
+        std::process::exit(2);
+    });
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stderr.lines() {
+        if stderr_texts.iter().any(|text| line.contains(text.as_str())) {
+            eprintln!("{}", line);
+        }
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match serde_json::from_str::<bril_rs::AbstractProgram>(&stdout) {
+        Ok(program) => print!("{}", program),
+        Err(_) => print!("{}", stdout),
+    }
+    std::process::exit(output.status.code().unwrap_or(1));
+}